@@ -1,36 +1,1116 @@
-use crate::mmu::MMU;
+use serde::{Deserialize, Serialize};
+use serde_big_array::BigArray;
+use std::collections::VecDeque;
 
-// APU (simplified - just tracks timing)
+// APU: the two pulse channels, the wave channel and the noise channel
+// mixed into an interleaved stereo f32 stream
+
+// Output sample rate of the mixed audio stream, and the accepted range
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+const MIN_SAMPLE_RATE: u32 = 8000;
+const MAX_SAMPLE_RATE: u32 = 96000;
+const CPU_CLOCK: u32 = 4_194_304;
+// The APU updates once per M-cycle; raw output takes a sample every update
+pub const NATIVE_SAMPLE_RATE: u32 = CPU_CLOCK / 4;
+
+// Read-back masks for 0xFF10-0xFF2F: write-only and unused bits read as 1
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3f, 0x00, 0xff, 0xbf, // NR10-NR14
+    0xff, 0x3f, 0x00, 0xff, 0xbf, // NR20-NR24
+    0x7f, 0xff, 0x9f, 0xff, 0xbf, // NR30-NR34
+    0xff, 0xff, 0x00, 0x00, 0xbf, // NR40-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+];
+
+// Pulse waveforms for NRx1 duty 12.5%, 25%, 50%, 75% (bit 7 = step 0)
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+// The DACs map digital 0..15 to analog +1..-1
+fn dac_level(digital: u8) -> f32 {
+    1.0 - digital as f32 / 7.5
+}
+
+// NRx4 write while the frame sequencer's next step does not clock lengths:
+// turning length enable on clocks the counter once, which stops the channel
+// if it reaches zero and this write does not also trigger it
+fn extra_length_clock(counter: &mut u16, enabled: &mut bool, old: u8, val: u8) {
+    if old & 0x40 == 0 && val & 0x40 != 0 && *counter > 0 {
+        *counter -= 1;
+        if *counter == 0 && val & 0x80 == 0 {
+            *enabled = false;
+        }
+    }
+}
+
+// Trigger: an expired length counter reloads with the maximum, less the
+// extra clock when length is enabled in the same half of the period
+fn reload_length(counter: &mut u16, max: u16, nrx4: u8, extra_clock: bool) {
+    if *counter == 0 {
+        *counter = if nrx4 & 0x40 != 0 && extra_clock { max - 1 } else { max };
+    }
+}
+
+// Volume envelope (NRx2). Period and direction are latched on trigger;
+// later NRx2 writes only take effect on the next trigger
+#[derive(Default, Serialize, Deserialize)]
+struct Envelope {
+    volume: u8,
+    period: u8,
+    increase: bool,
+    timer: u8,
+}
+
+impl Envelope {
+    fn trigger(&mut self, nrx2: u8) {
+        self.volume = nrx2 >> 4;
+        self.period = nrx2 & 0x07;
+        self.increase = nrx2 & 0x08 != 0;
+        self.timer = self.period;
+    }
+
+    // One step every `period` clocks until the volume reaches 0 or 15;
+    // period 0 leaves the volume fixed
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+// Channel 1 frequency sweep (NR10)
+#[derive(Default, Serialize, Deserialize)]
+struct Sweep {
+    enabled: bool,
+    shadow: u16,
+    timer: u8,
+    // A calculation in negate mode has happened since the last trigger
+    negate_used: bool,
+}
+
+// Square channel; `base` is the IO offset of its NRx0 register
+#[derive(Serialize, Deserialize)]
+struct PulseChannel {
+    base: usize,
+    enabled: bool,
+    length_counter: u16,
+    timer: u32,
+    duty_pos: u8,
+    envelope: Envelope,
+}
+
+impl PulseChannel {
+    fn new(base: usize) -> Self {
+        Self {
+            base,
+            enabled: false,
+            length_counter: 0,
+            timer: 0,
+            duty_pos: 0,
+            envelope: Envelope::default(),
+        }
+    }
+
+    fn reg(&self, regs: &[u8; 0x30], n: usize) -> u8 {
+        regs[self.base - 0x10 + n]
+    }
+
+    // NRx2 upper five bits all zero turns the DAC off
+    fn dac_enabled(&self, regs: &[u8; 0x30]) -> bool {
+        self.reg(regs, 2) & 0xf8 != 0
+    }
+
+    // T-cycles per duty step: (2048 - period) * 4
+    fn timer_period(&self, regs: &[u8; 0x30]) -> u32 {
+        let freq = self.reg(regs, 3) as u32 | ((self.reg(regs, 4) as u32 & 0x07) << 8);
+        (2048 - freq) * 4
+    }
+
+    fn trigger(&mut self, regs: &[u8; 0x30], extra_clock: bool) {
+        self.enabled = self.dac_enabled(regs);
+        let nrx4 = self.reg(regs, 4);
+        reload_length(&mut self.length_counter, 64, nrx4, extra_clock);
+        self.timer = self.timer_period(regs);
+        self.envelope.trigger(self.reg(regs, 2));
+    }
+
+    fn step(&mut self, regs: &[u8; 0x30], cycles: u32) {
+        let period = self.timer_period(regs);
+        let mut remaining = cycles;
+        while remaining > 0 {
+            if self.timer == 0 {
+                self.timer = period;
+            }
+            if self.timer <= remaining {
+                remaining -= self.timer;
+                self.timer = period;
+                self.duty_pos = (self.duty_pos + 1) & 7;
+            } else {
+                self.timer -= remaining;
+                remaining = 0;
+            }
+        }
+    }
+
+    fn clock_length(&mut self, regs: &[u8; 0x30]) {
+        if self.reg(regs, 4) & 0x40 != 0 && self.length_counter > 0 {
+            self.length_counter -= 1;
+            if self.length_counter == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    // Digital output 0..15
+    fn output(&self, regs: &[u8; 0x30]) -> u8 {
+        let duty = (self.reg(regs, 1) >> 6) as usize;
+        let high = (DUTY_PATTERNS[duty] >> (7 - self.duty_pos)) & 1 != 0;
+        if self.enabled && high { self.envelope.volume } else { 0 }
+    }
+
+    // A disabled DAC outputs nothing, while a channel stopped by its length
+    // counter still holds the DAC's level
+    fn dac_output(&self, regs: &[u8; 0x30]) -> f32 {
+        if !self.dac_enabled(regs) {
+            return 0.0;
+        }
+        dac_level(self.output(regs))
+    }
+}
+
+// Channel 3: plays the 32 4-bit samples of wave RAM (0xFF30-0xFF3F)
+#[derive(Default, Serialize, Deserialize)]
+struct WaveChannel {
+    enabled: bool,
+    length_counter: u16,
+    timer: u32,
+    position: u8,
+    // Last sample read from wave RAM; trigger does not refill it
+    sample_buffer: u8,
+}
+
+impl WaveChannel {
+    // NR30 bit 7
+    fn dac_enabled(regs: &[u8; 0x30]) -> bool {
+        regs[0x0a] & 0x80 != 0
+    }
+
+    // T-cycles per sample: (2048 - period) * 2
+    fn timer_period(regs: &[u8; 0x30]) -> u32 {
+        let freq = regs[0x0d] as u32 | ((regs[0x0e] as u32 & 0x07) << 8);
+        (2048 - freq) * 2
+    }
+
+    fn trigger(&mut self, regs: &[u8; 0x30], extra_clock: bool) {
+        self.enabled = Self::dac_enabled(regs);
+        reload_length(&mut self.length_counter, 256, regs[0x0e], extra_clock);
+        self.timer = Self::timer_period(regs);
+        self.position = 0;
+    }
+
+    fn step(&mut self, regs: &[u8; 0x30], cycles: u32) {
+        if !self.enabled {
+            return;
+        }
+        let period = Self::timer_period(regs);
+        let mut remaining = cycles;
+        while remaining > 0 {
+            if self.timer == 0 {
+                self.timer = period;
+            }
+            if self.timer <= remaining {
+                remaining -= self.timer;
+                self.timer = period;
+                self.position = (self.position + 1) & 31;
+                // High nibble first
+                let byte = regs[0x20 + (self.position >> 1) as usize];
+                self.sample_buffer = if self.position & 1 == 0 { byte >> 4 } else { byte & 0x0f };
+            } else {
+                self.timer -= remaining;
+                remaining = 0;
+            }
+        }
+    }
+
+    fn clock_length(&mut self, regs: &[u8; 0x30]) {
+        if regs[0x0e] & 0x40 != 0 && self.length_counter > 0 {
+            self.length_counter -= 1;
+            if self.length_counter == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    // NR32 output level: mute, 100%, 50%, 25%
+    fn output(&self, regs: &[u8; 0x30]) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        match (regs[0x0c] >> 5) & 0x03 {
+            0 => 0,
+            level => self.sample_buffer >> (level - 1),
+        }
+    }
+
+    fn dac_output(&self, regs: &[u8; 0x30]) -> f32 {
+        if !Self::dac_enabled(regs) {
+            return 0.0;
+        }
+        dac_level(self.output(regs))
+    }
+}
+
+// NR43 divisor codes 0..7 in T-cycles
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Channel 4: pseudo-random noise from a 15-bit LFSR (NR41-NR44)
+#[derive(Serialize, Deserialize)]
+struct NoiseChannel {
+    enabled: bool,
+    length_counter: u16,
+    timer: u32,
+    lfsr: u16,
+    envelope: Envelope,
+}
+
+impl NoiseChannel {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            length_counter: 0,
+            timer: 0,
+            lfsr: 0x7fff,
+            envelope: Envelope::default(),
+        }
+    }
+
+    // NR42 upper five bits all zero turns the DAC off
+    fn dac_enabled(regs: &[u8; 0x30]) -> bool {
+        regs[0x11] & 0xf8 != 0
+    }
+
+    // T-cycles per LFSR clock: divisor << shift
+    fn timer_period(regs: &[u8; 0x30]) -> u32 {
+        let nr43 = regs[0x12];
+        NOISE_DIVISORS[(nr43 & 0x07) as usize] << (nr43 >> 4)
+    }
+
+    fn trigger(&mut self, regs: &[u8; 0x30], extra_clock: bool) {
+        self.enabled = Self::dac_enabled(regs);
+        reload_length(&mut self.length_counter, 64, regs[0x13], extra_clock);
+        self.timer = Self::timer_period(regs);
+        self.lfsr = 0x7fff;
+        self.envelope.trigger(regs[0x11]);
+    }
+
+    // XOR the two low bits into bit 14 (and bit 6 too in 7-bit mode)
+    fn clock_lfsr(&mut self, short_mode: bool) {
+        let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (bit << 14);
+        if short_mode {
+            self.lfsr = (self.lfsr & !0x40) | (bit << 6);
+        }
+    }
+
+    fn step(&mut self, regs: &[u8; 0x30], cycles: u32) {
+        // Shift 14 and 15 leave the LFSR unclocked
+        if !self.enabled || regs[0x12] >> 4 >= 14 {
+            return;
+        }
+        let period = Self::timer_period(regs);
+        let short_mode = regs[0x12] & 0x08 != 0;
+        let mut remaining = cycles;
+        while remaining > 0 {
+            if self.timer == 0 {
+                self.timer = period;
+            }
+            if self.timer <= remaining {
+                remaining -= self.timer;
+                self.timer = period;
+                self.clock_lfsr(short_mode);
+            } else {
+                self.timer -= remaining;
+                remaining = 0;
+            }
+        }
+    }
+
+    fn clock_length(&mut self, regs: &[u8; 0x30]) {
+        if regs[0x13] & 0x40 != 0 && self.length_counter > 0 {
+            self.length_counter -= 1;
+            if self.length_counter == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    // Output is high while LFSR bit 0 is clear
+    fn output(&self) -> u8 {
+        if self.enabled && self.lfsr & 1 == 0 { self.envelope.volume } else { 0 }
+    }
+
+    fn dac_output(&self, regs: &[u8; 0x30]) -> f32 {
+        if !Self::dac_enabled(regs) {
+            return 0.0;
+        }
+        dac_level(self.output())
+    }
+}
+
+// Health of the drain ring: frames queued, drains asking for more than was
+// queued, and frames dropped because the ring was full
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct AudioStats {
+    pub buffered: usize,
+    pub underruns: u32,
+    pub overruns: u32,
+}
+
+// Save states carry the sound hardware; the output stream fields below the
+// sequencer belong to the frontend and are skipped
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Serialize, Deserialize)]
 pub struct APU {
-    accum_cycles: u32,
+    // Raw register file for 0xFF10-0xFF3F (wave RAM included)
+    #[serde(with = "BigArray")]
+    regs: [u8; 0x30],
+    powered: bool,
+    ch1: PulseChannel,
+    ch2: PulseChannel,
+    ch3: WaveChannel,
+    ch4: NoiseChannel,
+    sweep: Sweep,
+    // Next frame sequencer step to run (0..7)
+    frame_seq_step: u8,
+    // Output sampling: sample_clock accumulates cycles * output rate
+    #[serde(skip)]
+    sample_rate: u32,
+    // Raw output samples at NATIVE_SAMPLE_RATE instead of sample_rate,
+    // leaving resampling to the embedder
+    #[serde(skip)]
+    raw_output: bool,
+    #[serde(skip)]
+    sample_clock: u64,
+    #[serde(skip)]
+    samples: Vec<f32>,
+    // Stereo frames queued for apu_drain; holds buffer_size frames (one
+    // second of output when 0), dropping the oldest when a consumer falls
+    // behind
+    #[serde(skip)]
+    ring: VecDeque<[f32; 2]>,
+    #[serde(skip)]
+    buffer_size: usize,
+    #[serde(skip)]
+    dropped_frames: u32,
+    #[serde(skip)]
+    underruns: u32,
+    // High-pass filters (left, right) removing the DAC's DC offset
+    #[serde(skip)]
+    hpf_capacitor: [f32; 2],
+    #[serde(skip)]
+    hpf_charge: f32,
 }
 
 impl APU {
     pub fn new() -> Self {
         Self {
-            accum_cycles: 0,
+            regs: [0; 0x30],
+            powered: true,
+            ch1: PulseChannel::new(0x10),
+            ch2: PulseChannel::new(0x15),
+            ch3: WaveChannel::default(),
+            ch4: NoiseChannel::new(),
+            sweep: Sweep::default(),
+            frame_seq_step: 0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            raw_output: false,
+            sample_clock: 0,
+            samples: Vec::with_capacity(1024),
+            ring: VecDeque::with_capacity(DEFAULT_SAMPLE_RATE as usize),
+            buffer_size: 0,
+            dropped_frames: 0,
+            underruns: 0,
+            hpf_capacitor: [0.0; 2],
+            hpf_charge: Self::hpf_charge(DEFAULT_SAMPLE_RATE),
         }
     }
 
     pub fn reset(&mut self) {
-        self.accum_cycles = 0;
+        self.regs.fill(0);
+        self.powered = true;
+        self.ch1 = PulseChannel::new(0x10);
+        self.ch2 = PulseChannel::new(0x15);
+        self.ch3 = WaveChannel::default();
+        self.ch4 = NoiseChannel::new();
+        self.sweep = Sweep::default();
+        self.frame_seq_step = 0;
+        self.sample_clock = 0;
+        self.samples.clear();
+        self.ring.clear();
+        self.dropped_frames = 0;
+        self.underruns = 0;
+        self.hpf_capacitor = [0.0; 2];
+    }
+
+    // Take the sound hardware from a save state; the output stream keeps
+    // going at its current rate
+    pub fn load_state(&mut self, mut saved: APU) {
+        saved.sample_rate = self.sample_rate;
+        saved.raw_output = self.raw_output;
+        saved.sample_clock = self.sample_clock;
+        saved.samples = std::mem::take(&mut self.samples);
+        saved.ring = std::mem::take(&mut self.ring);
+        saved.buffer_size = self.buffer_size;
+        saved.dropped_frames = self.dropped_frames;
+        saved.underruns = self.underruns;
+        saved.hpf_capacitor = self.hpf_capacitor;
+        saved.hpf_charge = self.hpf_charge;
+        *self = saved;
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        if self.powered {
+            self.ch1.step(&self.regs, cycles);
+            self.ch2.step(&self.regs, cycles);
+            self.ch3.step(&self.regs, cycles);
+            self.ch4.step(&self.regs, cycles);
+        }
+
+        let rate = self.output_rate();
+        self.sample_clock += cycles as u64 * rate as u64;
+        while self.sample_clock >= CPU_CLOCK as u64 {
+            self.sample_clock -= CPU_CLOCK as u64;
+            let [left, right] = self.mix();
+            // Keep at most one second of audio if the frontend stops pulling
+            if self.samples.len() < rate as usize * 2 {
+                self.samples.push(left);
+                self.samples.push(right);
+            }
+            if self.ring.len() >= self.ring_capacity() {
+                self.ring.pop_front();
+                self.dropped_frames = self.dropped_frames.wrapping_add(1);
+            }
+            self.ring.push_back([left, right]);
+        }
     }
 
-    pub fn step(&mut self, mmu: &MMU, cycles: u32) {
-        // Track timing and master enable
-        self.accum_cycles = self.accum_cycles.wrapping_add(cycles);
-        
-        // Read NR52 (master enable)
-        let _ = self.is_enabled(mmu);
-        
-        // Bound counter
-        if self.accum_cycles > (1 << 20) {
-            self.accum_cycles &= (1 << 20) - 1;
+    // Output rate in Hz, clamped to 8000..=96000. Safe between frames: the
+    // sample accumulator restarts so no burst of samples is produced
+    pub fn set_sample_rate(&mut self, hz: u32) {
+        self.sample_rate = hz.clamp(MIN_SAMPLE_RATE, MAX_SAMPLE_RATE);
+        self.sample_clock = 0;
+        // Queued audio was produced at the old rate
+        self.ring.clear();
+        self.hpf_charge = Self::hpf_charge(self.output_rate());
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Switch between resampled output at sample_rate and raw output at
+    // NATIVE_SAMPLE_RATE; queued audio is dropped like on a rate change
+    pub fn set_raw_output(&mut self, enabled: bool) {
+        self.raw_output = enabled;
+        self.sample_clock = 0;
+        self.ring.clear();
+        self.hpf_charge = Self::hpf_charge(self.output_rate());
+    }
+
+    pub fn raw_output(&self) -> bool {
+        self.raw_output
+    }
+
+    // Drain ring size in stereo frames; 0 restores the default of one
+    // second of output. Shrinking drops the oldest frames as overruns
+    pub fn set_buffer_size(&mut self, frames: usize) {
+        self.buffer_size = frames;
+        while self.ring.len() > self.ring_capacity() {
+            self.ring.pop_front();
+            self.dropped_frames = self.dropped_frames.wrapping_add(1);
+        }
+    }
+
+    fn ring_capacity(&self) -> usize {
+        if self.buffer_size == 0 { self.output_rate() as usize } else { self.buffer_size }
+    }
+
+    // Rate the sample buffer and ring are filled at
+    pub fn output_rate(&self) -> u32 {
+        if self.raw_output { NATIVE_SAMPLE_RATE } else { self.sample_rate }
+    }
+
+    // Per-sample decay of the high-pass filter capacitor
+    fn hpf_charge(sample_rate: u32) -> f32 {
+        0.999958f32.powf(CPU_CLOCK as f32 / sample_rate as f32)
+    }
+
+    // One 512 Hz frame sequencer tick, driven by the timer's DIV bit:
+    // length counters on steps 0/2/4/6 (256 Hz), sweep on 2/6 (128 Hz),
+    // envelopes on 7 (64 Hz)
+    pub fn clock_frame_sequencer(&mut self) {
+        if !self.powered {
+            return;
+        }
+        let step = self.frame_seq_step;
+        if step & 1 == 0 {
+            self.ch1.clock_length(&self.regs);
+            self.ch2.clock_length(&self.regs);
+            self.ch3.clock_length(&self.regs);
+            self.ch4.clock_length(&self.regs);
+        }
+        if step == 2 || step == 6 {
+            self.clock_sweep();
+        }
+        if step == 7 {
+            self.ch1.envelope.clock();
+            self.ch2.envelope.clock();
+            self.ch4.envelope.clock();
+        }
+        self.frame_seq_step = (step + 1) & 7;
+    }
+
+    // NR10 sweep period; 0 reloads the timer with 8
+    fn sweep_period(&self) -> u8 {
+        match (self.regs[0] >> 4) & 0x07 {
+            0 => 8,
+            p => p,
+        }
+    }
+
+    // Next sweep frequency from the shadow register; going past 0x7FF
+    // disables channel 1
+    fn sweep_calc(&mut self) -> u16 {
+        let nr10 = self.regs[0];
+        let delta = self.sweep.shadow >> (nr10 & 0x07);
+        let freq = if nr10 & 0x08 != 0 {
+            self.sweep.negate_used = true;
+            self.sweep.shadow - delta
+        } else {
+            self.sweep.shadow + delta
+        };
+        if freq > 0x7ff {
+            self.ch1.enabled = false;
+        }
+        freq
+    }
+
+    fn trigger_sweep(&mut self) {
+        let nr10 = self.regs[0];
+        let period = (nr10 >> 4) & 0x07;
+        let shift = nr10 & 0x07;
+        self.sweep.shadow = self.regs[3] as u16 | ((self.regs[4] as u16 & 0x07) << 8);
+        self.sweep.timer = self.sweep_period();
+        self.sweep.enabled = period != 0 || shift != 0;
+        self.sweep.negate_used = false;
+        // With a non-zero shift the overflow check runs immediately
+        if shift != 0 {
+            self.sweep_calc();
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep.timer > 0 {
+            self.sweep.timer -= 1;
+        }
+        if self.sweep.timer != 0 {
+            return;
+        }
+        self.sweep.timer = self.sweep_period();
+        let period = (self.regs[0] >> 4) & 0x07;
+        if !self.sweep.enabled || period == 0 {
+            return;
+        }
+        // Shift 0 still runs the calculation (and its overflow check) but
+        // never writes the result back
+        let freq = self.sweep_calc();
+        if freq <= 0x7ff && self.regs[0] & 0x07 != 0 {
+            self.sweep.shadow = freq;
+            self.regs[3] = freq as u8;
+            self.regs[4] = (self.regs[4] & 0xf8) | (freq >> 8) as u8;
+            // Second overflow check with the new frequency
+            self.sweep_calc();
+        }
+    }
+
+    // NR51 routes each channel to the left (bits 4-7) and/or right (bits
+    // 0-3) terminal; NR50 scales each side by (volume + 1) / 8. Vin is
+    // not emulated.
+    fn mix(&mut self) -> [f32; 2] {
+        if !self.powered {
+            return [0.0; 2];
+        }
+        let outputs = [
+            self.ch1.dac_output(&self.regs),
+            self.ch2.dac_output(&self.regs),
+            self.ch3.dac_output(&self.regs),
+            self.ch4.dac_output(&self.regs),
+        ];
+        let nr50 = self.regs[0x14];
+        let nr51 = self.regs[0x15];
+        let mut mixed = [0.0f32; 2];
+        for (side, shift) in [(0, 4), (1, 0)] {
+            let routing = nr51 >> shift;
+            let sum: f32 = (0..4).filter(|ch| routing & (1 << ch) != 0).map(|ch| outputs[ch]).sum();
+            let volume = ((nr50 >> shift) & 0x07) as f32 + 1.0;
+            let input = sum / 4.0 * volume / 8.0;
+            let out = input - self.hpf_capacitor[side];
+            self.hpf_capacitor[side] = input - out * self.hpf_charge;
+            mixed[side] = out;
+        }
+        mixed
+    }
+
+    pub fn read_register(&self, offset: usize) -> u8 {
+        match offset {
+            0x26 => {
+                0x70 | (self.powered as u8) << 7
+                    | self.ch1.enabled as u8
+                    | (self.ch2.enabled as u8) << 1
+                    | (self.ch3.enabled as u8) << 2
+                    | (self.ch4.enabled as u8) << 3
+            }
+            0x10..=0x2f => self.regs[offset - 0x10] | READ_MASKS[offset - 0x10],
+            0x30..=0x3f => self.regs[offset - 0x10],
+            _ => 0xff,
+        }
+    }
+
+    pub fn write_register(&mut self, offset: usize, val: u8) {
+        match offset {
+            0x26 => self.set_power(val & 0x80 != 0),
+            // Wave RAM stays accessible with the APU off
+            0x30..=0x3f => self.regs[offset - 0x10] = val,
+            0x10..=0x25 if self.powered => {
+                let old = self.regs[offset - 0x10];
+                self.regs[offset - 0x10] = val;
+                self.write_channel_register(offset, old, val);
+                match offset {
+                    // Leaving negate mode after a negate calculation kills channel 1
+                    0x10 if val & 0x08 == 0 && self.sweep.negate_used => self.ch1.enabled = false,
+                    0x14 if val & 0x80 != 0 => self.trigger_sweep(),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Load a register value without side effects (no trigger), e.g. the
+    // post-boot state
+    pub fn restore_register(&mut self, offset: usize, val: u8) {
+        match offset {
+            0x26 => {
+                self.powered = val & 0x80 != 0;
+                self.ch1.enabled = val & 0x01 != 0;
+                self.ch2.enabled = val & 0x02 != 0;
+                self.ch3.enabled = val & 0x04 != 0;
+                self.ch4.enabled = val & 0x08 != 0;
+            }
+            0x10..=0x3f => self.regs[offset - 0x10] = val,
+            _ => {}
+        }
+    }
+
+    // Trigger event (NRx4 bit 7): the length counter reloads if it expired,
+    // the frequency timer and envelope restart from the registers, noise
+    // resets its LFSR, wave restarts at sample 0 and channel 1 reloads its
+    // sweep. A channel whose DAC is off stays disabled
+    fn write_channel_register(&mut self, offset: usize, old: u8, val: u8) {
+        // The next frame sequencer step is odd, so it will not clock lengths
+        let extra_clock = self.frame_seq_step & 1 != 0;
+        match offset {
+            0x1a if !WaveChannel::dac_enabled(&self.regs) => self.ch3.enabled = false,
+            0x1b => self.ch3.length_counter = 256 - val as u16,
+            0x1e => {
+                if extra_clock {
+                    extra_length_clock(&mut self.ch3.length_counter, &mut self.ch3.enabled, old, val);
+                }
+                if val & 0x80 != 0 {
+                    self.ch3.trigger(&self.regs, extra_clock);
+                }
+            }
+            0x20 => self.ch4.length_counter = 64 - (val & 0x3f) as u16,
+            0x21 if !NoiseChannel::dac_enabled(&self.regs) => self.ch4.enabled = false,
+            0x23 => {
+                if extra_clock {
+                    extra_length_clock(&mut self.ch4.length_counter, &mut self.ch4.enabled, old, val);
+                }
+                if val & 0x80 != 0 {
+                    self.ch4.trigger(&self.regs, extra_clock);
+                }
+            }
+            _ => {}
+        }
+        let ch = match offset {
+            0x10..=0x14 => &mut self.ch1,
+            0x15..=0x19 => &mut self.ch2,
+            _ => return,
+        };
+        match offset - ch.base {
+            1 => ch.length_counter = 64 - (val & 0x3f) as u16,
+            2 if !ch.dac_enabled(&self.regs) => ch.enabled = false,
+            4 => {
+                if extra_clock {
+                    extra_length_clock(&mut ch.length_counter, &mut ch.enabled, old, val);
+                }
+                if val & 0x80 != 0 {
+                    ch.trigger(&self.regs, extra_clock);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn set_power(&mut self, on: bool) {
+        if self.powered && !on {
+            // Powering off clears every sound register and stops all channels
+            self.regs[..0x16].fill(0);
+            self.ch1 = PulseChannel::new(0x10);
+            self.ch2 = PulseChannel::new(0x15);
+            self.ch3 = WaveChannel::default();
+            self.ch4 = NoiseChannel::new();
+            self.sweep = Sweep::default();
+        } else if !self.powered && on {
+            // Power on restarts the frame sequencer at step 0
+            self.frame_seq_step = 0;
+        }
+        self.powered = on;
+    }
+
+    // Frame sequencer step (0..7) that the next 512 Hz tick will run
+    pub fn frame_sequencer_step(&self) -> u8 {
+        self.frame_seq_step
+    }
+
+    // Current envelope volume (0..15) of channel 1-4; 0 for channels that
+    // are off or have no envelope
+    pub fn channel_volume(&self, channel: u8) -> u8 {
+        let (enabled, envelope) = match channel {
+            1 => (self.ch1.enabled, &self.ch1.envelope),
+            2 => (self.ch2.enabled, &self.ch2.envelope),
+            4 => (self.ch4.enabled, &self.ch4.envelope),
+            _ => return 0,
+        };
+        if enabled { envelope.volume } else { 0 }
+    }
+
+    // Samples generated since the last clear: interleaved left/right f32
+    // pairs at the output rate
+    pub fn get_sample_buffer(&self) -> &[f32] {
+        &self.samples
+    }
+
+    // CGB PCM12 (0xFF76): digital outputs of channel 1 (low) and 2 (high)
+    pub fn pcm12(&self) -> u8 {
+        self.ch1.output(&self.regs) | self.ch2.output(&self.regs) << 4
+    }
+
+    // CGB PCM34 (0xFF77): digital outputs of channel 3 (low) and 4 (high)
+    pub fn pcm34(&self) -> u8 {
+        self.ch3.output(&self.regs) | self.ch4.output() << 4
+    }
+
+    // Stereo frames waiting in the ring buffer
+    pub fn samples_available(&self) -> usize {
+        self.ring.len()
+    }
+
+    // Remove up to `max` of the oldest queued frames, interleaved left/right.
+    // Asking for more than is queued counts as an underrun
+    pub fn drain(&mut self, max: usize) -> Vec<f32> {
+        if max > self.ring.len() {
+            self.underruns = self.underruns.wrapping_add(1);
+        }
+        let count = max.min(self.ring.len());
+        self.ring.drain(..count).flatten().collect()
+    }
+
+    // Frames discarded because the ring buffer was full
+    pub fn dropped_frames(&self) -> u32 {
+        self.dropped_frames
+    }
+
+    pub fn stats(&self) -> AudioStats {
+        AudioStats { buffered: self.ring.len(), underruns: self.underruns, overruns: self.dropped_frames }
+    }
+
+    pub fn clear_samples(&mut self) {
+        self.samples.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // T-cycles in one video frame
+    const FRAME_CYCLES: u32 = 70224;
+
+    #[test]
+    fn raw_output_samples_at_native_rate() {
+        let mut apu = APU::new();
+        apu.set_raw_output(true);
+        apu.step(FRAME_CYCLES);
+        let expected = (FRAME_CYCLES as u64 * NATIVE_SAMPLE_RATE as u64 / CPU_CLOCK as u64) as usize;
+        assert_eq!(expected, 17556);
+        assert_eq!(apu.samples_available(), expected);
+        assert_eq!(apu.get_sample_buffer().len(), expected * 2);
+
+        apu.set_raw_output(false);
+        apu.clear_samples();
+        apu.step(FRAME_CYCLES);
+        assert_eq!(apu.samples_available(), 738);
+    }
+
+    #[test]
+    fn draining_past_the_buffer_counts_underruns() {
+        let mut apu = APU::new();
+        apu.step(FRAME_CYCLES);
+        let buffered = apu.samples_available();
+        assert_eq!(apu.stats(), AudioStats { buffered, underruns: 0, overruns: 0 });
+        apu.drain(buffered / 2);
+        assert_eq!(apu.stats().underruns, 0);
+        assert_eq!(apu.drain(buffered).len(), (buffered - buffered / 2) * 2);
+        assert_eq!(apu.stats(), AudioStats { buffered: 0, underruns: 1, overruns: 0 });
+    }
+
+    #[test]
+    fn small_buffer_counts_overruns() {
+        let mut apu = APU::new();
+        apu.set_buffer_size(100);
+        apu.step(FRAME_CYCLES);
+        assert_eq!(apu.stats(), AudioStats { buffered: 100, underruns: 0, overruns: 638 });
+    }
+
+    #[test]
+    fn expired_length_keeps_dac_level() {
+        let mut apu = APU::new();
+        // Channel 2: DAC on at volume 15, length 1, trigger with length enabled
+        apu.write_register(0x17, 0xf0);
+        apu.write_register(0x16, 0x3f);
+        apu.write_register(0x19, 0xc0);
+        assert_eq!(apu.read_register(0x26) & 0x02, 0x02);
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.read_register(0x26) & 0x02, 0);
+        // Status is off but the DAC still drives its level for digital 0
+        assert_eq!(apu.ch2.dac_output(&apu.regs), dac_level(0));
+        assert_ne!(apu.ch2.dac_output(&apu.regs), 0.0);
+        apu.write_register(0x17, 0x00);
+        assert_eq!(apu.ch2.dac_output(&apu.regs), 0.0);
+    }
+
+    #[test]
+    fn frame_sequencer_clocks_units_per_step() {
+        let mut apu = APU::new();
+        apu.write_register(0x26, 0x80);
+        // Channel 1: sweep period 7 shift 0, envelope up every clock from
+        // volume 0, length 64 enabled
+        apu.write_register(0x10, 0x70);
+        apu.write_register(0x11, 0x00);
+        apu.write_register(0x12, 0x09);
+        apu.write_register(0x14, 0xc0);
+        let mut clocked = Vec::new();
+        for _ in 0..8 {
+            let before = (apu.ch1.length_counter, apu.sweep.timer, apu.ch1.envelope.volume);
+            apu.clock_frame_sequencer();
+            let mut units = String::new();
+            if apu.ch1.length_counter != before.0 {
+                units.push('L');
+            }
+            if apu.sweep.timer != before.1 {
+                units.push('S');
+            }
+            if apu.ch1.envelope.volume != before.2 {
+                units.push('E');
+            }
+            clocked.push(units);
+        }
+        assert_eq!(clocked, ["L", "", "LS", "", "L", "", "LS", "E"]);
+    }
+
+    #[test]
+    fn sweep_overflow_disables_channel_1() {
+        let mut apu = APU::new();
+        apu.write_register(0x26, 0x80);
+        apu.write_register(0x12, 0xf0);
+        // Sweep up, shift 1: period 0x7FF overflows in the trigger check
+        apu.write_register(0x10, 0x11);
+        apu.write_register(0x13, 0xff);
+        apu.write_register(0x14, 0x87);
+        assert_eq!(apu.read_register(0x26) & 0x01, 0);
+
+        // 0x500 passes at trigger; the first sweep clock writes back 0x780
+        // and the follow-up check overflows
+        apu.write_register(0x13, 0x00);
+        apu.write_register(0x14, 0x85);
+        assert_eq!(apu.read_register(0x26) & 0x01, 0x01);
+        apu.clock_frame_sequencer();
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.read_register(0x26) & 0x01, 0x01);
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.read_register(0x26) & 0x01, 0);
+        assert_eq!((apu.regs[3], apu.regs[4] & 0x07), (0x80, 0x07));
+    }
+
+    #[test]
+    fn lfsr_sequence_and_short_mode_period() {
+        let mut noise = NoiseChannel::new();
+        let bits: String = (0..48)
+            .map(|_| {
+                noise.clock_lfsr(false);
+                if noise.lfsr & 1 != 0 { '1' } else { '0' }
+            })
+            .collect();
+        assert_eq!(bits, "111111111111110000000000000010000000000000110000");
+
+        // Clocks until the state repeats. 7-bit mode never returns to the
+        // all-ones seed, so count from a state that is already in the cycle
+        let period = |short_mode: bool| {
+            let mut noise = NoiseChannel::new();
+            for _ in 0..15 {
+                noise.clock_lfsr(short_mode);
+            }
+            let start = noise.lfsr;
+            (1..=0x8000).find(|_| {
+                noise.clock_lfsr(short_mode);
+                noise.lfsr == start
+            })
+        };
+        assert_eq!(period(false), Some(32767));
+        assert_eq!(period(true), Some(127));
+    }
+
+    #[test]
+    fn channel_routed_left_leaves_right_silent() {
+        let mut apu = APU::new();
+        apu.write_register(0x26, 0x80);
+        apu.write_register(0x24, 0x77);
+        // Channel 2 to the left terminal only
+        apu.write_register(0x25, 0x20);
+        apu.write_register(0x16, 0x80);
+        apu.write_register(0x17, 0xf0);
+        apu.write_register(0x18, 0x00);
+        apu.write_register(0x19, 0x87);
+        apu.step(FRAME_CYCLES);
+        let samples = apu.get_sample_buffer();
+        assert!(!samples.is_empty());
+        assert!(samples.chunks(2).all(|lr| lr[1] == 0.0));
+        assert!(samples.chunks(2).any(|lr| lr[0] != 0.0));
+    }
+
+    #[test]
+    fn one_second_yields_sample_rate_samples() {
+        for (requested, rate) in [(48000, 48000), (22050, 22050), (1000, 8000), (200_000, 96000)] {
+            let mut apu = APU::new();
+            apu.set_sample_rate(requested);
+            assert_eq!(apu.sample_rate(), rate);
+            let mut produced = 0;
+            for _ in 0..CPU_CLOCK / 4096 {
+                apu.step(4096);
+                produced += apu.samples_available();
+                apu.drain(apu.samples_available());
+            }
+            assert!(produced.abs_diff(rate as usize) <= 1, "{} Hz gave {}", rate, produced);
+        }
+    }
+
+    #[test]
+    fn ring_keeps_newest_frames_in_order() {
+        let mut apu = APU::new();
+        apu.write_register(0x26, 0x80);
+        apu.write_register(0x24, 0x77);
+        apu.write_register(0x25, 0x22);
+        apu.write_register(0x17, 0xf0);
+        apu.write_register(0x18, 0x00);
+        apu.write_register(0x19, 0x87);
+        apu.set_buffer_size(100);
+        // The per-frame sample buffer keeps the whole stream to compare with
+        apu.step(FRAME_CYCLES);
+        let stream = apu.get_sample_buffer().to_vec();
+        assert_eq!(stream.len(), 738 * 2);
+        assert!(stream.iter().any(|&s| s != stream[0]));
+        assert_eq!(apu.dropped_frames(), 638);
+        assert_eq!(apu.drain(30), stream[638 * 2..668 * 2]);
+
+        // New frames fill the space freed by the drain, then push out the
+        // oldest ones again
+        apu.step(8192);
+        let stream = apu.get_sample_buffer().to_vec();
+        let total = stream.len() / 2;
+        assert_eq!(total, 738 + 86);
+        assert_eq!(apu.samples_available(), 100);
+        assert_eq!(apu.dropped_frames(), 638 + 56);
+        assert_eq!(apu.drain(100), stream[(total - 100) * 2..]);
+        assert_eq!(apu.stats().underruns, 0);
+    }
+
+    #[test]
+    fn trigger_reloads_an_expired_length() {
+        let mut apu = APU::new();
+        apu.write_register(0x26, 0x80);
+        // Channel 2: length 63 of 64 used up by one length clock
+        apu.write_register(0x17, 0xf0);
+        apu.write_register(0x16, 0x3f);
+        apu.write_register(0x19, 0xc0);
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.ch2.length_counter, 0);
+        assert_eq!(apu.read_register(0x26) & 0x02, 0);
+
+        // Triggering again on an even step loads the full 64 and starts it
+        // playing; 64 length clocks then take 127 sequencer steps
+        apu.clock_frame_sequencer();
+        apu.write_register(0x19, 0xc0);
+        assert_eq!(apu.ch2.length_counter, 64);
+        assert_eq!(apu.ch2.envelope.volume, 15);
+        assert_eq!(apu.read_register(0x26) & 0x02, 0x02);
+        for _ in 0..126 {
+            apu.clock_frame_sequencer();
         }
+        assert_eq!(apu.read_register(0x26) & 0x02, 0x02);
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.read_register(0x26) & 0x02, 0);
+
+        // The wave channel's maximum is 256
+        apu.write_register(0x1a, 0x80);
+        apu.write_register(0x1e, 0x80);
+        assert_eq!(apu.ch3.length_counter, 256);
+        assert_eq!(apu.read_register(0x26) & 0x04, 0x04);
     }
 
-    // NR52 bit7: master enable
-    pub fn is_enabled(&self, mmu: &MMU) -> bool {
-        (mmu.read_byte(0xff26) & 0x80) != 0
+    #[test]
+    fn envelopes_step_every_period_at_64_hz() {
+        let mut apu = APU::new();
+        apu.write_register(0x26, 0x80);
+        // Channel 1 from 15 down every 3 ticks, noise from 1 up every tick
+        apu.write_register(0x12, 0xf3);
+        apu.write_register(0x14, 0x80);
+        apu.write_register(0x21, 0x19);
+        apu.write_register(0x23, 0x80);
+        for ticks in 1..=60u8 {
+            // Envelopes run on step 7 of the eight
+            for _ in 0..8 {
+                apu.clock_frame_sequencer();
+            }
+            assert_eq!(apu.channel_volume(1), 15u8.saturating_sub(ticks / 3), "tick {}", ticks);
+            assert_eq!(apu.channel_volume(4), (1 + ticks).min(15), "tick {}", ticks);
+        }
+        assert_eq!(apu.read_register(0x26) & 0x09, 0x09);
+
+        // Upper five NRx2 bits clear: DAC off, channel off at once
+        apu.write_register(0x21, 0x07);
+        assert_eq!(apu.read_register(0x26) & 0x08, 0);
+        assert_eq!(apu.channel_volume(4), 0);
     }
 }
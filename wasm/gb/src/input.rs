@@ -1,4 +1,8 @@
-// Input
+use serde::{Deserialize, Serialize};
+
+// Input: which of the eight buttons are held (active-low internally).
+// Owned by the MMU, which exposes it through JOYP
+#[derive(Serialize, Deserialize)]
 pub struct Input {
     buttons: u8,
 }
@@ -20,25 +24,12 @@ impl Input {
         self.buttons |= 1 << button;
     }
 
-    pub fn update_joypad(&self, io: &mut [u8]) {
-        let joyp = io[0x00];
-        let mut new_joyp = joyp | 0x0f;
-
-        // Group select
-        if joyp & 0x10 == 0 {
-            // D-pad
-            new_joyp &= !((self.buttons >> 4) & 0x0f);
-        }
-        if joyp & 0x20 == 0 {
-            // A/B/Select/Start
-            new_joyp &= !(self.buttons & 0x0f);
-        }
-
-        io[0x00] = new_joyp;
+    pub fn set_state(&mut self, pressed: u8) {
+        self.buttons = !pressed;
+    }
 
-        // Joypad IRQ if any pressed
-        if new_joyp & 0x0f != 0x0f {
-            io[0x0f] |= 0x10;
-        }
+    // Held buttons, bit set = pressed
+    pub fn pressed(&self) -> u8 {
+        !self.buttons
     }
 }
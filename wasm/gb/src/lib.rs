@@ -7,21 +7,37 @@ mod timer;
 mod input;
 mod ppu;
 mod apu;
+mod rtc;
+mod serial;
+mod rewind;
 
 use registers::Registers;
-use mmu::MMU;
-use timer::Timer;
-use input::Input;
-use ppu::PPU;
-use apu::APU;
+use rewind::Rewind;
+use mmu::{InvalidAccess, OamDmaState, MMU};
+use ppu::{ScanlineRegs, PPU};
+pub use ppu::FrameBufferFormat;
+pub use mmu::{DmaTiming, Model};
+use apu::{AudioStats, DEFAULT_SAMPLE_RATE, NATIVE_SAMPLE_RATE};
 
 const SCREEN_WIDTH: usize = 160;
 const SCREEN_HEIGHT: usize = 144;
+// T-cycles per frame and per second (DMG single speed)
+const CYCLES_PER_FRAME: u32 = 70224;
+const CPU_CLOCK_HZ: u32 = 4_194_304;
 
 use std::cell::RefCell;
+use std::collections::VecDeque;
+
+// Oldest events are dropped past this if the frontend stops polling
+const MAX_EVENTS: usize = 1024;
+
+// Frames of input history kept (a minute at ~60 fps)
+const MAX_INPUT_HISTORY: usize = 3600;
 
 thread_local! {
-    static GB_SINGLETON: RefCell<Option<GameBoy>> = RefCell::new(None);
+    static GB_SINGLETON: RefCell<Option<GameBoy>> = const { RefCell::new(None) };
+    // Second core on the other end of the link cable (gb_link_*)
+    static GB_LINK_PARTNER: RefCell<Option<GameBoy>> = const { RefCell::new(None) };
 }
 
 #[wasm_bindgen]
@@ -29,12 +45,11 @@ pub struct GameBoy {
     running: bool,
     mmu: MMU,
     registers: Registers,
-    timer: Timer,
-    input: Input,
     ppu: PPU,
-    apu: APU,
     cycles: u32,
     halted: bool,
+    // HALT bug: the next opcode fetch does not advance PC
+    halt_bug: bool,
     ime: bool,
     ime_scheduled: bool,
     // Debug trace of last N opcodes
@@ -42,10 +57,63 @@ pub struct GameBoy {
     trace_buf: [(u16, u8, u16); 256],
     trace_idx: usize,
     last_interrupt: Option<(u8, u16, u8, u8)>, // (interrupt id, pc before jump, IE, IF)
+    // PC of the instruction currently executing
+    instr_pc: u16,
+    // (PC, opcode, operands) of the most recent instruction, kept even
+    // with tracing off
+    last_instruction: (u16, u8, Option<u8>, Option<u8>),
+    // Set when break-on-invalid-access paused emulation
+    invalid_access: Option<InvalidAccess>,
+    // Expected (PC, opcode) sequence from a reference emulator's log
+    reference_trace: Vec<(u16, u8)>,
+    reference_idx: usize,
+    trace_divergence: Option<TraceDivergence>,
+    // Debug override ANDed with IE when picking an interrupt (not hardware)
+    interrupt_mask: u8,
+    // Slow-motion debugging: fraction of run_frame calls that emulate
+    speed_multiplier: f32,
+    speed_accum: f32,
+    should_present: bool,
+    // T-cycles actually run by the last run_frame (instructions overshoot)
+    last_frame_cycles: u32,
+    // Execution breakpoints; the PC of the one that just paused emulation
+    // is let through once so resuming does not stop again immediately
+    breakpoints: Vec<u16>,
+    breakpoint_hit: Option<u16>,
+    // Things that happened since the frontend last polled
+    events: VecDeque<Event>,
+    serial_seen: usize,
+    // Frames emulated since reset
+    frame_index: u64,
+    // Buttons held as each frame started, while recording is enabled
+    input_history_enabled: bool,
+    input_history: VecDeque<InputRecord>,
+    // Copies of the most recent completed frames, at most
+    // frame_capture_limit of them; 0 means capture is off
+    frame_capture_limit: usize,
+    captured_frames: VecDeque<Vec<u8>>,
+    // Periodic binary snapshots for rewinding
+    rewind: Rewind,
+}
+
+// Version 1 states (no version field) hold the CPU registers, OAM and any
+// OAM DMA; version 2 holds the whole machine. Newer versions are refused
+const SAVE_STATE_VERSION: u32 = 2;
+
+fn legacy_save_state_version() -> u32 {
+    1
 }
 
+// Binary save states: this magic, SAVE_STATE_VERSION as one byte, then the
+// bincode-encoded state. The layout is not self-describing, so only the
+// current version loads
+const BINARY_STATE_MAGIC: &[u8; 4] = b"CYGB";
+
+// Devices are borrowed when saving and owned when loading
 #[derive(Serialize, Deserialize)]
-struct SaveState {
+struct SaveState<M = MMU, P = PPU> {
+    #[serde(default = "legacy_save_state_version")]
+    version: u32,
     a: u8,
     f: u8,
     b: u8,
@@ -57,6 +125,112 @@ struct SaveState {
     sp: u16,
     pc: u16,
     cycles: u32,
+    // Version 1: OAM image and any OAM DMA still in flight at save time
+    #[serde(default)]
+    oam: Vec<u8>,
+    #[serde(default)]
+    oam_dma: Option<OamDmaState>,
+    // Global checksum of the ROM the state was taken from
+    #[serde(default)]
+    rom_checksum: Option<u16>,
+    // Version 2: CPU flags, frame count and every device
+    #[serde(default)]
+    halted: bool,
+    #[serde(default)]
+    halt_bug: bool,
+    #[serde(default)]
+    ime: bool,
+    #[serde(default)]
+    ime_scheduled: bool,
+    #[serde(default)]
+    frame_index: u64,
+    // Absent from version 1 states (a missing Option reads as None)
+    mmu: Option<M>,
+    ppu: Option<P>,
+}
+
+// Decoded view of IE/IF/IME for a debugger's interrupt panel.
+// Index order: 0=VBlank, 1=LCD STAT, 2=Timer, 3=Serial, 4=Joypad
+#[derive(Serialize, Deserialize)]
+pub struct InterruptState {
+    pub ie: u8,
+    pub if_: u8,
+    pub ime: bool,
+    pub pending: [bool; 5],
+    pub enabled: [bool; 5],
+}
+
+// Audio/video pacing of the last run_frame, for frontends correcting drift
+// between the ~59.73 Hz frame rate and the audio output rate
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct SyncReport {
+    pub cycles_this_frame: u32,
+    pub audio_samples_this_frame: u32,
+    pub expected_samples: f64,
+}
+
+// Build identification for bug reports
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VersionInfo {
+    pub crate_version: String,
+    pub features: Vec<String>,
+}
+
+// Subsystems compiled into this build. There are no cargo features, so
+// this only changes when the emulator gains or loses one
+const FEATURES: &[&str] = &[
+    "apu-pulse1",
+    "apu-pulse2",
+    "apu-wave",
+    "apu-noise",
+    "cgb",
+    "cgb-double-speed",
+    "hdma",
+    "accurate-oam-dma",
+    "mbc1",
+    "mbc2",
+    "mbc3-rtc",
+    "mbc5",
+    "serial",
+    "link-cable",
+    "boot-rom",
+    "save-states",
+    "rewind",
+];
+
+// Buttons held at the start of a frame; `buttons` uses the set_input_state
+// layout (bit set = pressed)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputRecord {
+    pub frame: u64,
+    pub buttons: u8,
+}
+
+// Emulation events, queued in order and drained by poll_events
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Event {
+    FrameReady,
+    BreakpointHit(u16),
+    SerialByte(u8),
+    InterruptServiced(u8),
+    // Emulation paused by break-on-invalid-access or a trace divergence
+    Stalled,
+}
+
+// First instruction that did not match the loaded reference trace
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct TraceDivergence {
+    pub index: usize,
+    pub expected_pc: u16,
+    pub expected_opcode: u8,
+    pub actual_pc: u16,
+    pub actual_opcode: u8,
+}
+
+impl Default for GameBoy {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[wasm_bindgen]
@@ -67,18 +241,37 @@ impl GameBoy {
             running: false,
             mmu: MMU::new(),
             registers: Registers::new(),
-            timer: Timer::new(),
-            input: Input::new(),
             ppu: PPU::new(),
-            apu: APU::new(),
             cycles: 0,
             halted: false,
+            halt_bug: false,
             ime: false,
             ime_scheduled: false,
             trace_enabled: false,
             trace_buf: [(0, 0, 0); 256],
             trace_idx: 0,
             last_interrupt: None,
+            instr_pc: 0,
+            last_instruction: (0, 0, None, None),
+            invalid_access: None,
+            reference_trace: Vec::new(),
+            reference_idx: 0,
+            trace_divergence: None,
+            interrupt_mask: 0x1f,
+            speed_multiplier: 1.0,
+            speed_accum: 0.0,
+            should_present: true,
+            last_frame_cycles: 0,
+            breakpoints: Vec::new(),
+            breakpoint_hit: None,
+            events: VecDeque::new(),
+            serial_seen: 0,
+            frame_index: 0,
+            input_history_enabled: false,
+            input_history: VecDeque::new(),
+            frame_capture_limit: 0,
+            captured_frames: VecDeque::new(),
+            rewind: Rewind::new(),
         }
     }
 
@@ -90,51 +283,155 @@ impl GameBoy {
     pub fn reset(&mut self) {
         self.running = false;
         self.mmu.reset();
-        self.registers = Registers::new();
-        self.timer.reset();
-        self.input.reset();
+        self.registers = if self.mmu.has_boot_rom() { Registers::zeroed() } else { Registers::new() };
+        if !self.mmu.has_boot_rom() && self.mmu.is_gbc() {
+            // A=0x11 is how games tell they are running on CGB hardware
+            self.registers.a = 0x11;
+        }
         self.ppu.reset(&mut self.mmu);
-        self.apu.reset();
         self.cycles = 0;
         self.halted = false;
+        self.halt_bug = false;
         self.ime = false;
         self.ime_scheduled = false;
         self.trace_idx = 0;
         self.trace_buf.fill((0, 0, 0));
         self.last_interrupt = None;
+        self.instr_pc = 0;
+        self.last_instruction = (0, 0, None, None);
+        self.invalid_access = None;
+        self.reference_idx = 0;
+        self.trace_divergence = None;
+        self.speed_accum = 0.0;
+        self.should_present = true;
+        self.last_frame_cycles = 0;
+        self.breakpoint_hit = None;
+        self.events.clear();
+        self.serial_seen = 0;
+        self.frame_index = 0;
+        self.input_history.clear();
+        self.captured_frames.clear();
+        self.rewind.clear();
+    }
+
+    // Reset only the PPU (scanline timing, framebuffer); CPU and memory
+    // are left alone
+    pub fn reset_ppu(&mut self) {
+        self.ppu.reset(&mut self.mmu);
     }
 
     pub fn start(&mut self) { self.running = true; }
     pub fn stop(&mut self) { self.running = false; }
     pub fn is_running(&self) -> bool { self.running }
 
+    // Slow motion for debugging, in (0, 1]: at 0.5 only every other
+    // run_frame call emulates a frame; the calls in between return false
+    // with the old framebuffer and an empty sample buffer. Pacing is still
+    // the frontend's job, and fast-forward is done by calling run_frame
+    // more often.
+    pub fn set_speed_multiplier(&mut self, multiplier: f32) {
+        self.speed_multiplier = multiplier.clamp(0.01, 1.0);
+        self.speed_accum = 0.0;
+    }
+
+    // Whether the last run_frame call emulated a new frame
+    pub fn should_present(&self) -> bool { self.should_present }
+
     pub fn run_frame(&mut self) -> bool {
         if !self.running { return false; }
 
-        let target_cycles = 70224;
+        // The sample buffer holds only this frame's audio
+        self.mmu.apu_mut().clear_samples();
+
+        // Slow motion: a call only emulates once the multiplier has
+        // accumulated a whole frame; otherwise the previous frame stays up
+        self.speed_accum += self.speed_multiplier;
+        self.should_present = self.speed_accum >= 1.0;
+        if !self.should_present {
+            self.last_frame_cycles = 0;
+            return false;
+        }
+        self.speed_accum -= 1.0;
+
+        if self.input_history_enabled {
+            if self.input_history.len() >= MAX_INPUT_HISTORY {
+                self.input_history.pop_front();
+            }
+            let record = InputRecord { frame: self.frame_index, buttons: self.mmu.joypad_pressed() };
+            self.input_history.push_back(record);
+        }
+        self.frame_index += 1;
+
+        let target_cycles = CYCLES_PER_FRAME;
         let mut frame_cycles = 0;
         let mut frame_ready = false;
 
         while frame_cycles < target_cycles {
-            let cpu_cycles = self.step_cpu();
-            frame_cycles += cpu_cycles;
+            let cpu_cycles = self.step_cpu() + self.mmu.take_hdma_stall();
+            // CGB double speed: the timer, serial clock and OAM DMA are
+            // clocked with the CPU, while the PPU, APU and clock keep
+            // real-time rates, so they (and the frame length) see half the
+            // cycles
+            let cycles = if self.mmu.double_speed() { cpu_cycles / 2 } else { cpu_cycles };
+            frame_cycles += cycles;
             
             // Update peripherals
-            self.timer.step(cpu_cycles, self.mmu.get_io_mut());
-            self.apu.step(&self.mmu, cpu_cycles);
+            self.mmu.step_timer(cpu_cycles);
+            self.mmu.step_serial(cpu_cycles);
+            self.mmu.step_oam_dma(cpu_cycles);
+            self.mmu.step_apu(cycles);
+            self.mmu.step_rtc(cycles);
             
             // PPU returns true when a frame is ready
-            if self.ppu.step(&mut self.mmu, cpu_cycles) {
+            if self.ppu.step(&mut self.mmu, cycles) {
                 frame_ready = true;
+                self.push_event(Event::FrameReady);
+                self.capture_frame();
+            }
+
+            while let Some(&byte) = self.mmu.serial_output().get(self.serial_seen) {
+                self.serial_seen += 1;
+                self.push_event(Event::SerialByte(byte));
+            }
+
+            // Break on invalid access: pause and keep the offending access
+            if let Some(mut access) = self.mmu.take_invalid_access() {
+                access.pc = self.instr_pc;
+                self.invalid_access = Some(access);
+                self.running = false;
+                self.push_event(Event::Stalled);
+                break;
+            }
+
+            // Reference trace divergence pauses inside step_cpu
+            if !self.running {
+                break;
             }
         }
+        self.last_frame_cycles = frame_cycles;
+
+        if self.running && self.rewind.frame_done() {
+            let state = self.save_state_binary();
+            self.rewind.push(self.frame_index, state);
+        }
 
         frame_ready
     }
 
+    fn capture_frame(&mut self) {
+        if self.frame_capture_limit == 0 {
+            return;
+        }
+        if self.captured_frames.len() >= self.frame_capture_limit {
+            self.captured_frames.pop_front();
+        }
+        self.captured_frames.push_back(self.ppu.frame_rgba());
+    }
+
     fn step_cpu(&mut self) -> u32 {
         if self.halted {
-            // Check for pending interrupts even when halted
+            // Check for pending interrupts even when halted. With IME off
+            // the CPU just resumes after HALT without servicing them
             if self.check_interrupts().is_some() {
                 self.halted = false;
             }
@@ -143,13 +440,10 @@ impl GameBoy {
 
         let cycles_before = self.cycles;
 
-        if self.ime_scheduled {
-            self.ime = true;
-            self.ime_scheduled = false;
-        }
-
-        // Only service interrupts if IME is enabled
-        if self.ime {
+        // EI takes effect after the following instruction, so no interrupt
+        // is taken before it runs; otherwise a handler ending in EI; RET
+        // nests again before returning while an interrupt is pending
+        if self.ime && !self.ime_scheduled {
             if let Some(interrupt) = self.check_interrupts() {
                 self.handle_interrupt(interrupt);
                 return self.cycles - cycles_before;
@@ -157,7 +451,35 @@ impl GameBoy {
         }
 
         let pc_before = self.registers.pc;
-        let opcode = self.fetch_byte();
+        self.instr_pc = pc_before;
+        if self.breakpoint_hit.take() != Some(pc_before) && self.breakpoints.contains(&pc_before) {
+            // Pause before the instruction; it runs on the next step
+            self.breakpoint_hit = Some(pc_before);
+            self.running = false;
+            self.push_event(Event::BreakpointHit(pc_before));
+            return 0;
+        }
+        if !self.check_reference_trace(pc_before) {
+            // Stop before executing the divergent instruction
+            self.running = false;
+            self.push_event(Event::Stalled);
+            return 0;
+        }
+        // Only now, so a pause between EI and the next instruction keeps the
+        // delay. That instruction runs with IME set; a DI there wins
+        if self.ime_scheduled {
+            self.ime = true;
+            self.ime_scheduled = false;
+        }
+        let opcode = if self.halt_bug {
+            // The byte after HALT is read twice
+            self.halt_bug = false;
+            self.mmu.cpu_read(pc_before)
+        } else {
+            self.fetch_byte()
+        };
+        // fetch_byte fills in the operands as the instruction reads them
+        self.last_instruction = (pc_before, opcode, None, None);
         if self.trace_enabled {
             self.trace_buf[self.trace_idx & 0xff] = (pc_before, opcode, self.registers.sp);
             self.trace_idx = self.trace_idx.wrapping_add(1);
@@ -167,17 +489,34 @@ impl GameBoy {
         self.cycles - cycles_before
     }
 
+    // Compare the instruction about to run against the next reference
+    // entry; false on the first mismatch. The remaining reference is
+    // dropped then so resuming runs freely.
+    fn check_reference_trace(&mut self, pc: u16) -> bool {
+        let Some(&(expected_pc, expected_opcode)) = self.reference_trace.get(self.reference_idx) else {
+            return true;
+        };
+        let opcode = self.mmu.read_byte(pc);
+        if pc == expected_pc && opcode == expected_opcode {
+            self.reference_idx += 1;
+            return true;
+        }
+        self.trace_divergence = Some(TraceDivergence {
+            index: self.reference_idx,
+            expected_pc,
+            expected_opcode,
+            actual_pc: pc,
+            actual_opcode: opcode,
+        });
+        self.reference_idx = self.reference_trace.len();
+        false
+    }
+
     fn check_interrupts(&self) -> Option<u8> {
         let ie = self.mmu.read_byte(0xffff);
         let if_ = self.mmu.read_byte(0xff0f);
-        let interrupts = ie & if_;
-        if interrupts == 0 { return None; }
-        for i in 0..5 {
-            if interrupts & (1 << i) != 0 {
-                return Some(i);
-            }
-        }
-        None
+        let interrupts = ie & if_ & self.interrupt_mask;
+        (0..5).find(|i| interrupts & (1 << i) != 0)
     }
 
     fn handle_interrupt(&mut self, interrupt: u8) {
@@ -188,22 +527,28 @@ impl GameBoy {
         let pc_before = self.registers.pc;
         let ie = self.mmu.read_byte(0xffff);
         self.last_interrupt = Some((interrupt, pc_before, ie, if_));
-        
-        // Guard against stack overflow during rapid interrupt loops
-        if self.registers.sp < 0x8100 {
-            // Stack has grown dangerously large; likely stuck in interrupt loop
-            // Don't service this interrupt; let the ROM recover
-            self.mmu.write_byte(0xff0f, 0); // Clear all IF flags
-            return;
-        }
-        
+
         self.push_word(self.registers.pc);
         let handlers = [0x40, 0x48, 0x50, 0x58, 0x60];
         self.registers.pc = handlers[interrupt as usize];
         self.cycles += 20;
-    }    fn fetch_byte(&mut self) -> u8 {
-        let byte = self.mmu.read_byte(self.registers.pc);
+        self.push_event(Event::InterruptServiced(interrupt));
+    }
+
+    fn push_event(&mut self, event: Event) {
+        if self.events.len() >= MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    fn fetch_byte(&mut self) -> u8 {
+        let byte = self.mmu.cpu_read(self.registers.pc);
         self.registers.pc = self.registers.pc.wrapping_add(1);
+        match &mut self.last_instruction {
+            (_, _, operand @ None, _) | (_, _, _, operand @ None) => *operand = Some(byte),
+            _ => {}
+        }
         byte
     }
 
@@ -215,15 +560,15 @@ impl GameBoy {
 
     fn push_word(&mut self, val: u16) {
         self.registers.sp = self.registers.sp.wrapping_sub(1);
-        self.mmu.write_byte(self.registers.sp, (val >> 8) as u8);
+        self.mmu.cpu_write(self.registers.sp, (val >> 8) as u8);
         self.registers.sp = self.registers.sp.wrapping_sub(1);
-        self.mmu.write_byte(self.registers.sp, val as u8);
+        self.mmu.cpu_write(self.registers.sp, val as u8);
     }
 
     fn pop_word(&mut self) -> u16 {
-        let lo = self.mmu.read_byte(self.registers.sp) as u16;
+        let lo = self.mmu.cpu_read(self.registers.sp) as u16;
         self.registers.sp = self.registers.sp.wrapping_add(1);
-        let hi = self.mmu.read_byte(self.registers.sp) as u16;
+        let hi = self.mmu.cpu_read(self.registers.sp) as u16;
         self.registers.sp = self.registers.sp.wrapping_add(1);
         (hi << 8) | lo
     }
@@ -242,7 +587,7 @@ impl GameBoy {
             
             // 0x02: LD (BC), A
             0x02 => {
-                self.mmu.write_byte(self.registers.bc(), self.registers.a);
+                self.mmu.cpu_write(self.registers.bc(), self.registers.a);
                 self.cycles += 8;
             }
             
@@ -280,8 +625,8 @@ impl GameBoy {
             // 0x08: LD (nn), SP
             0x08 => {
                 let addr = self.fetch_word();
-                self.mmu.write_byte(addr, (self.registers.sp & 0xff) as u8);
-                self.mmu.write_byte(addr.wrapping_add(1), ((self.registers.sp >> 8) & 0xff) as u8);
+                self.mmu.cpu_write(addr, (self.registers.sp & 0xff) as u8);
+                self.mmu.cpu_write(addr.wrapping_add(1), ((self.registers.sp >> 8) & 0xff) as u8);
                 self.cycles += 20;
             }
             
@@ -293,7 +638,7 @@ impl GameBoy {
             
             // 0x0A: LD A, (BC)
             0x0a => {
-                self.registers.a = self.mmu.read_byte(self.registers.bc());
+                self.registers.a = self.mmu.cpu_read(self.registers.bc());
                 self.cycles += 8;
             }
             
@@ -332,6 +677,10 @@ impl GameBoy {
             0x10 => {
                 self.fetch_byte(); // STOP takes 2 bytes
                 self.cycles += 4;
+                if self.mmu.try_speed_switch() {
+                    // The CPU sits out the switch for 2050 M-cycles
+                    self.cycles += 8200;
+                }
             }
             
             // 0x11: LD DE, nn
@@ -343,7 +692,7 @@ impl GameBoy {
             
             // 0x12: LD (DE), A
             0x12 => {
-                self.mmu.write_byte(self.registers.de(), self.registers.a);
+                self.mmu.cpu_write(self.registers.de(), self.registers.a);
                 self.cycles += 8;
             }
             
@@ -393,7 +742,7 @@ impl GameBoy {
             
             // 0x1A: LD A, (DE)
             0x1a => {
-                self.registers.a = self.mmu.read_byte(self.registers.de());
+                self.registers.a = self.mmu.cpu_read(self.registers.de());
                 self.cycles += 8;
             }
             
@@ -448,7 +797,7 @@ impl GameBoy {
             
             // 0x22: LD (HL+), A
             0x22 => {
-                self.mmu.write_byte(self.registers.hl(), self.registers.a);
+                self.mmu.cpu_write(self.registers.hl(), self.registers.a);
                 let val = self.registers.hl().wrapping_add(1);
                 self.registers.set_hl(val);
                 self.cycles += 8;
@@ -504,7 +853,7 @@ impl GameBoy {
             
             // 0x2A: LD A, (HL+)
             0x2a => {
-                self.registers.a = self.mmu.read_byte(self.registers.hl());
+                self.registers.a = self.mmu.cpu_read(self.registers.hl());
                 let val = self.registers.hl().wrapping_add(1);
                 self.registers.set_hl(val);
                 self.cycles += 8;
@@ -562,7 +911,7 @@ impl GameBoy {
             
             // 0x32: LD (HL-), A
             0x32 => {
-                self.mmu.write_byte(self.registers.hl(), self.registers.a);
+                self.mmu.cpu_write(self.registers.hl(), self.registers.a);
                 let val = self.registers.hl().wrapping_sub(1);
                 self.registers.set_hl(val);
                 self.cycles += 8;
@@ -576,24 +925,24 @@ impl GameBoy {
             
             // 0x34: INC (HL)
             0x34 => {
-                let val = self.mmu.read_byte(self.registers.hl());
+                let val = self.mmu.cpu_read(self.registers.hl());
                 let result = self.inc8(val);
-                self.mmu.write_byte(self.registers.hl(), result);
+                self.mmu.cpu_write(self.registers.hl(), result);
                 self.cycles += 12;
             }
             
             // 0x35: DEC (HL)
             0x35 => {
-                let val = self.mmu.read_byte(self.registers.hl());
+                let val = self.mmu.cpu_read(self.registers.hl());
                 let result = self.dec8(val);
-                self.mmu.write_byte(self.registers.hl(), result);
+                self.mmu.cpu_write(self.registers.hl(), result);
                 self.cycles += 12;
             }
             
             // 0x36: LD (HL), n
             0x36 => {
                 let val = self.fetch_byte();
-                self.mmu.write_byte(self.registers.hl(), val);
+                self.mmu.cpu_write(self.registers.hl(), val);
                 self.cycles += 12;
             }
             
@@ -624,7 +973,7 @@ impl GameBoy {
             
             // 0x3A: LD A, (HL-)
             0x3a => {
-                self.registers.a = self.mmu.read_byte(self.registers.hl());
+                self.registers.a = self.mmu.cpu_read(self.registers.hl());
                 let val = self.registers.hl().wrapping_sub(1);
                 self.registers.set_hl(val);
                 self.cycles += 8;
@@ -667,9 +1016,17 @@ impl GameBoy {
                 self.ld_rr(opcode);
             }
             
-            // 0x76: HALT
+            // 0x76: HALT. With IME off and an interrupt already pending the
+            // CPU does not halt, and PC fails to advance past the next byte
             0x76 => {
-                self.halted = true;
+                // With an interrupt already pending HALT never sleeps: IME
+                // on (including EI right before) services it next with the
+                // return address after HALT; IME off hits the halt bug
+                if self.check_interrupts().is_none() {
+                    self.halted = true;
+                } else if !self.ime {
+                    self.halt_bug = true;
+                }
                 self.cycles += 4;
             }
             
@@ -730,7 +1087,7 @@ impl GameBoy {
             3 => self.registers.e,
             4 => self.registers.h,
             5 => self.registers.l,
-            6 => self.mmu.read_byte(self.registers.hl()),
+            6 => self.mmu.cpu_read(self.registers.hl()),
             7 => self.registers.a,
             _ => 0,
         }
@@ -745,7 +1102,7 @@ impl GameBoy {
             3 => self.registers.e = value,
             4 => self.registers.h = value,
             5 => self.registers.l = value,
-            6 => self.mmu.write_byte(self.registers.hl(), value),
+            6 => self.mmu.cpu_write(self.registers.hl(), value),
             7 => self.registers.a = value,
             _ => {}
         }
@@ -954,7 +1311,7 @@ impl GameBoy {
             // 0xE0: LDH (n), A
             0xe0 => {
                 let offset = self.fetch_byte();
-                self.mmu.write_byte(0xff00 | offset as u16, self.registers.a);
+                self.mmu.cpu_write(0xff00 | offset as u16, self.registers.a);
                 self.cycles += 12;
             }
             // 0xE1: POP HL
@@ -965,7 +1322,7 @@ impl GameBoy {
             }
             // 0xE2: LD (C), A
             0xe2 => {
-                self.mmu.write_byte(0xff00 | self.registers.c as u16, self.registers.a);
+                self.mmu.cpu_write(0xff00 | self.registers.c as u16, self.registers.a);
                 self.cycles += 8;
             }
             // 0xE5: PUSH HL
@@ -1001,7 +1358,7 @@ impl GameBoy {
             // 0xEA: LD (nn), A
             0xea => {
                 let addr = self.fetch_word();
-                self.mmu.write_byte(addr, self.registers.a);
+                self.mmu.cpu_write(addr, self.registers.a);
                 self.cycles += 16;
             }
             // 0xEE: XOR n
@@ -1015,7 +1372,7 @@ impl GameBoy {
             // 0xF0: LDH A, (n)
             0xf0 => {
                 let offset = self.fetch_byte();
-                self.registers.a = self.mmu.read_byte(0xff00 | offset as u16);
+                self.registers.a = self.mmu.cpu_read(0xff00 | offset as u16);
                 self.cycles += 12;
             }
             // 0xF1: POP AF
@@ -1026,12 +1383,13 @@ impl GameBoy {
             }
             // 0xF2: LD A, (C)
             0xf2 => {
-                self.registers.a = self.mmu.read_byte(0xff00 | self.registers.c as u16);
+                self.registers.a = self.mmu.cpu_read(0xff00 | self.registers.c as u16);
                 self.cycles += 8;
             }
             // 0xF3: DI
             0xf3 => {
                 self.ime = false;
+                self.ime_scheduled = false;
                 self.cycles += 4;
             }
             // 0xF5: PUSH AF
@@ -1067,7 +1425,7 @@ impl GameBoy {
             // 0xFA: LD A, (nn)
             0xfa => {
                 let addr = self.fetch_word();
-                self.registers.a = self.mmu.read_byte(addr);
+                self.registers.a = self.mmu.cpu_read(addr);
                 self.cycles += 16;
             }
             // 0xFB: EI
@@ -1167,7 +1525,9 @@ impl GameBoy {
         let result = self.registers.a.wrapping_sub(value).wrapping_sub(carry);
         self.registers.set_flag_z(result == 0);
         self.registers.set_flag_n(true);
-        self.registers.set_flag_h((self.registers.a & 0x0f) < (value & 0x0f) + carry);
+        // Borrow out of bit 4, with the carry-in counted in the same subtraction
+        let half = (self.registers.a & 0x0f) as i16 - (value & 0x0f) as i16 - carry as i16;
+        self.registers.set_flag_h(half < 0);
         self.registers.set_flag_c((self.registers.a as u16) < (value as u16 + carry as u16));
         self.registers.a = result;
     }
@@ -1341,7 +1701,7 @@ impl GameBoy {
     }
     
     fn swap(&mut self, value: u8) -> u8 {
-        let result = (value >> 4) | (value << 4);
+        let result = value.rotate_left(4);
         self.registers.set_flag_z(result == 0);
         self.registers.set_flag_n(false);
         self.registers.set_flag_h(false);
@@ -1386,36 +1746,392 @@ impl GameBoy {
         self.ppu.get_frame_buffer().len() 
     }
 
+    // Text preview of the current frame (80x36, " .:#" light to dark)
+    pub fn frame_ascii(&self) -> String {
+        self.ppu.frame_ascii()
+    }
+
+    pub fn frame_buffer_format(&self) -> FrameBufferFormat {
+        self.ppu.frame_buffer_format()
+    }
+
+    // 12 bytes: RGB for DMG shades 0 (lightest) to 3. Returns false and
+    // keeps the current palette if the length is wrong
+    pub fn set_dmg_palette(&mut self, colors: &[u8]) -> bool {
+        if colors.len() != 12 {
+            return false;
+        }
+        let mut palette = [[0u8; 3]; 4];
+        for (shade, rgb) in palette.iter_mut().zip(colors.chunks_exact(3)) {
+            shade.copy_from_slice(rgb);
+        }
+        self.ppu.set_dmg_palette(palette);
+        true
+    }
+
+    // RGBA by default; switching reallocates, so re-read frame_buffer_ptr
+    pub fn set_frame_buffer_format(&mut self, format: FrameBufferFormat) {
+        self.ppu.set_frame_buffer_format(format);
+    }
+
+    // Keep an RGBA copy of each completed frame, whatever the framebuffer
+    // format, holding only the last `max_frames`. Restarting drops
+    // whatever was captured; 0 stops capturing
+    pub fn start_frame_capture(&mut self, max_frames: usize) {
+        self.frame_capture_limit = max_frames;
+        self.captured_frames.clear();
+    }
+
+    pub fn stop_frame_capture(&mut self) {
+        self.start_frame_capture(0);
+    }
+
+    // Frames captured and not yet taken
+    pub fn captured_frame_count(&self) -> usize {
+        self.captured_frames.len()
+    }
+
+    // Debug buffer of pre-palette color indices (one byte per pixel, 0..3),
+    // for telling bad tile data apart from bad palettes; costs a write per
+    // pixel so it is off by default
+    pub fn set_raw_index_debug(&mut self, enabled: bool) {
+        self.ppu.set_raw_index_enabled(enabled);
+    }
+
+    pub fn raw_index_buffer_ptr(&self) -> *const u8 {
+        self.ppu.get_raw_index_buffer().as_ptr()
+    }
+
+    pub fn raw_index_buffer_len(&self) -> usize {
+        self.ppu.get_raw_index_buffer().len()
+    }
+
+    // Output sample rate in Hz (8000..=96000, default 44100); may be changed
+    // between frames
+    pub fn set_sample_rate(&mut self, hz: u32) {
+        self.mmu.apu_mut().set_sample_rate(hz);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.mmu.apu().sample_rate()
+    }
+
+    // Raw audio: samples at the APU's own ~1.05 MHz update rate instead of
+    // sample_rate, for embedders with their own resampler
+    pub fn set_raw_audio(&mut self, enabled: bool) {
+        self.mmu.apu_mut().set_raw_output(enabled);
+    }
+
+    pub fn raw_audio(&self) -> bool {
+        self.mmu.apu().raw_output()
+    }
+
+    pub fn native_sample_rate(&self) -> u32 {
+        NATIVE_SAMPLE_RATE
+    }
+
+    // Interleaved stereo (left, right) f32 samples at the output rate,
+    // produced by the last run_frame
+    pub fn get_sample_buffer_ptr(&self) -> *const f32 {
+        self.mmu.apu().get_sample_buffer().as_ptr()
+    }
+
+    pub fn get_sample_buffer_len(&self) -> usize {
+        self.mmu.apu().get_sample_buffer().len()
+    }
+
+    // Envelope volume (0..15) of sound channel 1-4, for audio debugging
+    pub fn apu_channel_volume(&self, channel: u8) -> u8 {
+        self.mmu.apu().channel_volume(channel)
+    }
+
+    // Position (0..7) of the APU's 512 Hz frame sequencer: length on even
+    // steps, sweep on 2 and 6, envelope on 7
+    pub fn apu_frame_sequencer_step(&self) -> u8 {
+        self.mmu.apu().frame_sequencer_step()
+    }
+
+    // True when the framebuffer changed since the last clear_frame_dirty,
+    // so a frontend can skip re-uploading a static screen
+    pub fn frame_dirty(&self) -> bool {
+        self.ppu.frame_dirty()
+    }
+
+    pub fn clear_frame_dirty(&mut self) {
+        self.ppu.clear_frame_dirty();
+    }
+
+    // Pull-based audio for frontends managing their own backpressure:
+    // stereo frames queued since the last drain (up to one second)
+    pub fn apu_samples_available(&self) -> usize {
+        self.mmu.apu().samples_available()
+    }
+
+    // Up to `max` queued frames as interleaved left/right f32 samples
+    pub fn apu_drain(&mut self, max: usize) -> Vec<f32> {
+        self.mmu.apu_mut().drain(max)
+    }
+
+    // Frames lost because the queue was full when a new one arrived
+    pub fn apu_dropped_frames(&self) -> u32 {
+        self.mmu.apu().dropped_frames()
+    }
+
+    // Queue size in stereo frames; 0 is one second of output
+    pub fn set_audio_buffer_size(&mut self, frames: usize) {
+        self.mmu.apu_mut().set_buffer_size(frames);
+    }
+
     pub fn press_button(&mut self, bit: u8) {
-        // Update internal input model (optional) and MMU's joypad state
-        self.input.press_button(bit);
         self.mmu.joypad_press(bit);
     }
 
     pub fn release_button(&mut self, bit: u8) {
-        self.input.release_button(bit);
         self.mmu.joypad_release(bit);
     }
 
+    // All eight buttons in one call: bit set = pressed, same bit numbers as
+    // press_button (0 A, 1 B, 2 Select, 3 Start, 4 Right, 5 Left, 6 Up, 7 Down)
+    pub fn set_input_state(&mut self, pressed: u8) {
+        self.mmu.joypad_set_state(pressed);
+    }
+
     pub fn get_pc(&self) -> u16 {
         self.registers.pc
     }
+
+    // Record the buttons held as each frame starts, to tell input desyncs
+    // (netplay, TAS) from emulation ones. Turning it off drops the history
+    pub fn set_input_history(&mut self, enabled: bool) {
+        self.input_history_enabled = enabled;
+        if !enabled {
+            self.input_history.clear();
+        }
+    }
+
+    // Frames emulated since the last reset
+    pub fn frame_index(&self) -> u64 {
+        self.frame_index
+    }
     
     pub fn get_lcdc(&self) -> u8 {
         self.mmu.get_io()[0x40]
     }
 
+    // Whether 0xFF50 has latched the boot ROM out of the memory map
+    pub fn boot_rom_disabled(&self) -> bool {
+        self.mmu.boot_rom_disabled()
+    }
+
+    // Boot from a DMG (256 byte) or CGB (2304 byte) boot ROM image instead of
+    // the post-boot shortcut; an empty slice goes back to fast boot. The
+    // machine is reset. Returns false for any other size
+    pub fn load_boot_rom(&mut self, data: &[u8]) -> bool {
+        if !self.mmu.load_boot_rom(data) {
+            return false;
+        }
+        self.reset();
+        true
+    }
+
+    // Human-readable cartridge type for the cart-info panel
+    pub fn mbc_name(&self) -> String {
+        self.mmu.mbc_name()
+    }
+
+    // Global checksum computed over the loaded ROM; also the ROM id in save states
+    pub fn global_checksum(&self) -> u16 {
+        self.mmu.global_checksum()
+    }
+
+    // The computed checksum matches 0x014E-0x014F. Real hardware never
+    // checks it, so a mismatch only flags a bad dump or a patched ROM
+    pub fn global_checksum_valid(&self) -> bool {
+        self.mmu.global_checksum() == self.mmu.header_global_checksum()
+    }
+
+    // Dot-accurate PPU debugging: advance the PPU by one T-cycle without
+    // running the CPU. Returns true when the step completes a frame.
+    pub fn step_ppu_dot(&mut self) -> bool {
+        self.ppu.step_dot(&mut self.mmu)
+    }
+
+    pub fn ppu_dot(&self) -> u32 {
+        self.ppu.dot()
+    }
+
+    // Mode 3 pixel position on the current line; None outside mode 3
+    pub fn ppu_pixel_x(&self) -> Option<u8> {
+        self.ppu.pixel_x(&self.mmu)
+    }
+
+    // Draw a single scanline (0..143) into the framebuffer from the current
+    // VRAM/OAM/register contents, without running the CPU or PPU timing
+    pub fn render_line(&mut self, ly: u8) {
+        self.ppu.render_line_for_test(&mut self.mmu, ly);
+    }
+
+    // RGB currently displayed for BG color index 0..3 (DMG, via BGP)
+    pub fn resolve_bg_color(&self, index: u8) -> Vec<u8> {
+        self.ppu.resolve_bg_color(&self.mmu, index).to_vec()
+    }
+
+    // RGB currently displayed for BG color index 0..3 in CGB palette 0..7
+    pub fn resolve_cgb_bg_color(&self, palette: u8, index: u8) -> Vec<u8> {
+        self.ppu.resolve_cgb_bg_color(&self.mmu, palette, index).to_vec()
+    }
+
     // Debug controls
     pub fn enable_trace(&mut self, enabled: bool) { self.trace_enabled = enabled; }
 
+    // Pause run_frame when the guest touches disabled ERAM, 0xFEA0-0xFEFF or an
+    // unmapped IO register; the access is kept until the next reset
+    pub fn set_break_on_invalid_access(&mut self, enabled: bool) {
+        self.mmu.set_break_on_invalid_access(enabled);
+        self.invalid_access = None;
+    }
+
+    // Testing aid, not hardware behaviour: only interrupts whose bit is set
+    // in `mask` can be taken or wake HALT, without touching the guest's IE.
+    // Defaults to 0x1F (all allowed) and survives reset.
+    pub fn set_interrupt_mask(&mut self, mask: u8) {
+        self.interrupt_mask = mask & 0x1f;
+    }
+
+    // Record when the guest turns the LCD off outside VBlank (LY < 144),
+    // which real hardware does not tolerate; emulation is unaffected
+    pub fn set_warn_on_lcd_disable_outside_vblank(&mut self, enabled: bool) {
+        self.mmu.set_warn_on_lcd_disable(enabled);
+    }
+
+    // LY at the last out-of-VBlank LCD disable since the warning was enabled
+    pub fn lcd_disable_warning(&self) -> Option<u8> {
+        self.mmu.lcd_disable_warning()
+    }
+
+    // Battery-backed cartridge RAM (plus the MBC3 clock) for persisting
+    // between sessions; empty for carts without a battery
+    pub fn export_sram(&self) -> Vec<u8> {
+        self.mmu.export_sram()
+    }
+
+    // Load a blob from export_sram after load_rom; false if it does not fit
+    pub fn import_sram(&mut self, data: &[u8]) -> bool {
+        self.mmu.import_sram(data)
+    }
+
+    // Bytes the game sent over the serial port (test ROMs print results
+    // this way), decoded lossily as text
+    pub fn serial_output(&self) -> String {
+        String::from_utf8_lossy(self.mmu.serial_output()).into_owned()
+    }
+
+    pub fn clear_serial_output(&mut self) {
+        self.mmu.clear_serial_output();
+        self.serial_seen = 0;
+    }
+
+    // Byte the link partner sends during the next serial transfer; without
+    // one the port reads 0xFF
+    pub fn set_serial_byte_in(&mut self, val: u8) {
+        self.mmu.set_serial_byte_in(val);
+    }
+
+    // While connected, serial transfers wait for link_exchange instead of
+    // completing on their own. Disconnect between transfers
+    pub fn set_link_connected(&mut self, connected: bool) {
+        self.mmu.set_serial_linked(connected);
+    }
+
+    // Pause before executing the instruction at addr (kept across reset)
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.retain(|&bp| bp != addr);
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    // Skip drawing pixels; everything else, including frame timing, runs
+    pub fn set_rendering_enabled(&mut self, enabled: bool) {
+        self.ppu.set_rendering_enabled(enabled);
+    }
+
+    // Test helper: run frames headless until the serial output contains
+    // `pattern`. False if max_frames pass first or emulation stops (invalid
+    // access, trace divergence). Rendering is re-enabled afterwards
+    pub fn run_until_serial_contains(&mut self, pattern: &str, max_frames: u32) -> bool {
+        self.running = true;
+        self.ppu.set_rendering_enabled(false);
+        let mut found = false;
+        for _ in 0..max_frames {
+            self.run_frame();
+            if self.serial_output().contains(pattern) {
+                found = true;
+                break;
+            }
+            if !self.running {
+                break;
+            }
+        }
+        self.ppu.set_rendering_enabled(true);
+        found
+    }
+
+    // CGB CPU speed as set through KEY1 and STOP
+    pub fn double_speed(&self) -> bool {
+        self.mmu.double_speed()
+    }
+
+    // Model the loaded ROM is running as, after auto-detection or an override
+    pub fn current_model(&self) -> Model {
+        self.mmu.model()
+    }
+
+    // Force a model regardless of the header; the machine is reset. The
+    // override persists across ROM loads until cleared
+    pub fn set_model(&mut self, model: Model) {
+        self.mmu.set_model_override(Some(model));
+        self.reset();
+    }
+
+    pub fn clear_model_override(&mut self) {
+        self.mmu.set_model_override(None);
+        self.reset();
+    }
+
+    // MBC3 clock: pass wall time (unix seconds) after loading a save so the
+    // time the emulator was closed is added; running time is counted in
+    // emulated cycles, so calling it again later never double counts
+    pub fn set_rtc_now(&mut self, unix_secs: f64) {
+        self.mmu.set_rtc_now(unix_secs.max(0.0) as u64);
+    }
+
+    // Accurate (default) or Instant for OAM DMA and HBlank HDMA alike;
+    // persists across reset. Transfers already running keep their timing
+    pub fn set_dma_timing(&mut self, timing: DmaTiming) {
+        self.mmu.set_dma_timing(timing);
+    }
+
+    pub fn dma_timing(&self) -> DmaTiming {
+        self.mmu.dma_timing()
+    }
+
+    // Restrict all CPU reads and writes to HRAM while OAM DMA is running, as
+    // on hardware; off by default since few games rely on it
+    pub fn set_strict_oam_dma(&mut self, enabled: bool) {
+        self.mmu.set_strict_oam_dma(enabled);
+    }
+
     pub fn dump_trace(&self) -> String {
-        let mut out = String::new();
+        let mut out = self.dump_trace_last(256);
         use std::fmt::Write as _;
-        let start = self.trace_idx.min(256);
-        for i in 0..start {
-            let (pc, op, sp) = self.trace_buf[(self.trace_idx.wrapping_sub(start - i)) & 0xff];
-            let _ = write!(out, "{:04X}: {:02X} SP={:04X}\n", pc, op, sp);
-        }
         if let Some((intr, pc, ie, if_)) = self.last_interrupt {
             let _ = write!(
                 out,
@@ -1430,51 +2146,311 @@ impl GameBoy {
         out
     }
 
-    pub fn save_state(&self) -> String {
-        let state = SaveState {
-            a: self.registers.a,
-            f: self.registers.f,
-            b: self.registers.b,
-            c: self.registers.c,
-            d: self.registers.d,
-            e: self.registers.e,
-            h: self.registers.h,
-            l: self.registers.l,
-            sp: self.registers.sp,
-            pc: self.registers.pc,
-            cycles: self.cycles,
-        };
-        serde_json::to_string(&state).unwrap_or_else(|_| "{}".to_string())
+    // Only the most recent n traced instructions, oldest first
+    pub fn dump_trace_last(&self, n: usize) -> String {
+        let mut out = String::new();
+        use std::fmt::Write as _;
+        let start = self.trace_idx.min(256).min(n);
+        for i in 0..start {
+            let (pc, op, sp) = self.trace_buf[(self.trace_idx.wrapping_sub(start - i)) & 0xff];
+            let _ = writeln!(out, "{:04X}: {:02X} SP={:04X}", pc, op, sp);
+        }
+        out
     }
 
-    pub fn load_state(&mut self, s: &str) {
-        if let Ok(st) = serde_json::from_str::<SaveState>(s) {
-            self.registers.a = st.a;
-            self.registers.f = st.f;
-            self.registers.b = st.b;
-            self.registers.c = st.c;
-            self.registers.d = st.d;
-            self.registers.e = st.e;
-            self.registers.h = st.h;
-            self.registers.l = st.l;
-            self.registers.sp = st.sp;
-            self.registers.pc = st.pc;
-            self.cycles = st.cycles;
+    // Load a reference log of `PC:opcode` lines in hex (e.g. `0100:00`);
+    // blank lines and `#` comments are skipped. run_frame then pauses at
+    // the first instruction that differs. Returns the number of entries, or
+    // 0 (and no reference) if any line is malformed.
+    pub fn load_reference_trace(&mut self, text: &str) -> usize {
+        self.reference_trace.clear();
+        self.reference_idx = 0;
+        self.trace_divergence = None;
+        let mut entries = Vec::new();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parsed = line.split_once(':').and_then(|(pc, op)| {
+                Some((u16::from_str_radix(pc.trim(), 16).ok()?, u8::from_str_radix(op.trim(), 16).ok()?))
+            });
+            match parsed {
+                Some(entry) => entries.push(entry),
+                None => return 0,
+            }
         }
+        self.reference_trace = entries;
+        self.reference_trace.len()
     }
-}
 
-// Free-function API to avoid Rc/RefMutFromWasmAbi on methods
-#[wasm_bindgen]
-pub fn gb_create() {
-    GB_SINGLETON.with(|cell| {
-        *cell.borrow_mut() = Some(GameBoy::new());
-    });
-}
+    pub fn clear_reference_trace(&mut self) {
+        self.reference_trace.clear();
+        self.reference_idx = 0;
+        self.trace_divergence = None;
+    }
 
-#[wasm_bindgen]
-pub fn gb_load_rom(data: &[u8]) {
-    GB_SINGLETON.with(|cell| {
+    pub fn save_state(&self) -> String {
+        serde_json::to_string(&self.capture_state()).unwrap_or_else(|_| "{}".to_string())
+    }
+
+    // False (emulator untouched) for unparsable states, states from another
+    // ROM or a newer version, and states whose memory sizes do not fit
+    pub fn load_state(&mut self, s: &str) -> bool {
+        match serde_json::from_str::<SaveState>(s) {
+            Ok(st) => self.restore_state(st),
+            Err(_) => false,
+        }
+    }
+
+    // Same state as save_state in a compact binary form; JSON stays for
+    // inspecting states by hand
+    pub fn save_state_binary(&self) -> Vec<u8> {
+        let mut out = BINARY_STATE_MAGIC.to_vec();
+        out.push(SAVE_STATE_VERSION as u8);
+        match bincode::serialize_into(&mut out, &self.capture_state()) {
+            Ok(()) => out,
+            Err(_) => Vec::new(),
+        }
+    }
+
+    // Keep a snapshot every `interval_frames` frames, up to `max_snapshots`
+    // of them (oldest dropped first). Either at 0 turns rewind off.
+    // Reconfiguring drops the snapshots taken so far
+    pub fn set_rewind(&mut self, interval_frames: u32, max_snapshots: usize) {
+        self.rewind.configure(interval_frames, max_snapshots);
+    }
+
+    // Restore the most recent snapshot and drop it, so calling again goes
+    // further back. False when there is nothing to go back to
+    pub fn rewind(&mut self) -> bool {
+        self.rewind_to(u64::MAX)
+    }
+
+    // Go back at least `frames` frames: restore the newest snapshot that
+    // old, or the oldest one kept; newer snapshots are dropped
+    pub fn rewind_frames(&mut self, frames: u32) -> bool {
+        self.rewind_to(self.frame_index.saturating_sub(frames as u64))
+    }
+
+    pub fn rewind_snapshot_count(&self) -> usize {
+        self.rewind.len()
+    }
+
+    // False (emulator untouched) on a bad header or version, and for the
+    // same reasons as load_state
+    pub fn load_state_binary(&mut self, data: &[u8]) -> bool {
+        let header = BINARY_STATE_MAGIC.len();
+        if data.len() <= header || &data[..header] != BINARY_STATE_MAGIC || data[header] != SAVE_STATE_VERSION as u8 {
+            return false;
+        }
+        match bincode::deserialize::<SaveState>(&data[header + 1..]) {
+            Ok(st) => self.restore_state(st),
+            Err(_) => false,
+        }
+    }
+}
+
+// Rust-side debug API (types not exposed across the wasm boundary)
+impl GameBoy {
+    fn rewind_to(&mut self, frame: u64) -> bool {
+        match self.rewind.pop_at_or_before(frame) {
+            Some(state) => self.load_state_binary(&state),
+            None => false,
+        }
+    }
+
+    fn capture_state(&self) -> SaveState<&MMU, &PPU> {
+        SaveState {
+            version: SAVE_STATE_VERSION,
+            a: self.registers.a,
+            f: self.registers.f,
+            b: self.registers.b,
+            c: self.registers.c,
+            d: self.registers.d,
+            e: self.registers.e,
+            h: self.registers.h,
+            l: self.registers.l,
+            sp: self.registers.sp,
+            pc: self.registers.pc,
+            cycles: self.cycles,
+            // OAM travels with the MMU now
+            oam: Vec::new(),
+            oam_dma: None,
+            rom_checksum: Some(self.mmu.global_checksum()),
+            halted: self.halted,
+            halt_bug: self.halt_bug,
+            ime: self.ime,
+            ime_scheduled: self.ime_scheduled,
+            frame_index: self.frame_index,
+            mmu: Some(&self.mmu),
+            ppu: Some(&self.ppu),
+        }
+    }
+
+    fn restore_state(&mut self, st: SaveState) -> bool {
+        // A state from another ROM would resume in foreign code; older
+        // states without an id are trusted
+        if st.version > SAVE_STATE_VERSION
+            || st.rom_checksum.is_some_and(|sum| sum != self.mmu.global_checksum())
+        {
+            return false;
+        }
+        match (st.mmu, st.ppu) {
+            (Some(mmu), Some(ppu)) => {
+                if !self.mmu.load_state(mmu) {
+                    return false;
+                }
+                self.ppu.load_state(ppu);
+                self.halted = st.halted;
+                self.halt_bug = st.halt_bug;
+                self.ime = st.ime;
+                self.ime_scheduled = st.ime_scheduled;
+                self.frame_index = st.frame_index;
+            }
+            _ if st.version >= 2 => return false,
+            // States saved before OAM was captured carry no OAM image
+            _ if st.oam.len() == 0xa0 => self.mmu.restore_oam(&st.oam, st.oam_dma),
+            _ => {}
+        }
+        self.registers.a = st.a;
+        // F bits 0-3 do not exist; keep PUSH AF from leaking them from a bad state
+        self.registers.f = st.f & 0xf0;
+        self.registers.b = st.b;
+        self.registers.c = st.c;
+        self.registers.d = st.d;
+        self.registers.e = st.e;
+        self.registers.h = st.h;
+        self.registers.l = st.l;
+        self.registers.sp = st.sp;
+        self.registers.pc = st.pc;
+        self.cycles = st.cycles;
+        true
+    }
+
+    pub fn version_info() -> VersionInfo {
+        let mut features: Vec<String> = FEATURES.iter().map(|f| f.to_string()).collect();
+        if cfg!(debug_assertions) {
+            features.push("debug-build".to_string());
+        }
+        VersionInfo { crate_version: env!("CARGO_PKG_VERSION").to_string(), features }
+    }
+
+    // Link cable between two connected cores, called between their frames.
+    // Whole bytes are swapped once one side drives the clock (SC bit 0) and
+    // both have SC bit 7 set; each then gets the other's SB and a serial
+    // interrupt. A clock master whose partner is not ready yet shifts in
+    // 0xFF, as with nothing plugged in. Transfers therefore complete at frame
+    // granularity whatever the clock speed: at most one byte per direction
+    // per exchange. Returns true when bytes were swapped
+    pub fn link_exchange(a: &mut GameBoy, b: &mut GameBoy) -> bool {
+        match (a.mmu.serial_link_request(), b.mmu.serial_link_request()) {
+            (Some((clock_a, byte_a)), Some((clock_b, byte_b))) if clock_a || clock_b => {
+                a.mmu.complete_serial_link(byte_b);
+                b.mmu.complete_serial_link(byte_a);
+                true
+            }
+            (Some((true, _)), _) => {
+                a.mmu.complete_serial_link(0xff);
+                false
+            }
+            (_, Some((true, _))) => {
+                b.mmu.complete_serial_link(0xff);
+                false
+            }
+            _ => false,
+        }
+    }
+
+    pub fn interrupt_state(&self) -> InterruptState {
+        let ie = self.mmu.read_byte(0xffff);
+        let if_ = self.mmu.read_byte(0xff0f);
+        let mut pending = [false; 5];
+        let mut enabled = [false; 5];
+        for i in 0..5 {
+            pending[i] = if_ & (1 << i) != 0;
+            enabled[i] = ie & (1 << i) != 0;
+        }
+        InterruptState { ie, if_, ime: self.ime, pending, enabled }
+    }
+
+    // PC, opcode and up to two operand bytes of the last executed
+    // instruction (the CB sub-opcode counts as an operand)
+    pub fn last_instruction(&self) -> (u16, u8, Option<u8>, Option<u8>) {
+        self.last_instruction
+    }
+
+    // Queued frames plus underrun (drain asked for more than was queued)
+    // and overrun (frame dropped on a full queue) counts
+    pub fn audio_stats(&self) -> AudioStats {
+        self.mmu.apu().stats()
+    }
+
+    // Stereo frames produced by the last run_frame against the ideal
+    // output rate / frame_rate_hz
+    pub fn sync_report(&self) -> SyncReport {
+        let frame_rate_hz = CPU_CLOCK_HZ as f64 / CYCLES_PER_FRAME as f64;
+        SyncReport {
+            cycles_this_frame: self.last_frame_cycles,
+            audio_samples_this_frame: (self.mmu.apu().get_sample_buffer().len() / 2) as u32,
+            expected_samples: self.mmu.apu().output_rate() as f64 / frame_rate_hz,
+        }
+    }
+
+    // Seed all 40 sprite entries at once (rendering tests, debuggers)
+    pub fn set_oam(&mut self, oam: &[u8; 0xa0]) {
+        self.mmu.set_oam(oam);
+    }
+
+    // Everything queued since the last call, oldest first
+    pub fn poll_events(&mut self) -> Vec<Event> {
+        self.events.drain(..).collect()
+    }
+
+    // Captured frames, oldest first; capture keeps running
+    pub fn take_captured_frames(&mut self) -> Vec<Vec<u8>> {
+        self.captured_frames.drain(..).collect()
+    }
+
+    // Recorded input per frame, oldest first (the last MAX_INPUT_HISTORY)
+    pub fn input_history(&self) -> Vec<InputRecord> {
+        self.input_history.iter().copied().collect()
+    }
+
+    // Source base and bytes copied so far (0..160) of an in-flight OAM DMA
+    pub fn oam_dma_progress(&self) -> Option<(u16, u8)> {
+        self.mmu.oam_dma_state().map(|st| (st.source, st.offset))
+    }
+
+    pub fn trace_divergence(&self) -> Option<TraceDivergence> {
+        self.trace_divergence
+    }
+
+    pub fn invalid_access(&self) -> Option<InvalidAccess> {
+        self.invalid_access
+    }
+
+    // One entry per visible line (LY 0..143), refreshed as each line renders
+    pub fn scanline_registers(&self) -> &[ScanlineRegs] {
+        self.ppu.scanline_regs()
+    }
+}
+
+// Free-function API to avoid Rc/RefMutFromWasmAbi on methods
+#[wasm_bindgen]
+pub fn gb_version_info() -> String {
+    serde_json::to_string(&GameBoy::version_info()).unwrap_or_else(|_| "{}".to_string())
+}
+
+#[wasm_bindgen]
+pub fn gb_create() {
+    GB_SINGLETON.with(|cell| {
+        *cell.borrow_mut() = Some(GameBoy::new());
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_load_rom(data: &[u8]) {
+    GB_SINGLETON.with(|cell| {
         if let Some(gb) = cell.borrow_mut().as_mut() {
             gb.load_rom(data);
         }
@@ -1495,6 +2471,45 @@ pub fn gb_start() {
     });
 }
 
+#[wasm_bindgen]
+pub fn gb_reset_ppu() {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.reset_ppu(); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_load_boot_rom(data: &[u8]) -> bool {
+    GB_SINGLETON.with(|cell| cell.borrow_mut().as_mut().map(|gb| gb.load_boot_rom(data)).unwrap_or(false))
+}
+
+#[wasm_bindgen]
+pub fn gb_mbc_name() -> String {
+    GB_SINGLETON.with(|cell| cell.borrow().as_ref().map(|g| g.mbc_name()).unwrap_or_default())
+}
+
+#[wasm_bindgen]
+pub fn gb_global_checksum() -> u16 {
+    GB_SINGLETON.with(|cell| cell.borrow().as_ref().map(|g| g.global_checksum()).unwrap_or(0))
+}
+
+#[wasm_bindgen]
+pub fn gb_global_checksum_valid() -> bool {
+    GB_SINGLETON.with(|cell| cell.borrow().as_ref().map(|g| g.global_checksum_valid()).unwrap_or(false))
+}
+
+#[wasm_bindgen]
+pub fn gb_set_speed_multiplier(multiplier: f32) {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.set_speed_multiplier(multiplier); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_should_present() -> bool {
+    GB_SINGLETON.with(|cell| cell.borrow().as_ref().map(|g| g.should_present()).unwrap_or(false))
+}
+
 #[wasm_bindgen]
 pub fn gb_stop() {
     GB_SINGLETON.with(|cell| {
@@ -1529,9 +2544,159 @@ pub fn gb_frame_buffer_len() -> usize {
     })
 }
 
+#[wasm_bindgen]
+pub fn gb_frame_ascii() -> String {
+    GB_SINGLETON.with(|cell| cell.borrow().as_ref().map(|gb| gb.frame_ascii()).unwrap_or_default())
+}
+
+#[wasm_bindgen]
+pub fn gb_set_dmg_palette(colors: &[u8]) -> bool {
+    GB_SINGLETON.with(|cell| {
+        cell.borrow_mut().as_mut().map(|gb| gb.set_dmg_palette(colors)).unwrap_or(false)
+    })
+}
+
+#[wasm_bindgen]
+pub fn gb_frame_buffer_format() -> FrameBufferFormat {
+    GB_SINGLETON.with(|cell| {
+        cell.borrow().as_ref().map(|gb| gb.frame_buffer_format()).unwrap_or(FrameBufferFormat::Rgba)
+    })
+}
+
+#[wasm_bindgen]
+pub fn gb_set_frame_buffer_format(format: FrameBufferFormat) {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.set_frame_buffer_format(format); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_start_frame_capture(max_frames: usize) {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.start_frame_capture(max_frames); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_stop_frame_capture() {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.stop_frame_capture(); }
+    });
+}
+
+// Captured RGBA frames back to back, oldest first; each is 160*144*4 bytes
+#[wasm_bindgen]
+pub fn gb_take_captured_frames() -> Vec<u8> {
+    GB_SINGLETON.with(|cell| {
+        cell.borrow_mut().as_mut().map(|gb| gb.take_captured_frames().concat()).unwrap_or_default()
+    })
+}
+
+#[wasm_bindgen]
+pub fn gb_frame_dirty() -> bool {
+    GB_SINGLETON.with(|cell| cell.borrow().as_ref().map(|g| g.frame_dirty()).unwrap_or(false))
+}
+
+#[wasm_bindgen]
+pub fn gb_clear_frame_dirty() {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.clear_frame_dirty(); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_set_raw_index_debug(enabled: bool) {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.set_raw_index_debug(enabled); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_raw_index_buffer_ptr() -> *const u8 {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow().as_ref() { gb.raw_index_buffer_ptr() } else { std::ptr::null() }
+    })
+}
+
+#[wasm_bindgen]
+pub fn gb_raw_index_buffer_len() -> usize {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow().as_ref() { gb.raw_index_buffer_len() } else { 0 }
+    })
+}
+
+#[wasm_bindgen]
+pub fn gb_get_sample_buffer_ptr() -> *const f32 {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow().as_ref() { gb.get_sample_buffer_ptr() } else { std::ptr::null() }
+    })
+}
+
+#[wasm_bindgen]
+pub fn gb_get_sample_buffer_len() -> usize {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow().as_ref() { gb.get_sample_buffer_len() } else { 0 }
+    })
+}
+
+#[wasm_bindgen]
+pub fn gb_apu_samples_available() -> usize {
+    GB_SINGLETON.with(|cell| cell.borrow().as_ref().map(|g| g.apu_samples_available()).unwrap_or(0))
+}
+
+#[wasm_bindgen]
+pub fn gb_apu_drain(max: usize) -> Vec<f32> {
+    GB_SINGLETON.with(|cell| cell.borrow_mut().as_mut().map(|g| g.apu_drain(max)).unwrap_or_default())
+}
+
+#[wasm_bindgen]
+pub fn gb_apu_dropped_frames() -> u32 {
+    GB_SINGLETON.with(|cell| cell.borrow().as_ref().map(|g| g.apu_dropped_frames()).unwrap_or(0))
+}
+
 #[wasm_bindgen]
 pub fn screen_width() -> usize { SCREEN_WIDTH }
 
+#[wasm_bindgen]
+pub fn audio_sample_rate() -> u32 {
+    GB_SINGLETON.with(|cell| cell.borrow().as_ref().map(|g| g.sample_rate()).unwrap_or(DEFAULT_SAMPLE_RATE))
+}
+
+#[wasm_bindgen]
+pub fn gb_set_sample_rate(hz: u32) {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.set_sample_rate(hz); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_set_audio_buffer_size(frames: usize) {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.set_audio_buffer_size(frames); }
+    });
+}
+
+// JSON `{buffered, underruns, overruns}`
+#[wasm_bindgen]
+pub fn gb_audio_stats() -> String {
+    GB_SINGLETON.with(|cell| match cell.borrow().as_ref() {
+        Some(gb) => serde_json::to_string(&gb.audio_stats()).unwrap_or_else(|_| "null".to_string()),
+        None => "null".to_string(),
+    })
+}
+
+#[wasm_bindgen]
+pub fn gb_set_raw_audio(enabled: bool) {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.set_raw_audio(enabled); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_native_sample_rate() -> u32 {
+    NATIVE_SAMPLE_RATE
+}
+
 #[wasm_bindgen]
 pub fn screen_height() -> usize { SCREEN_HEIGHT }
 
@@ -1549,6 +2714,13 @@ pub fn gb_press_button(bit: u8) {
     });
 }
 
+#[wasm_bindgen]
+pub fn gb_set_input_state(pressed: u8) {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.set_input_state(pressed); }
+    });
+}
+
 #[wasm_bindgen]
 pub fn gb_release_button(bit: u8) {
     GB_SINGLETON.with(|cell| {
@@ -1564,8 +2736,1200 @@ pub fn gb_save_state() -> String {
 }
 
 #[wasm_bindgen]
-pub fn gb_load_state(state: &str) {
+pub fn gb_load_state(state: &str) -> bool {
+    GB_SINGLETON.with(|cell| {
+        cell.borrow_mut().as_mut().map(|gb| gb.load_state(state)).unwrap_or(false)
+    })
+}
+
+#[wasm_bindgen]
+pub fn gb_set_rewind(interval_frames: u32, max_snapshots: usize) {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.set_rewind(interval_frames, max_snapshots); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_rewind() -> bool {
+    GB_SINGLETON.with(|cell| cell.borrow_mut().as_mut().map(|gb| gb.rewind()).unwrap_or(false))
+}
+
+#[wasm_bindgen]
+pub fn gb_rewind_frames(frames: u32) -> bool {
+    GB_SINGLETON.with(|cell| cell.borrow_mut().as_mut().map(|gb| gb.rewind_frames(frames)).unwrap_or(false))
+}
+
+#[wasm_bindgen]
+pub fn gb_save_state_binary() -> Vec<u8> {
+    GB_SINGLETON.with(|cell| cell.borrow().as_ref().map(|gb| gb.save_state_binary()).unwrap_or_default())
+}
+
+#[wasm_bindgen]
+pub fn gb_load_state_binary(state: &[u8]) -> bool {
+    GB_SINGLETON.with(|cell| {
+        cell.borrow_mut().as_mut().map(|gb| gb.load_state_binary(state)).unwrap_or(false)
+    })
+}
+
+#[wasm_bindgen]
+pub fn gb_interrupt_state() -> String {
+    GB_SINGLETON.with(|cell| {
+        match cell.borrow().as_ref() {
+            Some(gb) => serde_json::to_string(&gb.interrupt_state()).unwrap_or_else(|_| "{}".to_string()),
+            None => "{}".to_string(),
+        }
+    })
+}
+
+#[wasm_bindgen]
+pub fn gb_set_break_on_invalid_access(enabled: bool) {
     GB_SINGLETON.with(|cell| {
-        if let Some(gb) = cell.borrow_mut().as_mut() { gb.load_state(state); }
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.set_break_on_invalid_access(enabled); }
     });
-}
\ No newline at end of file
+}
+
+#[wasm_bindgen]
+pub fn gb_set_interrupt_mask(mask: u8) {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.set_interrupt_mask(mask); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_set_warn_on_lcd_disable_outside_vblank(enabled: bool) {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.set_warn_on_lcd_disable_outside_vblank(enabled); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_lcd_disable_warning() -> Option<u8> {
+    GB_SINGLETON.with(|cell| cell.borrow().as_ref().and_then(|g| g.lcd_disable_warning()))
+}
+
+// JSON array of queued events, e.g. ["FrameReady",{"BreakpointHit":336}]
+#[wasm_bindgen]
+pub fn gb_poll_events() -> String {
+    GB_SINGLETON.with(|cell| {
+        let events = cell.borrow_mut().as_mut().map(|gb| gb.poll_events()).unwrap_or_default();
+        serde_json::to_string(&events).unwrap_or_else(|_| "[]".to_string())
+    })
+}
+
+#[wasm_bindgen]
+pub fn gb_set_input_history(enabled: bool) {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.set_input_history(enabled); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_input_history() -> String {
+    GB_SINGLETON.with(|cell| {
+        let history = cell.borrow().as_ref().map(|gb| gb.input_history()).unwrap_or_default();
+        serde_json::to_string(&history).unwrap_or_else(|_| "[]".to_string())
+    })
+}
+
+#[wasm_bindgen]
+pub fn gb_add_breakpoint(addr: u16) {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.add_breakpoint(addr); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_remove_breakpoint(addr: u16) {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.remove_breakpoint(addr); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_clear_breakpoints() {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.clear_breakpoints(); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_export_sram() -> Vec<u8> {
+    GB_SINGLETON.with(|cell| cell.borrow().as_ref().map(|gb| gb.export_sram()).unwrap_or_default())
+}
+
+#[wasm_bindgen]
+pub fn gb_import_sram(data: &[u8]) -> bool {
+    GB_SINGLETON.with(|cell| {
+        cell.borrow_mut().as_mut().map(|gb| gb.import_sram(data)).unwrap_or(false)
+    })
+}
+
+#[wasm_bindgen]
+pub fn gb_serial_output() -> String {
+    GB_SINGLETON.with(|cell| cell.borrow().as_ref().map(|gb| gb.serial_output()).unwrap_or_default())
+}
+
+#[wasm_bindgen]
+pub fn gb_clear_serial_output() {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.clear_serial_output(); }
+    });
+}
+
+// Link cable: a second core in its own slot, driven with the gb_link_*
+// functions alongside the main one (create that first). Run a frame on
+// each, then gb_link_step
+#[wasm_bindgen]
+pub fn gb_link_create() {
+    let mut partner = GameBoy::new();
+    partner.set_link_connected(true);
+    GB_LINK_PARTNER.with(|cell| *cell.borrow_mut() = Some(partner));
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.set_link_connected(true); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_link_destroy() {
+    GB_LINK_PARTNER.with(|cell| *cell.borrow_mut() = None);
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.set_link_connected(false); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_link_load_rom(data: &[u8]) {
+    GB_LINK_PARTNER.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.load_rom(data); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_link_start() {
+    GB_LINK_PARTNER.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.start(); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_link_run_frame() -> bool {
+    GB_LINK_PARTNER.with(|cell| cell.borrow_mut().as_mut().map(|gb| gb.run_frame()).unwrap_or(false))
+}
+
+#[wasm_bindgen]
+pub fn gb_link_set_input_state(pressed: u8) {
+    GB_LINK_PARTNER.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.set_input_state(pressed); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_link_frame_buffer_ptr() -> *const u8 {
+    GB_LINK_PARTNER.with(|cell| cell.borrow().as_ref().map(|gb| gb.frame_buffer_ptr()).unwrap_or(std::ptr::null()))
+}
+
+#[wasm_bindgen]
+pub fn gb_link_frame_buffer_len() -> usize {
+    GB_LINK_PARTNER.with(|cell| cell.borrow().as_ref().map(|gb| gb.frame_buffer_len()).unwrap_or(0))
+}
+
+// Swap pending serial bytes between the two cores; true if a byte crossed
+#[wasm_bindgen]
+pub fn gb_link_step() -> bool {
+    GB_SINGLETON.with(|main| {
+        GB_LINK_PARTNER.with(|partner| {
+            match (main.borrow_mut().as_mut(), partner.borrow_mut().as_mut()) {
+                (Some(a), Some(b)) => GameBoy::link_exchange(a, b),
+                _ => false,
+            }
+        })
+    })
+}
+
+#[wasm_bindgen]
+pub fn gb_set_serial_byte_in(val: u8) {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.set_serial_byte_in(val); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_set_rendering_enabled(enabled: bool) {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.set_rendering_enabled(enabled); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_double_speed() -> bool {
+    GB_SINGLETON.with(|cell| cell.borrow().as_ref().map(|gb| gb.double_speed()).unwrap_or(false))
+}
+
+#[wasm_bindgen]
+pub fn gb_current_model() -> Model {
+    GB_SINGLETON.with(|cell| cell.borrow().as_ref().map(|gb| gb.current_model()).unwrap_or(Model::Dmg))
+}
+
+#[wasm_bindgen]
+pub fn gb_set_model(model: Model) {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.set_model(model); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_clear_model_override() {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.clear_model_override(); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_set_rtc_now(unix_secs: f64) {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.set_rtc_now(unix_secs); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_set_dma_timing(timing: DmaTiming) {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.set_dma_timing(timing); }
+    });
+}
+
+#[wasm_bindgen]
+pub fn gb_set_strict_oam_dma(enabled: bool) {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.set_strict_oam_dma(enabled); }
+    });
+}
+
+// JSON `{addr, write, pc}` of the access that paused emulation, or "null"
+#[wasm_bindgen]
+pub fn gb_invalid_access() -> String {
+    GB_SINGLETON.with(|cell| {
+        let access = cell.borrow().as_ref().and_then(|gb| gb.invalid_access());
+        serde_json::to_string(&access).unwrap_or_else(|_| "null".to_string())
+    })
+}
+
+#[wasm_bindgen]
+pub fn gb_load_reference_trace(text: &str) -> usize {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.load_reference_trace(text) } else { 0 }
+    })
+}
+
+#[wasm_bindgen]
+pub fn gb_clear_reference_trace() {
+    GB_SINGLETON.with(|cell| {
+        if let Some(gb) = cell.borrow_mut().as_mut() { gb.clear_reference_trace(); }
+    });
+}
+
+// JSON `{index, expected_pc, expected_opcode, actual_pc, actual_opcode}` of
+// the first mismatch against the reference trace, or "null"
+#[wasm_bindgen]
+pub fn gb_trace_divergence() -> String {
+    GB_SINGLETON.with(|cell| {
+        let divergence = cell.borrow().as_ref().and_then(|gb| gb.trace_divergence());
+        serde_json::to_string(&divergence).unwrap_or_else(|_| "null".to_string())
+    })
+}
+
+// JSON `[pc, opcode, operand1, operand2]` (missing operands are null)
+#[wasm_bindgen]
+pub fn gb_last_instruction() -> String {
+    GB_SINGLETON.with(|cell| match cell.borrow().as_ref() {
+        Some(gb) => serde_json::to_string(&gb.last_instruction()).unwrap_or_else(|_| "null".to_string()),
+        None => "null".to_string(),
+    })
+}
+
+// JSON `{cycles_this_frame, audio_samples_this_frame, expected_samples}`
+#[wasm_bindgen]
+pub fn gb_sync_report() -> String {
+    GB_SINGLETON.with(|cell| match cell.borrow().as_ref() {
+        Some(gb) => serde_json::to_string(&gb.sync_report()).unwrap_or_else(|_| "null".to_string()),
+        None => "null".to_string(),
+    })
+}
+
+// JSON `[source, bytes_copied]` of the OAM DMA in flight, or "null"
+#[wasm_bindgen]
+pub fn gb_oam_dma_progress() -> String {
+    GB_SINGLETON.with(|cell| {
+        let progress = cell.borrow().as_ref().and_then(|gb| gb.oam_dma_progress());
+        serde_json::to_string(&progress).unwrap_or_else(|_| "null".to_string())
+    })
+}
+
+// JSON array of per-scanline LCD register snapshots for the last frame
+#[wasm_bindgen]
+pub fn gb_scanline_registers() -> String {
+    GB_SINGLETON.with(|cell| match cell.borrow().as_ref() {
+        Some(gb) => serde_json::to_string(gb.scanline_registers()).unwrap_or_else(|_| "[]".to_string()),
+        None => "[]".to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A machine with `code` at the 0x0100 entry point
+    fn machine(code: &[u8]) -> GameBoy {
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x100..0x100 + code.len()].copy_from_slice(code);
+        let mut gb = GameBoy::new();
+        gb.load_rom(&rom);
+        gb
+    }
+
+    // ROM jumping from 0x0100 to `code` at 0x0150, with a subroutine at
+    // 0x0200 sending A over serial and waiting for the transfer
+    fn program_rom(code: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x100..0x103].copy_from_slice(&[0xc3, 0x50, 0x01]);
+        rom[0x150..0x150 + code.len()].copy_from_slice(code);
+        rom[0x200..0x20d].copy_from_slice(&[0xe0, 0x01, 0x3e, 0x81, 0xe0, 0x02, 0xf0, 0x02, 0xcb, 0x7f, 0x20, 0xfa, 0xc9]);
+        rom
+    }
+
+    fn program(code: &[u8]) -> GameBoy {
+        let mut gb = GameBoy::new();
+        gb.load_rom(&program_rom(code));
+        gb.start();
+        gb
+    }
+
+    // T-cycles per opcode, conditional branches not taken. 0xCB is timed with
+    // its second byte by cb_opcode_cycles; 0 marks the illegal opcodes, which
+    // lock up real hardware and are not checked
+    #[rustfmt::skip]
+    const OPCODE_CYCLES: [u8; 256] = [
+        //  0   1   2   3   4   5   6   7   8   9   A   B   C   D   E   F
+         4, 12,  8,  8,  4,  4,  8,  4, 20,  8,  8,  8,  4,  4,  8,  4, // 0x
+         4, 12,  8,  8,  4,  4,  8,  4, 12,  8,  8,  8,  4,  4,  8,  4, // 1x
+         8, 12,  8,  8,  4,  4,  8,  4,  8,  8,  8,  8,  4,  4,  8,  4, // 2x
+         8, 12,  8,  8, 12, 12, 12,  4,  8,  8,  8,  8,  4,  4,  8,  4, // 3x
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 4x
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 5x
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 6x
+         8,  8,  8,  8,  8,  8,  4,  8,  4,  4,  4,  4,  4,  4,  8,  4, // 7x
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 8x
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 9x
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // Ax
+         4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // Bx
+         8, 12, 12, 16, 12, 16,  8, 16,  8, 16, 12,  0, 12, 24,  8, 16, // Cx
+         8, 12, 12,  0, 12, 16,  8, 16,  8, 16, 12,  0, 12,  0,  8, 16, // Dx
+        12, 12,  8,  0,  0, 16,  8, 16, 16,  4, 16,  0,  0,  0,  8, 16, // Ex
+        12, 12,  8,  4,  0, 16,  8, 16, 12,  8, 16,  4,  0,  0,  8, 16, // Fx
+    ];
+
+    // Extra T-cycles a conditional JR/JP/CALL/RET takes when the branch is taken
+    fn branch_taken_penalty(opcode: u8) -> Option<u8> {
+        match opcode {
+            0x20 | 0x28 | 0x30 | 0x38 | 0xc2 | 0xca | 0xd2 | 0xda => Some(4),
+            0xc4 | 0xcc | 0xd4 | 0xdc => Some(12),
+            0xc0 | 0xc8 | 0xd0 | 0xd8 => Some(12),
+            _ => None,
+        }
+    }
+
+    // T-cycles of a whole CB-prefixed instruction: 8, 16 on (HL), but only 12 for
+    // BIT n,(HL), which does not write back
+    fn cb_opcode_cycles(opcode: u8) -> u8 {
+        match (opcode & 0x07, opcode >> 6) {
+            (6, 1) => 12,
+            (6, _) => 16,
+            _ => 8,
+        }
+    }
+
+    // One opcode whose measured timing disagrees with the reference tables.
+    // `opcode` is 0xCBxx for CB-prefixed instructions
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct TimingMismatch {
+        opcode: u16,
+        taken: bool,
+        expected: u32,
+        actual: u32,
+    }
+
+    impl GameBoy {
+        // Timing regression check: run every opcode once on a scratch machine
+        // and compare the cycles step_cpu reports with OPCODE_CYCLES and
+        // cb_opcode_cycles. Conditional branches are timed taken and not taken.
+        // Empty when the whole timing contract holds
+        fn verify_opcode_timing() -> Vec<TimingMismatch> {
+            let mut mismatches = Vec::new();
+            for opcode in 0..=0xffu8 {
+                let base = OPCODE_CYCLES[opcode as usize] as u32;
+                if base == 0 || opcode == 0xcb {
+                    continue;
+                }
+                let penalty = branch_taken_penalty(opcode);
+                // F=0x00 fails the Z and C conditions, F=0xF0 passes them
+                let flag_sets: &[u8] = if penalty.is_some() { &[0x00, 0xf0] } else { &[0x00] };
+                for &flags in flag_sets {
+                    let wants_set = opcode & 0x08 != 0;
+                    let taken = penalty.is_some() && wants_set == (flags != 0);
+                    let expected = base + if taken { penalty.unwrap_or(0) as u32 } else { 0 };
+                    let actual = Self::time_instruction(&[opcode, 0x00, 0xc1], flags);
+                    if actual != expected {
+                        mismatches.push(TimingMismatch { opcode: opcode as u16, taken, expected, actual });
+                    }
+                }
+            }
+            for cb in 0..=0xffu8 {
+                let expected = cb_opcode_cycles(cb) as u32;
+                let actual = Self::time_instruction(&[0xcb, cb], 0x00);
+                if actual != expected {
+                    mismatches.push(TimingMismatch { opcode: 0xcb00 | cb as u16, taken: false, expected, actual });
+                }
+            }
+            mismatches
+        }
+
+        // Cycles for one instruction at 0x0100 with interrupts off. Immediate
+        // operands read 0x00 0xC1 and BC/DE/HL/SP point into WRAM, so memory
+        // operands and jumps stay on harmless addresses
+        fn time_instruction(code: &[u8], flags: u8) -> u32 {
+            let mut rom = vec![0u8; 0x8000];
+            rom[0x100..0x100 + code.len()].copy_from_slice(code);
+            let mut gb = GameBoy::new();
+            gb.load_rom(&rom);
+            gb.registers.f = flags;
+            gb.registers.set_bc(0xc100);
+            gb.registers.set_de(0xc100);
+            gb.registers.set_hl(0xc100);
+            gb.registers.sp = 0xdff0;
+            gb.step_cpu()
+        }
+    }
+
+    #[test]
+    fn opcode_timing_matches_tables() {
+        assert_eq!(GameBoy::verify_opcode_timing(), Vec::new());
+    }
+
+    #[test]
+    fn last_instruction_reports_ld_bc_nn() {
+        let mut gb = machine(&[0x00, 0x01, 0x34, 0x12]);
+        gb.step_cpu();
+        assert_eq!(gb.last_instruction(), (0x0100, 0x00, None, None));
+        gb.step_cpu();
+        assert_eq!(gb.last_instruction(), (0x0101, 0x01, Some(0x34), Some(0x12)));
+        assert_eq!(gb.registers.bc(), 0x1234);
+    }
+
+    #[test]
+    fn last_instruction_records_halt_bug_operand() {
+        // DI; IE = IF = VBlank; HALT with nothing to service; LD B,0x42
+        let mut gb = machine(&[0xf3, 0x3e, 0x01, 0xe0, 0xff, 0xe0, 0x0f, 0x76, 0x06, 0x42]);
+        for _ in 0..5 {
+            gb.step_cpu();
+        }
+        // The HALT bug reads the opcode byte again as the operand
+        gb.step_cpu();
+        assert_eq!(gb.last_instruction(), (0x0108, 0x06, Some(0x06), None));
+        assert_eq!(gb.registers.b, 0x06);
+    }
+
+    // LCD on, then BGP bumped at every VBlank so consecutive frames differ
+    const BGP_PER_FRAME: &[u8] = &[
+        0x3e, 0x91, 0xe0, 0x40, 0xaf, 0x47, 0xf0, 0x44, 0xfe, 0x90, 0x20, 0xfa, 0x78, 0x3c, 0x47, 0xe0, 0x47,
+        0xf0, 0x44, 0xfe, 0x90, 0x28, 0xfa, 0x18, 0xed,
+    ];
+
+    #[test]
+    fn frame_capture_keeps_last_frames() {
+        let mut gb = program(BGP_PER_FRAME);
+        gb.run_frame();
+        gb.start_frame_capture(3);
+        for _ in 0..5 {
+            gb.run_frame();
+        }
+        let frames = gb.take_captured_frames();
+        assert_eq!(frames.len(), 3);
+        assert!(frames.iter().all(|f| f.len() == 160 * 144 * 4));
+        assert!(frames[0] != frames[1] && frames[1] != frames[2] && frames[0] != frames[2]);
+        assert_eq!(frames[2], gb.ppu.get_frame_buffer());
+        assert!(gb.take_captured_frames().is_empty());
+    }
+
+    #[test]
+    fn captured_frames_are_rgba_in_rgb_mode() {
+        let mut gb = program(BGP_PER_FRAME);
+        gb.set_frame_buffer_format(FrameBufferFormat::Rgb);
+        gb.start_frame_capture(1);
+        gb.run_frame();
+        let frames = gb.take_captured_frames();
+        assert_eq!(frames[0].len(), 160 * 144 * 4);
+        let rgb = gb.ppu.get_frame_buffer();
+        for (rgba, rgb) in frames[0].chunks_exact(4).zip(rgb.chunks_exact(3)) {
+            assert_eq!(rgba, [rgb[0], rgb[1], rgb[2], 255]);
+        }
+    }
+
+    #[test]
+    fn interrupt_state_shows_vblank_pending() {
+        // IE = VBlank; IF cleared; JR -2
+        let mut gb = machine(&[0x3e, 0x01, 0xe0, 0xff, 0xaf, 0xe0, 0x0f, 0x18, 0xfe]);
+        for _ in 0..4 {
+            gb.step_cpu();
+        }
+        let state = gb.interrupt_state();
+        assert!(state.enabled[0] && !state.pending[0]);
+        gb.start();
+        gb.run_frame();
+        let state = gb.interrupt_state();
+        assert!(state.pending[0]);
+        assert_eq!(state.if_ & 0x01, 0x01);
+        assert!(!state.ime);
+        assert_eq!(state.enabled, [true, false, false, false, false]);
+    }
+
+    #[test]
+    fn save_state_mid_oam_dma_completes_after_load() {
+        // OAM DMA from 0x0000; NOPs; JR -2
+        let mut code = vec![0x3e, 0x00, 0xe0, 0x46];
+        code.extend([0x00; 20]);
+        code.extend([0x18, 0xfe]);
+        let mut rom = vec![0u8; 0x8000];
+        for (i, b) in rom.iter_mut().take(0xa0).enumerate() {
+            *b = i as u8 ^ 0x5a;
+        }
+        rom[0x100..0x100 + code.len()].copy_from_slice(&code);
+        let mut gb = GameBoy::new();
+        gb.load_rom(&rom);
+        gb.add_breakpoint(0x110);
+        gb.start();
+        gb.run_frame();
+        let (_, copied) = gb.oam_dma_progress().expect("DMA still running");
+        assert!(copied > 0 && copied < 0xa0);
+        let saved = gb.save_state();
+
+        let mut restored = GameBoy::new();
+        restored.load_rom(&rom);
+        assert!(restored.load_state(&saved));
+        restored.start();
+        restored.run_frame();
+        assert!(restored.oam_dma_progress().is_none());
+        assert_eq!(restored.mmu.get_oam(), &rom[..0xa0]);
+    }
+
+    #[test]
+    fn break_on_disabled_eram_read() {
+        // NOP; LD A,(0xA000); JR -2
+        let code = [0x00, 0xfa, 0x00, 0xa0, 0x18, 0xfe];
+        let mut gb = machine(&code);
+        gb.start();
+        gb.run_frame();
+        assert!(gb.is_running());
+
+        let mut gb = machine(&code);
+        gb.set_break_on_invalid_access(true);
+        gb.start();
+        gb.run_frame();
+        assert!(!gb.is_running());
+        let access = gb.invalid_access().expect("access reported");
+        assert_eq!((access.addr, access.write, access.pc), (0xa000, false, 0x0101));
+        assert!(gb.poll_events().contains(&Event::Stalled));
+    }
+
+    #[test]
+    fn oam_dma_from_disabled_eram_is_not_flagged() {
+        // OAM DMA from 0xA000; JR -2
+        let mut gb = machine(&[0x3e, 0xa0, 0xe0, 0x46, 0x18, 0xfe]);
+        gb.set_break_on_invalid_access(true);
+        gb.start();
+        gb.run_frame();
+        assert!(gb.is_running());
+        assert!(gb.invalid_access().is_none());
+    }
+
+    #[test]
+    fn dump_trace_last_returns_newest_in_order() {
+        // NOP; INC A; INC B; INC C; INC D; INC E; INC H; INC L
+        let mut gb = machine(&[0x00, 0x3c, 0x04, 0x0c, 0x14, 0x1c, 0x24, 0x2c]);
+        gb.enable_trace(true);
+        for _ in 0..8 {
+            gb.step_cpu();
+        }
+        let sp = gb.registers.sp;
+        let expected: String = [(0x0103, 0x0c), (0x0104, 0x14), (0x0105, 0x1c), (0x0106, 0x24), (0x0107, 0x2c)]
+            .iter()
+            .map(|(pc, op)| format!("{:04X}: {:02X} SP={:04X}\n", pc, op, sp))
+            .collect();
+        assert_eq!(gb.dump_trace_last(5), expected);
+        assert_eq!(gb.dump_trace_last(100).lines().count(), 8);
+    }
+
+    #[test]
+    fn reset_ppu_leaves_cpu_and_wram() {
+        let mut gb = program(BGP_PER_FRAME);
+        gb.run_frame();
+        gb.run_frame();
+        for _ in 0..100 {
+            gb.step_ppu_dot();
+        }
+        gb.mmu.write_byte(0xc123, 0x77);
+        assert_ne!(gb.ppu_dot(), 0);
+        assert!(gb.ppu.get_frame_buffer().iter().any(|&b| b != 0xff));
+        let pc = gb.get_pc();
+        let (a, sp) = (gb.registers.a, gb.registers.sp);
+
+        gb.reset_ppu();
+        assert_eq!(gb.ppu_dot(), 0);
+        assert_eq!(gb.mmu.read_byte(0xff44), 0);
+        assert!(gb.ppu.get_frame_buffer().iter().all(|&b| b == 0xff));
+        assert_eq!((gb.get_pc(), gb.registers.a, gb.registers.sp), (pc, a, sp));
+        assert_eq!(gb.mmu.read_byte(0xc123), 0x77);
+    }
+
+    #[test]
+    fn reference_trace_reports_first_mismatch() {
+        let mut gb = machine(&[0x00, 0x00, 0x18, 0xfc]);
+        assert_eq!(gb.load_reference_trace("0100:00\n0101:00\n0102:18\n# loop\n0100:01\n"), 4);
+        gb.start();
+        gb.run_frame();
+        let d = gb.trace_divergence().expect("divergence");
+        assert_eq!(d.index, 3);
+        assert_eq!((d.expected_pc, d.expected_opcode), (0x0100, 0x01));
+        assert_eq!((d.actual_pc, d.actual_opcode), (0x0100, 0x00));
+        assert!(!gb.is_running());
+        assert_eq!(gb.get_pc(), 0x0100);
+    }
+
+    #[test]
+    fn interrupt_mask_hides_pending_vblank() {
+        // EI, then NOPs
+        let mut gb = machine(&[0xfb, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        gb.set_interrupt_mask(0x04);
+        gb.mmu.write_byte(0xffff, 0x05);
+        gb.mmu.write_byte(0xff0f, 0x01);
+        for _ in 0..4 {
+            gb.step_cpu();
+        }
+        assert_eq!(gb.get_pc(), 0x0104);
+        assert_eq!(gb.mmu.read_byte(0xff0f) & 0x1f, 0x01);
+        // The timer interrupt still gets through
+        gb.mmu.write_byte(0xff0f, 0x05);
+        gb.step_cpu();
+        assert_eq!(gb.get_pc(), 0x0050);
+        assert_eq!(gb.mmu.read_byte(0xff0f) & 0x1f, 0x01);
+    }
+
+    #[test]
+    fn pop_af_masks_low_flag_nibble() {
+        // LD BC,0x12FF; PUSH BC; POP AF; PUSH AF; POP DE
+        let mut gb = machine(&[0x01, 0xff, 0x12, 0xc5, 0xf1, 0xf5, 0xd1]);
+        for _ in 0..3 {
+            gb.step_cpu();
+        }
+        assert_eq!(gb.registers.af(), 0x12f0);
+        gb.step_cpu();
+        assert_eq!(gb.mmu.read_byte(0xfffc), 0xf0);
+        gb.step_cpu();
+        assert_eq!(gb.registers.de(), 0x12f0);
+    }
+
+    #[test]
+    fn half_speed_emulates_every_other_call() {
+        let cycles_over = |multiplier: f32| {
+            let mut gb = machine(&[0x18, 0xfe]);
+            gb.start();
+            gb.set_speed_multiplier(multiplier);
+            let mut total = 0;
+            let mut presented = 0;
+            for _ in 0..10 {
+                if gb.run_frame() || gb.should_present() {
+                    presented += 1;
+                }
+                total += gb.last_frame_cycles;
+            }
+            (total, presented)
+        };
+        let (full, full_frames) = cycles_over(1.0);
+        let (half, half_frames) = cycles_over(0.5);
+        assert_eq!((full_frames, half_frames), (10, 5));
+        assert!(full.abs_diff(2 * half) <= 2 * 24, "{} vs {}", full, half);
+    }
+
+    #[test]
+    fn lcd_disable_at_line_50_warns() {
+        // Wait for LY=50, then turn the LCD off
+        let code = [0xf0, 0x44, 0xfe, 0x32, 0x20, 0xfa, 0x3e, 0x11, 0xe0, 0x40, 0x18, 0xfe];
+        let mut gb = program(&code);
+        gb.run_frame();
+        assert_eq!(gb.lcd_disable_warning(), None);
+
+        let mut gb = program(&code);
+        gb.set_warn_on_lcd_disable_outside_vblank(true);
+        gb.run_frame();
+        assert_eq!(gb.lcd_disable_warning(), Some(50));
+        assert_eq!(gb.mmu.read_byte(0xff40) & 0x80, 0);
+    }
+
+    #[test]
+    fn oam_dma_progress_counts_bytes_copied() {
+        let mut gb = machine(&[]);
+        assert_eq!(gb.oam_dma_progress(), None);
+        gb.mmu.cpu_write(0xff46, 0xc0);
+        assert_eq!(gb.oam_dma_progress(), Some((0xc000, 0)));
+        // One byte per M-cycle
+        gb.mmu.step_oam_dma(40);
+        assert_eq!(gb.oam_dma_progress(), Some((0xc000, 10)));
+        gb.mmu.step_oam_dma(596);
+        assert_eq!(gb.oam_dma_progress(), Some((0xc000, 159)));
+        gb.mmu.step_oam_dma(4);
+        assert_eq!(gb.oam_dma_progress(), None);
+    }
+
+    #[test]
+    fn frame_audio_within_one_sample_of_expected() {
+        for rate in [44100, 48000] {
+            let mut gb = machine(&[0x18, 0xfe]);
+            gb.set_sample_rate(rate);
+            gb.start();
+            for _ in 0..3 {
+                gb.run_frame();
+                let report = gb.sync_report();
+                assert!(report.cycles_this_frame >= CYCLES_PER_FRAME);
+                let diff = report.audio_samples_this_frame as f64 - report.expected_samples;
+                assert!(diff.abs() <= 1.0, "{} Hz: {} vs {}", rate, report.audio_samples_this_frame, report.expected_samples);
+            }
+        }
+    }
+
+    #[test]
+    fn set_input_state_applies_all_buttons_at_once() {
+        let mut gb = machine(&[]);
+        gb.mmu.cpu_write(0xff00, 0x10);
+        gb.mmu.cpu_write(0xff0f, 0x00);
+        // A, Start, Right and Up
+        gb.set_input_state(0x59);
+        assert_eq!(gb.mmu.cpu_read(0xff00), 0xd6);
+        assert_eq!(gb.mmu.cpu_read(0xff0f) & 0x10, 0x10);
+        gb.mmu.cpu_write(0xff00, 0x20);
+        assert_eq!(gb.mmu.cpu_read(0xff00), 0xea);
+        gb.set_input_state(0x00);
+        assert_eq!(gb.mmu.cpu_read(0xff00), 0xef);
+    }
+
+    #[test]
+    fn current_model_follows_header_and_override() {
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x143] = 0x80;
+        let mut gb = GameBoy::new();
+        gb.load_rom(&rom);
+        assert_eq!(gb.current_model(), Model::Cgb);
+        gb.set_model(Model::Dmg);
+        assert_eq!(gb.current_model(), Model::Dmg);
+        assert!(!gb.mmu.is_gbc());
+        // The override outlives a reload until cleared
+        gb.load_rom(&rom);
+        assert_eq!(gb.current_model(), Model::Dmg);
+        gb.clear_model_override();
+        assert_eq!(gb.current_model(), Model::Cgb);
+    }
+
+    #[test]
+    fn run_until_serial_contains_stops_at_the_pattern() {
+        // Send "OK" over serial, then spin
+        let code = [0x3e, b'O', 0xcd, 0x00, 0x02, 0x3e, b'K', 0xcd, 0x00, 0x02, 0x18, 0xfe];
+        let mut gb = GameBoy::new();
+        gb.load_rom(&program_rom(&code));
+        assert!(gb.run_until_serial_contains("OK", 3));
+        assert!(!gb.run_until_serial_contains("NOPE", 3));
+    }
+
+    #[test]
+    fn adc_sbc_flags_for_every_input() {
+        let mut gb = GameBoy::new();
+        for a in 0..=255u8 {
+            for value in 0..=255u8 {
+                for carry in [false, true] {
+                    let c = carry as u16;
+                    // Carry out of bit 3 and bit 7 from the full-width result
+                    let sum = a as u16 + value as u16 + c;
+                    let diff = (a as u16).wrapping_sub(value as u16).wrapping_sub(c);
+                    let cases = [
+                        (sum, (a as u16 ^ value as u16 ^ sum) & 0x10 != 0, sum > 0xff),
+                        (diff, (a as u16 ^ value as u16 ^ diff) & 0x10 != 0, diff > 0xff),
+                    ];
+                    for (sbc, &(full, h, c_out)) in cases.iter().enumerate() {
+                        gb.registers.a = a;
+                        gb.registers.set_flag_c(carry);
+                        if sbc == 1 { gb.sbc8(value) } else { gb.adc8(value) }
+                        let r = &gb.registers;
+                        let got = (r.a, r.flag_z(), r.flag_n(), r.flag_h(), r.flag_c());
+                        let want = (full as u8, full as u8 == 0, sbc == 1, h, c_out);
+                        assert_eq!(got, want, "{} a={:02x} v={:02x} c={}", ["adc", "sbc"][sbc], a, value, carry);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn frames_and_breakpoints_queue_events() {
+        let mut gb = machine(&[0x00, 0x00, 0x18, 0xfc]);
+        gb.start();
+        gb.run_frame();
+        gb.run_frame();
+        let events = gb.poll_events();
+        assert!(events.contains(&Event::FrameReady), "{:?}", events);
+        assert!(gb.poll_events().is_empty());
+
+        gb.add_breakpoint(0x101);
+        gb.run_frame();
+        assert_eq!(gb.poll_events(), vec![Event::BreakpointHit(0x101)]);
+        gb.start();
+        gb.run_frame();
+        assert!(gb.poll_events().contains(&Event::BreakpointHit(0x101)));
+    }
+
+    #[test]
+    fn key1_and_stop_switch_speed() {
+        // LD A,1; LDH (4D),A; STOP; NOP; JR -2
+        let mut rom = program_rom(&[0x3e, 0x01, 0xe0, 0x4d, 0x10, 0x00, 0x18, 0xfe]);
+        rom[0x143] = 0x80;
+        let mut gb = GameBoy::new();
+        gb.load_rom(&rom);
+        gb.start();
+        assert!(!gb.double_speed());
+        gb.run_frame();
+        assert!(gb.double_speed());
+        assert_eq!(gb.mmu.cpu_read(0xff4d), 0xfe);
+        // Without the prepare bit STOP leaves the speed alone
+        let mut gb = GameBoy::new();
+        rom[0x151] = 0x00;
+        gb.load_rom(&rom);
+        gb.start();
+        gb.run_frame();
+        assert!(!gb.double_speed());
+    }
+
+    #[test]
+    fn boot_rom_runs_from_zero_until_ff50() {
+        let mut gb = machine(&[0x18, 0xfe]);
+        let mut boot = vec![0u8; 0x100];
+        // LD A,1; LDH (50),A as the last bytes, so PC falls through to 0x0100
+        boot[0xfc..].copy_from_slice(&[0x3e, 0x01, 0xe0, 0x50]);
+        assert!(!gb.load_boot_rom(&[0; 10]));
+        assert!(gb.load_boot_rom(&boot));
+        assert_eq!((gb.get_pc(), gb.registers.af(), gb.registers.sp), (0x0000, 0x0000, 0x0000));
+        assert!(!gb.boot_rom_disabled());
+        assert_eq!(gb.mmu.cpu_read(0x00fc), 0x3e);
+        gb.start();
+        gb.run_frame();
+        assert!(gb.boot_rom_disabled());
+        assert_eq!(gb.get_pc(), 0x0100);
+        assert_eq!(gb.mmu.cpu_read(0x00fc), 0x00);
+        // Back to fast boot
+        assert!(gb.load_boot_rom(&[]));
+        assert!(gb.boot_rom_disabled());
+        assert_eq!(gb.get_pc(), 0x0100);
+    }
+
+    // Serial bytes sent in one frame by a CGB loop restarting the transfer
+    // with SC value `sc`, optionally after switching to double speed
+    fn serial_bytes_per_frame(sc: u8, double_speed: bool) -> usize {
+        let mut code = vec![];
+        if double_speed {
+            code.extend_from_slice(&[0x3e, 0x01, 0xe0, 0x4d, 0x10, 0x00]);
+        }
+        let start = code.len();
+        code.extend_from_slice(&[0x3e, sc, 0xe0, 0x02, 0xf0, 0x02, 0xcb, 0x7f, 0x20, 0xfa]);
+        code.extend_from_slice(&[0x18, (start as i32 - code.len() as i32 - 2) as i8 as u8]);
+        let mut rom = program_rom(&code);
+        rom[0x143] = 0x80;
+        let mut gb = GameBoy::new();
+        gb.load_rom(&rom);
+        gb.start();
+        gb.run_frame();
+        gb.poll_events();
+        gb.run_frame();
+        gb.poll_events().iter().filter(|e| matches!(e, Event::SerialByte(_))).count()
+    }
+
+    #[test]
+    fn double_speed_shortens_serial_transfers() {
+        let normal = serial_bytes_per_frame(0x81, false);
+        let double = serial_bytes_per_frame(0x81, true);
+        assert!(normal > 0);
+        assert!(double.abs_diff(2 * normal) <= 1, "{} vs {}", double, normal);
+        // The fast clock bit is 32 times quicker again
+        assert!(serial_bytes_per_frame(0x83, false) > 16 * normal);
+    }
+
+    #[test]
+    fn halt_bug_refetches_the_next_opcode() {
+        // DI; IE = IF = VBlank; A = 0; HALT; INC A; send A
+        let mut gb = machine(&[
+            0xf3, 0x3e, 0x01, 0xe0, 0xff, 0xe0, 0x0f, 0xaf, 0x76, 0x3c,
+            0xe0, 0x01, 0x3e, 0x81, 0xe0, 0x02, 0x18, 0xfe,
+        ]);
+        gb.start();
+        gb.run_frame();
+        let sent: Vec<u8> = gb.poll_events().into_iter().filter_map(|e| if let Event::SerialByte(b) = e { Some(b) } else { None }).collect();
+        // The INC A after HALT runs twice
+        assert_eq!(sent, vec![2]);
+    }
+
+    #[test]
+    fn interrupts_fire_with_a_low_stack() {
+        // Return address stored at 0xC000; VBlank handler EI; RET
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x40..0x42].copy_from_slice(&[0xfb, 0xc9]);
+        rom[0x100..0x10a].copy_from_slice(&[0x31, 0x02, 0xc0, 0x3e, 0x01, 0xe0, 0xff, 0xfb, 0x18, 0xfe]);
+        let mut gb = GameBoy::new();
+        gb.load_rom(&rom);
+        gb.start();
+        for _ in 0..3 {
+            gb.run_frame();
+        }
+        let serviced = gb.poll_events().into_iter().filter(|e| *e == Event::InterruptServiced(0)).count();
+        assert!(serviced >= 2, "{}", serviced);
+        assert_eq!(gb.registers.sp, 0xc002);
+        assert_eq!(gb.mmu.read_byte(0xff0f) & 0x01, 0);
+    }
+
+    #[test]
+    fn ei_waits_one_instruction_and_di_cancels_it() {
+        // EI; DI: IME never comes on
+        let mut gb = machine(&[0xfb, 0xf3, 0x00, 0x00]);
+        gb.mmu.write_byte(0xffff, 0x01);
+        gb.mmu.write_byte(0xff0f, 0x01);
+        for _ in 0..4 {
+            gb.step_cpu();
+        }
+        assert_eq!(gb.get_pc(), 0x0104);
+        assert!(!gb.ime);
+
+        // EI; NOP; RET: the interrupt is taken after the NOP, before the RET
+        let mut gb = machine(&[0xfb, 0x00, 0xc9]);
+        gb.mmu.write_byte(0xffff, 0x01);
+        gb.mmu.write_byte(0xff0f, 0x01);
+        gb.step_cpu();
+        gb.step_cpu();
+        assert_eq!(gb.get_pc(), 0x0102);
+        gb.step_cpu();
+        assert_eq!(gb.get_pc(), 0x0040);
+        let sp = gb.registers.sp;
+        assert_eq!((gb.mmu.read_byte(sp), gb.mmu.read_byte(sp + 1)), (0x02, 0x01));
+    }
+
+    #[test]
+    fn version_info_matches_the_build() {
+        let info = GameBoy::version_info();
+        assert!(!info.crate_version.is_empty());
+        assert_eq!(info.crate_version, env!("CARGO_PKG_VERSION"));
+        assert!(FEATURES.iter().all(|f| info.features.iter().any(|g| g == f)));
+        assert_eq!(info.features.iter().any(|f| f == "debug-build"), cfg!(debug_assertions));
+        assert_eq!(info.features.len(), FEATURES.len() + cfg!(debug_assertions) as usize);
+        let json = gb_version_info();
+        assert!(json.contains(&format!("\"crate_version\":\"{}\"", info.crate_version)), "{}", json);
+    }
+
+    #[test]
+    fn input_history_records_each_frame() {
+        let mut gb = machine(&[0x18, 0xfe]);
+        gb.start();
+        gb.run_frame();
+        assert!(gb.input_history().is_empty());
+        gb.set_input_history(true);
+        let inputs = [0x00, 0x01, 0x81, 0x81, 0x10];
+        for &buttons in &inputs {
+            gb.set_input_state(buttons);
+            gb.run_frame();
+        }
+        let expected: Vec<InputRecord> = inputs.iter().enumerate()
+            .map(|(i, &buttons)| InputRecord { frame: 1 + i as u64, buttons })
+            .collect();
+        assert_eq!(gb.input_history(), expected);
+        gb.set_input_history(false);
+        assert!(gb.input_history().is_empty());
+    }
+
+    #[test]
+    fn joyp_reads_follow_press_and_release() {
+        let mut gb = machine(&[]);
+        gb.press_button(0);
+        gb.press_button(7);
+        // Upper bits read 1; the low nibble is active-low per group
+        gb.mmu.cpu_write(0xff00, 0x10);
+        assert_eq!(gb.mmu.cpu_read(0xff00), 0xde);
+        gb.mmu.cpu_write(0xff00, 0x20);
+        assert_eq!(gb.mmu.cpu_read(0xff00), 0xe7);
+        gb.mmu.cpu_write(0xff00, 0x00);
+        assert_eq!(gb.mmu.cpu_read(0xff00), 0xc6);
+        gb.mmu.cpu_write(0xff00, 0x30);
+        assert_eq!(gb.mmu.cpu_read(0xff00), 0xff);
+        gb.release_button(7);
+        gb.mmu.cpu_write(0xff00, 0x20);
+        assert_eq!(gb.mmu.cpu_read(0xff00), 0xef);
+    }
+
+    #[test]
+    fn ei_halt_services_the_pending_interrupt_after_halt() {
+        // Timer handler: LD B,0x55; RETI
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x50..0x53].copy_from_slice(&[0x06, 0x55, 0xd9]);
+        // IE = IF = timer; EI; HALT; INC C; JR -2
+        rom[0x100..0x10b].copy_from_slice(&[0x3e, 0x04, 0xe0, 0xff, 0xe0, 0x0f, 0xfb, 0x76, 0x0c, 0x18, 0xfe]);
+        let mut gb = GameBoy::new();
+        gb.load_rom(&rom);
+        gb.registers.b = 0;
+        gb.registers.c = 0;
+        let mut steps = 0;
+        while gb.get_pc() != 0x0050 {
+            gb.step_cpu();
+            steps += 1;
+            assert!(steps < 16, "interrupt never serviced");
+        }
+        let sp = gb.registers.sp;
+        assert_eq!((gb.mmu.read_byte(sp), gb.mmu.read_byte(sp + 1)), (0x08, 0x01));
+        assert_eq!(gb.registers.c, 0);
+        gb.step_cpu();
+        gb.step_cpu();
+        assert_eq!((gb.get_pc(), gb.registers.b), (0x0108, 0x55));
+        gb.step_cpu();
+        assert_eq!(gb.registers.c, 1);
+    }
+
+    #[test]
+    fn save_state_round_trip_is_deterministic() {
+        // Timer on, then forever: copy DIV to WRAM (HL wrapping in
+        // 0xC000-0xDFFF) and BGP, and send it over serial
+        let mut gb = program(&[
+            0x3e, 0x05, 0xe0, 0x07, 0x21, 0x00, 0xc0,
+            0xf0, 0x04, 0x22, 0xe0, 0x47, 0xcd, 0x00, 0x02,
+            0x7c, 0xe6, 0xdf, 0xf6, 0xc0, 0x67, 0x18, 0xf0,
+        ]);
+        for _ in 0..10 {
+            gb.run_frame();
+        }
+        let saved = gb.save_state();
+        let run = |gb: &mut GameBoy| {
+            for _ in 0..5 {
+                gb.run_frame();
+            }
+            (gb.save_state(), gb.ppu.get_frame_buffer().to_vec())
+        };
+        let first = run(&mut gb);
+        assert!(gb.load_state(&saved));
+        let second = run(&mut gb);
+        assert!(first == second, "runs from the same state diverged");
+        assert!(first.0 != saved);
+    }
+
+    #[test]
+    fn binary_and_json_states_restore_the_same_machine() {
+        // Count in WRAM and send the count over serial
+        let code = [0x21, 0x00, 0xc0, 0x34, 0x7e, 0xcd, 0x00, 0x02, 0x18, 0xf9];
+        let mut gb = program(&code);
+        for _ in 0..7 {
+            gb.run_frame();
+        }
+        let json = gb.save_state();
+        let binary = gb.save_state_binary();
+        assert!(binary.len() < json.len());
+
+        let mut from_json = program(&code);
+        let mut from_binary = program(&code);
+        assert!(from_json.load_state(&json));
+        assert!(from_binary.load_state_binary(&binary));
+        assert_eq!(from_binary.save_state(), json);
+        assert_eq!(from_json.save_state_binary(), binary);
+        from_json.run_frame();
+        from_binary.run_frame();
+        assert!(from_json.save_state_binary() == from_binary.save_state_binary());
+
+        // A different version byte is refused and changes nothing
+        let before = from_binary.save_state_binary();
+        let mut stale = binary.clone();
+        stale[BINARY_STATE_MAGIC.len()] ^= 0xff;
+        assert!(!from_binary.load_state_binary(&stale));
+        assert!(!from_binary.load_state_binary(&binary[1..]));
+        assert!(from_binary.save_state_binary() == before);
+    }
+
+    #[test]
+    fn rewind_resumes_from_the_snapshot() {
+        // Count in WRAM and send the count over serial
+        let mut gb = program(&[0x21, 0x00, 0xc0, 0x34, 0x7e, 0xcd, 0x00, 0x02, 0x18, 0xf9]);
+        gb.set_rewind(2, 3);
+        // Snapshots after frames 2, 4, 6 and 8; the first is dropped
+        let mut states = Vec::new();
+        for _ in 0..8 {
+            gb.run_frame();
+            states.push(gb.save_state_binary());
+        }
+        assert_eq!(gb.rewind_snapshot_count(), 3);
+
+        gb.run_frame();
+        assert!(gb.rewind());
+        assert!(gb.save_state_binary() == states[7]);
+        assert!(gb.rewind());
+        assert!(gb.save_state_binary() == states[5]);
+        assert_eq!(gb.rewind_snapshot_count(), 1);
+        // Running on from there retraces the original frames
+        gb.run_frame();
+        assert!(gb.save_state_binary() == states[6]);
+        gb.run_frame();
+        assert!(gb.save_state_binary() == states[7]);
+
+        // That took a new snapshot after frame 8. Further back than any
+        // snapshot lands on the oldest one and empties the buffer
+        assert_eq!(gb.rewind_snapshot_count(), 2);
+        assert!(gb.rewind_frames(100));
+        assert!(gb.save_state_binary() == states[3]);
+        assert!(!gb.rewind());
+    }
+
+    #[test]
+    fn link_cable_swaps_a_byte_between_two_cores() {
+        // Send `byte` with SC `sc`, wait, store what came back at 0xC000
+        let core = |byte: u8, sc: u8| {
+            let mut gb = machine(&[
+                0x3e, byte, 0xe0, 0x01, 0x3e, sc, 0xe0, 0x02,
+                0xf0, 0x02, 0xcb, 0x7f, 0x20, 0xfa,
+                0xf0, 0x01, 0xea, 0x00, 0xc0, 0x18, 0xfe,
+            ]);
+            gb.set_link_connected(true);
+            gb.start();
+            gb
+        };
+        let mut master = core(0x42, 0x81);
+        let mut slave = core(0x99, 0x80);
+        master.run_frame();
+        slave.run_frame();
+        // Both waiting: the exchange completes both transfers
+        assert_eq!(master.mmu.read_byte(0xff02) & 0x80, 0x80);
+        assert!(GameBoy::link_exchange(&mut master, &mut slave));
+        assert!(!GameBoy::link_exchange(&mut master, &mut slave));
+        master.run_frame();
+        slave.run_frame();
+        assert_eq!((master.mmu.read_byte(0xc000), slave.mmu.read_byte(0xc000)), (0x99, 0x42));
+        assert_eq!(master.mmu.read_byte(0xff0f) & 0x08, 0x08);
+        assert_eq!(slave.mmu.read_byte(0xff0f) & 0x08, 0x08);
+
+        // A master with nobody listening reads 0xFF; a lone slave waits
+        let mut master = core(0x42, 0x81);
+        let mut slave = core(0x99, 0x80);
+        master.run_frame();
+        slave.run_frame();
+        let mut unplugged = machine(&[0x18, 0xfe]);
+        assert!(!GameBoy::link_exchange(&mut master, &mut unplugged));
+        assert!(!GameBoy::link_exchange(&mut unplugged, &mut slave));
+        master.run_frame();
+        slave.run_frame();
+        assert_eq!(master.mmu.read_byte(0xc000), 0xff);
+        assert_eq!(slave.mmu.read_byte(0xff02) & 0x80, 0x80);
+    }
+}
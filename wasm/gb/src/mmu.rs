@@ -1,27 +1,161 @@
-// MMU: memory map, banking, I/O
+use crate::apu::APU;
+use crate::input::Input;
+use crate::rtc::Rtc;
+use crate::serial::Serial;
+use crate::timer::Timer;
+use serde::{Deserialize, Serialize};
+use serde_big_array::BigArray;
+use std::cell::Cell;
+use wasm_bindgen::prelude::*;
+
+// OAM DMA transfer length (bytes) and cost per byte (T-cycles)
+const OAM_DMA_LEN: u8 = 0xa0;
+const OAM_DMA_CYCLES_PER_BYTE: u32 = 4;
+// T-cycles general-purpose HDMA halts the CPU for, per 16-byte block: 8
+// M-cycles, which take twice the CPU cycles in double speed
+const HDMA_STALL_PER_BLOCK: u32 = 32;
+const HDMA_STALL_PER_BLOCK_DOUBLE_SPEED: u32 = 64;
+
+// WRAM is switched in 4 KiB banks: two on DMG, eight on CGB
+const WRAM_BANK_SIZE: usize = 0x1000;
+
+// DMG register values after the boot ROM hands over at 0x0100 (Pan Docs,
+// "Power Up Sequence"); registers not listed read back as 0x00
+const DMG_POST_BOOT_IO: [(usize, u8); 41] = [
+    (0x00, 0xcf), // P1/JOYP: no group selected
+    (0x01, 0x00), // SB
+    (0x02, 0x7e), // SC
+    (0x04, 0xab), // DIV
+    (0x05, 0x00), // TIMA
+    (0x06, 0x00), // TMA
+    (0x07, 0xf8), // TAC
+    (0x0f, 0xe1), // IF: VBlank already requested
+    (0x10, 0x80), // NR10
+    (0x11, 0xbf), // NR11
+    (0x12, 0xf3), // NR12
+    (0x13, 0xff), // NR13
+    (0x14, 0xbf), // NR14
+    (0x16, 0x3f), // NR21
+    (0x17, 0x00), // NR22
+    (0x18, 0xff), // NR23
+    (0x19, 0xbf), // NR24
+    (0x1a, 0x7f), // NR30
+    (0x1b, 0xff), // NR31
+    (0x1c, 0x9f), // NR32
+    (0x1d, 0xff), // NR33
+    (0x1e, 0xbf), // NR34
+    (0x20, 0xff), // NR41
+    (0x21, 0x00), // NR42
+    (0x22, 0x00), // NR43
+    (0x23, 0xbf), // NR44
+    (0x24, 0x77), // NR50
+    (0x25, 0xf3), // NR51
+    (0x26, 0xf1), // NR52
+    (0x40, 0x91), // LCDC
+    (0x41, 0x85), // STAT: mode 1, LY=LYC
+    (0x42, 0x00), // SCY
+    (0x43, 0x00), // SCX
+    (0x44, 0x00), // LY
+    (0x45, 0x00), // LYC
+    (0x46, 0xff), // DMA
+    (0x47, 0xfc), // BGP
+    (0x48, 0xff), // OBP0: uninitialized on hardware
+    (0x49, 0xff), // OBP1: uninitialized on hardware
+    (0x4a, 0x00), // WY
+    (0x4b, 0x00), // WX
+];
+
+// One switch for every DMA engine. Accurate (the default) runs OAM DMA a
+// byte per M-cycle and HBlank HDMA a block per HBlank; Instant finishes any
+// transfer inside the write that starts it, trading accuracy for speed
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DmaTiming {
+    #[default]
+    Accurate = 0,
+    Instant = 1,
+}
+
+// Hardware being emulated. SGB only changes what is reported: the core
+// runs it as a DMG
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Model {
+    Dmg = 0,
+    Cgb = 1,
+    Sgb = 2,
+}
+
+// In-flight OAM DMA progress, captured in save states so a transfer
+// interrupted by a save resumes where it left off
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct OamDmaState {
+    pub source: u16,
+    pub offset: u8,
+    pub cycles: u32,
+}
+
+// Guest access to a prohibited region (disabled ERAM, the 0xFEA0-0xFEFF
+// hole, or an unmapped IO register); pc is filled in by the CPU
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct InvalidAccess {
+    pub addr: u16,
+    pub write: bool,
+    pub pc: u16,
+}
+
+// MMU: memory map, banking, I/O. Save states carry everything except the
+// cartridge and boot ROM images and the frontend's debug settings
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Serialize, Deserialize)]
 pub struct MMU {
+    #[serde(skip)]
     rom: Vec<u8>,
     vram: Vec<u8>,
     eram: Vec<u8>,
+    // Eight 4 KiB WRAM banks back to back. 0xC000 is always bank 0; 0xD000
+    // is bank 1 on DMG and the SVBK bank (1-7) on CGB
     wram: Vec<u8>,
+    #[serde(with = "BigArray")]
     oam: [u8; 0xa0],
+    #[serde(with = "BigArray")]
     io: [u8; 0x80],
+    #[serde(with = "BigArray")]
     hram: [u8; 0x7f],
     ie: u8,
     
     rom_bank: usize,
+    // Bank mapped at 0x0000-0x3FFF (non-zero only in MBC1 mode 1)
+    rom0_bank: usize,
+    // Bank count - 1 from the header ROM size; bank numbers wrap with it
+    rom_bank_mask: usize,
     ram_bank: usize,
     ram_enabled: bool,
     mbc_type: u8,
     banking_mode: u8,
+    // MBC1 0x4000-0x5FFF register, kept regardless of the banking mode so
+    // mode switches after the write still see it
+    bank_upper: usize,
+    // MBC3 clock; 0x08-0x0C written to 0x4000-0x5FFF maps one of its
+    // registers at 0xA000 instead of a RAM bank
+    rtc: Rtc,
+    rtc_select: Option<u8>,
     
     is_gbc: bool,
+    // CGB KEY1: current speed and the switch armed by bit 0, taken on STOP
+    double_speed: bool,
+    speed_switch_armed: bool,
+    // Header CGB flag (0x80/0xC0) and a frontend-forced model, if any
+    cgb_rom: bool,
+    #[serde(skip)]
+    model_override: Option<Model>,
     vram_bank: usize,
     wram_bank: usize,
     vram_banks: [Vec<u8>; 2],
-    wram_banks: Vec<Vec<u8>>,
     // CGB palette RAM and registers
+    #[serde(with = "BigArray")]
     cgb_bg_palette_data: [u8; 64],
+    #[serde(with = "BigArray")]
     cgb_obj_palette_data: [u8; 64],
     bgpi: u8,
     obpi: u8,
@@ -31,8 +165,45 @@ pub struct MMU {
     hdma_src: u16,
     hdma_dst: u16,
     hdma_remaining: u16, // bytes remaining
-    // Joypad state (active-low bits: 0=pressed)
-    joypad_buttons: u8,
+    // CPU cycles a general-purpose transfer halts the CPU for, collected by
+    // the main loop after the instruction that started it
+    hdma_stall: u32,
+    // OAM DMA (0xFF46): one byte per M-cycle, 160 bytes total
+    oam_dma_active: bool,
+    oam_dma_src: u16,
+    oam_dma_offset: u8,
+    oam_dma_cycles: u32,
+    // Only HRAM is CPU-accessible while OAM DMA runs (opt-in; off by default)
+    #[serde(skip)]
+    strict_oam_dma: bool,
+    #[serde(skip)]
+    dma_timing: DmaTiming,
+    // Held buttons; the only joypad state, read back through JOYP
+    input: Input,
+    // JOYP low nibble as last seen, for falling-edge interrupt detection
+    joypad_prev_lines: u8,
+    // Optional boot ROM: 256 bytes (DMG) or 2304 (CGB, whose 0x0200-0x08FF
+    // part maps there too). Empty means fast boot with post-boot state
+    #[serde(skip)]
+    boot_rom: Vec<u8>,
+    // 0xFF50: once set the boot ROM is unmapped until reset. Without a boot
+    // ROM it starts out latched
+    boot_rom_disabled: bool,
+    timer: Timer,
+    serial: Serial,
+    // Homebrew debugging: LY at which the LCD was last switched off outside
+    // VBlank (harmful on real hardware); only recorded when enabled
+    #[serde(skip)]
+    warn_on_lcd_disable: bool,
+    #[serde(skip)]
+    lcd_disable_warning: Option<u8>,
+    // Homebrew debugging: record the first access to a prohibited region
+    #[serde(skip)]
+    break_on_invalid_access: bool,
+    #[serde(skip)]
+    invalid_access: Cell<Option<InvalidAccess>>,
+    // Sound registers 0xFF10-0xFF3F are owned by the APU
+    apu: APU,
 }
 
 impl MMU {
@@ -41,21 +212,29 @@ impl MMU {
             rom: vec![0; 0x8000],
             vram: vec![0; 0x2000],
             eram: vec![0; 0x2000],
-            wram: vec![0; 0x2000],
+            wram: vec![0; 8 * WRAM_BANK_SIZE],
             oam: [0; 0xa0],
             io: [0; 0x80],
             hram: [0; 0x7f],
             ie: 0,
             rom_bank: 1,
+            rom0_bank: 0,
+            rom_bank_mask: 1,
             ram_bank: 0,
             ram_enabled: false,
             mbc_type: 0,
+            rtc: Rtc::new(),
+            rtc_select: None,
             banking_mode: 0,
+            bank_upper: 0,
             is_gbc: false,
+            double_speed: false,
+            speed_switch_armed: false,
+            cgb_rom: false,
+            model_override: None,
             vram_bank: 0,
             wram_bank: 1,
             vram_banks: [vec![0; 0x2000], vec![0; 0x2000]],
-            wram_banks: (0..8).map(|_| vec![0; 0x1000]).collect(),
             cgb_bg_palette_data: [0; 64],
             cgb_obj_palette_data: [0; 64],
             bgpi: 0,
@@ -65,7 +244,24 @@ impl MMU {
             hdma_src: 0,
             hdma_dst: 0,
             hdma_remaining: 0,
-            joypad_buttons: 0xff,
+            hdma_stall: 0,
+            oam_dma_active: false,
+            oam_dma_src: 0,
+            oam_dma_offset: 0,
+            oam_dma_cycles: 0,
+            strict_oam_dma: false,
+            dma_timing: DmaTiming::Accurate,
+            boot_rom: Vec::new(),
+            boot_rom_disabled: true,
+            timer: Timer::new(),
+            serial: Serial::new(),
+            input: Input::new(),
+            joypad_prev_lines: 0x0f,
+            warn_on_lcd_disable: false,
+            lcd_disable_warning: None,
+            break_on_invalid_access: false,
+            invalid_access: Cell::new(None),
+            apu: APU::new(),
         };
         mmu.reset();
         mmu
@@ -74,16 +270,25 @@ impl MMU {
     pub fn reset(&mut self) {
         // Do NOT clear ROM here keep loaded cartridge contents intact across resets
         self.vram.fill(0);
-        self.eram.fill(0);
+        // Battery-backed RAM outlives a power cycle
+        if !self.has_battery() {
+            self.eram.fill(0);
+        }
         self.wram.fill(0);
         self.oam.fill(0);
         self.io.fill(0);
         self.hram.fill(0);
         self.ie = 0;
         self.rom_bank = 1;
+        self.rom0_bank = 0;
+        self.double_speed = false;
+        self.speed_switch_armed = false;
         self.ram_bank = 0;
         self.ram_enabled = false;
         self.banking_mode = 0;
+        self.bank_upper = 0;
+        // The cartridge clock keeps running across resets
+        self.rtc_select = None;
         self.vram_bank = 0;
         self.wram_bank = 1;
     self.cgb_bg_palette_data.fill(0);
@@ -95,19 +300,47 @@ impl MMU {
         self.hdma_src = 0;
         self.hdma_dst = 0;
         self.hdma_remaining = 0;
-    self.joypad_buttons = 0xff;
+        self.hdma_stall = 0;
+        self.io[0x55] = 0xff;
+        self.oam_dma_active = false;
+        self.oam_dma_src = 0;
+        self.oam_dma_offset = 0;
+        self.oam_dma_cycles = 0;
+        self.input.reset();
+        self.joypad_prev_lines = 0x0f;
+        self.boot_rom_disabled = self.boot_rom.is_empty();
+        self.timer.reset();
+        self.serial.reset();
+        self.lcd_disable_warning = None;
+        self.invalid_access.set(None);
 
         // IO defaults
-    self.io[0x00] = 0xCF; // JOYP: no group selected, upper bits 1
-        self.io[0x05] = 0x00; self.io[0x06] = 0x00; self.io[0x07] = 0x00;
-        self.io[0x10] = 0x80; self.io[0x11] = 0xbf; self.io[0x12] = 0xf3; self.io[0x14] = 0xbf;
-        self.io[0x16] = 0x3f; self.io[0x17] = 0x00; self.io[0x19] = 0xbf;
-        self.io[0x1a] = 0x7f; self.io[0x1b] = 0xff; self.io[0x1c] = 0x9f; self.io[0x1e] = 0xbf;
-        self.io[0x20] = 0xff; self.io[0x21] = 0x00; self.io[0x22] = 0x00; self.io[0x23] = 0xbf;
-        self.io[0x24] = 0x77; self.io[0x25] = 0xf3; self.io[0x26] = 0xf1;
-        self.io[0x40] = 0x91; self.io[0x42] = 0x00; self.io[0x43] = 0x00; self.io[0x45] = 0x00;
-        self.io[0x47] = 0xfc; self.io[0x48] = 0xff; self.io[0x49] = 0xff;
-        self.io[0x4a] = 0x00; self.io[0x4b] = 0x00;
+        self.apu.reset();
+        // A boot ROM sets the hardware up itself from power-on state
+        if !self.boot_rom.is_empty() {
+            self.timer.write(0x04, 0, &mut self.io);
+            return;
+        }
+        for &(offset, val) in DMG_POST_BOOT_IO.iter() {
+            self.io[offset] = val;
+            if (0x10..=0x3f).contains(&offset) {
+                self.apu.restore_register(offset, val);
+            }
+        }
+    }
+
+    // False (nothing changed) unless `data` is a 256 or 2304 byte image; an
+    // empty slice removes the boot ROM. Takes effect on the next reset
+    pub fn load_boot_rom(&mut self, data: &[u8]) -> bool {
+        if !matches!(data.len(), 0 | 0x100 | 0x900) {
+            return false;
+        }
+        self.boot_rom = data.to_vec();
+        true
+    }
+
+    pub fn has_boot_rom(&self) -> bool {
+        !self.boot_rom.is_empty()
     }
 
     pub fn load_rom(&mut self, data: &[u8]) {
@@ -115,11 +348,22 @@ impl MMU {
         self.rom = vec![0; len];
         self.rom[..data.len()].copy_from_slice(data);
         
+        // 0x0148: 32 KiB << n. Unknown codes fall back to the image size
+        let banks = match data.get(0x0148) {
+            Some(&n) if n <= 8 => 2usize << n,
+            _ => (len / 0x4000).next_power_of_two(),
+        };
+        self.rom_bank_mask = banks - 1;
+
         if data.len() > 0x0147 {
             self.mbc_type = data[0x0147];
-            self.is_gbc = data.len() > 0x0143 && (data[0x0143] == 0x80 || data[0x0143] == 0xc0);
+            self.cgb_rom = data.len() > 0x0143 && (data[0x0143] == 0x80 || data[0x0143] == 0xc0);
+            self.is_gbc = self.model() == Model::Cgb;
             
-            if data.len() > 0x0149 {
+            if self.is_mbc2() {
+                // Built-in 512x4-bit RAM; the header RAM size is 0
+                self.eram = vec![0; 0x200];
+            } else if data.len() > 0x0149 {
                 let ram_size = data[0x0149];
                 let ram_sizes = [0, 0x800, 0x2000, 0x8000, 0x20000];
                 if (ram_size as usize) < ram_sizes.len() {
@@ -129,12 +373,169 @@ impl MMU {
         }
     }
 
+    // Effective model: the override if set, else what the header asks for
+    pub fn model(&self) -> Model {
+        self.model_override.unwrap_or(if self.cgb_rom { Model::Cgb } else { Model::Dmg })
+    }
+
+    // Callers reset afterwards; switching hardware mid-run is not supported
+    pub fn set_model_override(&mut self, model: Option<Model>) {
+        self.model_override = model;
+        self.is_gbc = self.model() == Model::Cgb;
+    }
+
+    fn is_mbc2(&self) -> bool {
+        matches!(self.mbc_type, 0x05 | 0x06)
+    }
+
+    fn is_mbc3(&self) -> bool {
+        matches!(self.mbc_type, 0x0f..=0x13)
+    }
+
+    fn is_mbc5(&self) -> bool {
+        matches!(self.mbc_type, 0x19..=0x1e)
+    }
+
+    // Header cartridge types with a battery keeping RAM (and any clock) alive
+    fn has_battery(&self) -> bool {
+        matches!(self.mbc_type, 0x03 | 0x06 | 0x09 | 0x0d | 0x0f | 0x10 | 0x13 | 0x1b | 0x1e | 0x22 | 0xff)
+    }
+
+    fn has_rtc(&self) -> bool {
+        matches!(self.mbc_type, 0x0f | 0x10)
+    }
+
+    // Battery-backed save data: the external RAM as-is, followed by the
+    // clock snapshot on MBC3+TIMER carts. Empty without a battery
+    pub fn export_sram(&self) -> Vec<u8> {
+        if !self.has_battery() {
+            return Vec::new();
+        }
+        let mut out = self.eram.clone();
+        if self.has_rtc() {
+            out.extend_from_slice(&self.rtc.snapshot());
+        }
+        out
+    }
+
+    // Accepts what export_sram produced for the same cartridge type. A blob
+    // shorter than the RAM is rejected; a missing or unknown clock
+    // snapshot leaves the clock alone
+    pub fn import_sram(&mut self, data: &[u8]) -> bool {
+        if !self.has_battery() || data.len() < self.eram.len() {
+            return false;
+        }
+        let (ram, rest) = data.split_at(self.eram.len());
+        self.eram.copy_from_slice(ram);
+        if self.has_rtc() && rest.len() >= crate::rtc::SNAPSHOT_LEN {
+            self.rtc.restore(rest);
+        }
+        true
+    }
+
+    pub fn step_rtc(&mut self, cycles: u32) {
+        if self.is_mbc3() {
+            self.rtc.step(cycles);
+        }
+    }
+
+    pub fn set_rtc_now(&mut self, unix_secs: u64) {
+        self.rtc.set_now(unix_secs);
+    }
+
+    // Cartridge register writes (0x0000-0x7FFF)
+    fn write_mbc(&mut self, addr: usize, val: u8) {
+        if self.is_mbc2() {
+            // MBC2 only decodes 0x0000-0x3FFF; address bit 8 picks RAM
+            // enable (clear) or the 4-bit ROM bank (set)
+            if addr < 0x4000 {
+                if addr & 0x100 == 0 {
+                    self.ram_enabled = (val & 0x0f) == 0x0a;
+                } else {
+                    self.rom_bank = ((val & 0x0f) as usize).max(1);
+                }
+            }
+            return;
+        }
+        if self.is_mbc3() {
+            match addr {
+                0x0000..=0x1fff => self.ram_enabled = (val & 0x0f) == 0x0a,
+                0x2000..=0x3fff => self.rom_bank = ((val & 0x7f) as usize).max(1),
+                0x4000..=0x5fff => match val {
+                    // Carts without the clock chip have no registers to map
+                    0x08..=0x0c if self.has_rtc() => self.rtc_select = Some(val),
+                    _ => {
+                        self.rtc_select = None;
+                        self.ram_bank = (val & 0x03) as usize;
+                    }
+                },
+                _ if self.has_rtc() => self.rtc.write_latch(val),
+                _ => {}
+            }
+            return;
+        }
+        if self.is_mbc5() {
+            // 9-bit ROM bank split over two registers; bank 0 is selectable
+            match addr {
+                0x0000..=0x1fff => self.ram_enabled = (val & 0x0f) == 0x0a,
+                0x2000..=0x2fff => self.rom_bank = (self.rom_bank & 0x100) | val as usize,
+                0x3000..=0x3fff => self.rom_bank = (self.rom_bank & 0xff) | ((val as usize & 0x01) << 8),
+                0x4000..=0x5fff => {
+                    // Rumble carts (0x1C-0x1E) drive the motor with bit 3
+                    let mask = if self.mbc_type >= 0x1c { 0x07 } else { 0x0f };
+                    self.ram_bank = (val & mask) as usize;
+                }
+                _ => {}
+            }
+            return;
+        }
+        match addr {
+            0x0000..=0x1fff => self.ram_enabled = (val & 0x0f) == 0x0a,
+            0x2000..=0x3fff => {
+                let mut bank = (val & 0x1f) as usize;
+                if bank == 0 { bank = 1; }
+                self.rom_bank = (self.rom_bank & 0x60) | bank;
+            }
+            0x4000..=0x5fff => {
+                self.bank_upper = (val & 0x03) as usize;
+                self.update_mbc1_banks();
+            }
+            _ => {
+                self.banking_mode = val & 0x01;
+                self.update_mbc1_banks();
+            }
+        }
+    }
+
+    // MBC1: the upper register always supplies bits 5-6 of the 0x4000 bank;
+    // mode 1 also applies it to the 0x0000 area and selects the RAM bank
+    fn update_mbc1_banks(&mut self) {
+        self.rom_bank = (self.rom_bank & 0x1f) | (self.bank_upper << 5);
+        if self.banking_mode == 0 {
+            self.rom0_bank = 0;
+            self.ram_bank = 0;
+        } else {
+            self.rom0_bank = self.bank_upper << 5;
+            self.ram_bank = self.bank_upper;
+        }
+    }
+
     pub fn read_byte(&self, addr: u16) -> u8 {
         let addr = addr as usize;
         match addr {
-            0x0000..=0x3fff => self.rom.get(addr).copied().unwrap_or(0),
+            // Boot ROM overlay until 0xFF50 is written; the CGB image leaves
+            // the cartridge header at 0x0100-0x01FF visible
+            0x0000..=0x00ff | 0x0200..=0x08ff if !self.boot_rom_disabled && addr < self.boot_rom.len() => {
+                self.boot_rom[addr]
+            }
+            // Bank numbers past the end of the ROM alias, as the MBC only
+            // drives as many address lines as the chip has
+            0x0000..=0x3fff => {
+                let offset = (self.rom0_bank & self.rom_bank_mask) * 0x4000 + addr;
+                self.rom.get(offset).copied().unwrap_or(0)
+            }
             0x4000..=0x7fff => {
-                let offset = self.rom_bank * 0x4000 + (addr - 0x4000);
+                let offset = (self.rom_bank & self.rom_bank_mask) * 0x4000 + (addr - 0x4000);
                 self.rom.get(offset).copied().unwrap_or(0)
             }
             0x8000..=0x9fff => {
@@ -147,28 +548,20 @@ impl MMU {
                     0
                 }
             }
-            0xa000..=0xbfff => {
-                if self.ram_enabled {
-                    let offset = self.ram_bank * 0x2000 + (addr - 0xa000);
-                    self.eram.get(offset).copied().unwrap_or(0)
-                } else {
-                    0xff
-                }
-            }
-            0xc000..=0xcfff => {
-                let offset = addr - 0xc000;
-                self.wram.get(offset).copied().unwrap_or(0)
+            // MBC2 nibble RAM repeats through the window; the upper four bits
+            // are not connected and read as 1s
+            0xa000..=0xbfff if self.ram_enabled && self.is_mbc2() => {
+                0xf0 | self.eram.get(addr & 0x1ff).copied().unwrap_or(0x0f)
             }
-            0xd000..=0xdfff => {
-                let offset = addr - 0xd000;
-                if self.is_gbc && self.wram_bank < 8 {
-                    self.wram_banks[self.wram_bank].get(offset).copied().unwrap_or(0)
-                } else if offset < self.wram.len() {
-                    self.wram[offset]
-                } else {
-                    0
+            0xa000..=0xbfff if self.ram_enabled => {
+                if let Some(reg) = self.rtc_select {
+                    return self.rtc.read(reg);
                 }
+                let offset = self.ram_bank * 0x2000 + (addr - 0xa000);
+                self.eram.get(offset).copied().unwrap_or(0)
             }
+            0xa000..=0xbfff => 0xff,
+            0xc000..=0xdfff => self.wram[self.wram_offset(addr)],
             0xe000..=0xfdff => self.read_byte((addr - 0x2000) as u16),
             0xfe00..=0xfe9f => {
                 let offset = addr - 0xfe00;
@@ -196,20 +589,7 @@ impl MMU {
     pub fn write_byte(&mut self, addr: u16, val: u8) {
         let addr = addr as usize;
         match addr {
-            0x0000..=0x1fff => self.ram_enabled = (val & 0x0f) == 0x0a,
-            0x2000..=0x3fff => {
-                let mut bank = (val & 0x1f) as usize;
-                if bank == 0 { bank = 1; }
-                self.rom_bank = (self.rom_bank & 0x60) | bank;
-            }
-            0x4000..=0x5fff => {
-                if self.banking_mode == 0 {
-                    self.rom_bank = (self.rom_bank & 0x1f) | (((val & 0x03) as usize) << 5);
-                } else {
-                    self.ram_bank = (val & 0x03) as usize;
-                }
-            }
-            0x6000..=0x7fff => self.banking_mode = val & 0x01,
+            0x0000..=0x7fff => self.write_mbc(addr, val),
             0x8000..=0x9fff => {
                 let offset = addr - 0x8000;
                 if self.is_gbc && self.vram_bank < 2 && offset < 0x2000 {
@@ -218,28 +598,26 @@ impl MMU {
                     self.vram[offset] = val;
                 }
             }
-            0xa000..=0xbfff => {
-                if self.ram_enabled {
-                    let offset = self.ram_bank * 0x2000 + (addr - 0xa000);
-                    if offset < self.eram.len() {
-                        self.eram[offset] = val;
-                    }
+            0xa000..=0xbfff if self.ram_enabled && self.is_mbc2() => {
+                if let Some(nibble) = self.eram.get_mut(addr & 0x1ff) {
+                    *nibble = val & 0x0f;
                 }
             }
-            0xc000..=0xcfff => {
-                let offset = addr - 0xc000;
-                if offset < self.wram.len() {
-                    self.wram[offset] = val;
+            0xa000..=0xbfff if self.ram_enabled => {
+                if let Some(reg) = self.rtc_select {
+                    self.rtc.write(reg, val);
+                    return;
                 }
-            }
-            0xd000..=0xdfff => {
-                let offset = addr - 0xd000;
-                if self.is_gbc && self.wram_bank < 8 && offset < 0x1000 {
-                    self.wram_banks[self.wram_bank][offset] = val;
-                } else if offset < self.wram.len() {
-                    self.wram[offset] = val;
+                let offset = self.ram_bank * 0x2000 + (addr - 0xa000);
+                if offset < self.eram.len() {
+                    self.eram[offset] = val;
                 }
             }
+            0xa000..=0xbfff => {}
+            0xc000..=0xdfff => {
+                let offset = self.wram_offset(addr);
+                self.wram[offset] = val;
+            }
             0xe000..=0xfdff => self.write_byte((addr - 0x2000) as u16, val),
             0xfe00..=0xfe9f => {
                 let offset = addr - 0xfe00;
@@ -262,22 +640,34 @@ impl MMU {
 
     fn read_io(&self, addr: usize) -> u8 {
         let offset = addr - 0xff00;
+        if (0x10..=0x3f).contains(&offset) {
+            return self.apu.read_register(offset);
+        }
+        if offset == 0x04 {
+            return self.timer.div();
+        }
         if offset == 0x00 {
             // JOYP read is dynamic based on select lines and current button state
             // Bits 6-7 read as 1; bits 4-5 are select lines; low nibble depends on selection
-            let joyp = self.io[0x00];
-            let mut value = 0xC0 | (joyp & 0x30) | 0x0F; // default: all released
-            if joyp & 0x10 == 0 {
-                // D-pad: Up/Down/Left/Right are bits 2/3/1/0 of upper nibble
-                value &= !((self.joypad_buttons >> 4) & 0x0F);
-            }
-            if joyp & 0x20 == 0 {
-                // Buttons: A/B/Select/Start are bits 0/1/2/3 of lower nibble
-                value &= !(self.joypad_buttons & 0x0F);
-            }
-            return value;
+            return 0xC0 | (self.io[0x00] & 0x30) | self.joypad_lines();
+        }
+        // IF: only the five interrupt bits exist, the rest read as 1 (IE at
+        // 0xFFFF, by contrast, keeps all eight bits)
+        if offset == 0x0f { return self.io[offset] | 0xe0; }
+        if offset == 0x50 { return 0xfe | self.boot_rom_disabled as u8; }
+        // Undocumented CGB registers: 0xFF72-0xFF74 plain storage, 0xFF75
+        // bits 4-6 only, 0xFF76/0xFF77 the channels' digital outputs
+        if (0x72..=0x77).contains(&offset) {
+            return match offset {
+                _ if !self.is_gbc => 0xff,
+                0x75 => self.io[offset] | 0x8f,
+                0x76 => self.apu.pcm12(),
+                0x77 => self.apu.pcm34(),
+                _ => self.io[offset],
+            };
         }
         if self.is_gbc {
+            if offset == 0x4d { return 0x7e | (self.double_speed as u8) << 7 | self.speed_switch_armed as u8; }
             if offset == 0x4f { return self.vram_bank as u8 | 0xfe; }
             if offset == 0x70 { return self.wram_bank as u8 | 0xf8; }
             if offset == 0x68 { return self.bgpi; }
@@ -290,31 +680,70 @@ impl MMU {
             if offset == 0x53 { return ((self.hdma_dst >> 8) as u8) & 0x1F; }
             if offset == 0x54 { return (self.hdma_dst & 0x00ff) as u8 & 0xF0; }
             if offset == 0x55 {
-                // Bit7 indicates active when set; low 7 bits = remaining blocks-1
+                // Bit7 clear while an HBlank transfer runs; low 7 bits = remaining
+                // blocks-1. Idle reads 0xFF, or bit7 plus the blocks left after an abort
                 if self.hdma_active {
-                    let blocks = (self.hdma_remaining + 15) / 16;
-                    return 0x80 | (((blocks.saturating_sub(1)) as u8) & 0x7f);
+                    let blocks = self.hdma_remaining.div_ceil(16);
+                    return ((blocks.saturating_sub(1)) as u8) & 0x7f;
                 } else {
-                    return 0xff;
+                    return self.io[0x55];
                 }
             }
         }
         self.io[offset]
     }
 
+    // Index into `wram` for 0xC000-0xDFFF
+    fn wram_offset(&self, addr: usize) -> usize {
+        let bank = match addr {
+            0xc000..=0xcfff => 0,
+            _ if self.is_gbc => self.wram_bank,
+            _ => 1,
+        };
+        bank * WRAM_BANK_SIZE + (addr & 0x0fff)
+    }
+
     fn write_io(&mut self, addr: usize, val: u8) {
         let offset = addr - 0xff00;
+        if (0x10..=0x3f).contains(&offset) {
+            self.apu.write_register(offset, val);
+            return;
+        }
         if offset == 0x00 {
             // JOYP: only bits 4-5 (select lines) are writable
             let prev = self.io[0x00];
             self.io[0x00] = (prev & 0xCF) | (val & 0x30);
+            // Selecting a group with a button already held pulls a line low
+            self.update_joypad_irq();
+            return;
+        }
+        if (0x04..=0x07).contains(&offset) {
+            self.timer.write(offset, val, &mut self.io);
+            self.clock_frame_sequencer();
             return;
         }
-        if offset == 0x04 { self.io[offset] = 0; return; }
-        if offset == 0x41 { self.io[offset] = (self.io[offset] & 0x07) | (val & 0xf8); return; }
+        if offset == 0x02 { self.serial.write_control(val, self.is_gbc, &self.io); }
+        // STAT: mode and LY=LYC flag are read-only, bit 7 is unused and reads as 1
+        if offset == 0x41 { self.io[offset] = 0x80 | (val & 0x78) | (self.io[offset] & 0x07); return; }
+        if offset == 0x40 && self.warn_on_lcd_disable && self.io[0x40] & 0x80 != 0 && val & 0x80 == 0 && self.io[0x44] < 144 {
+            self.lcd_disable_warning = Some(self.io[0x44]);
+        }
         if offset == 0x44 { return; }
-        if offset == 0x46 { self.dma_transfer(val); self.io[offset] = val; return; }
+        if offset == 0x46 { self.start_oam_dma(val); self.io[offset] = val; return; }
+        // Boot ROM disable is write-once: any set bit 0 latches until reset
+        if offset == 0x50 { self.boot_rom_disabled |= val & 0x01 != 0; return; }
+        if (0x72..=0x77).contains(&offset) {
+            if self.is_gbc {
+                match offset {
+                    0x72..=0x74 => self.io[offset] = val,
+                    0x75 => self.io[offset] = val & 0x70,
+                    _ => {}
+                }
+            }
+            return;
+        }
         if self.is_gbc {
+            if offset == 0x4d { self.speed_switch_armed = val & 0x01 != 0; return; }
             if offset == 0x4f { self.vram_bank = (val & 0x01) as usize; return; }
             if offset == 0x70 {
                 let bank = (val & 0x07) as usize;
@@ -342,13 +771,29 @@ impl MMU {
             }
             if offset == 0x55 {
                 // Length is (val & 0x7F) + 1 blocks of 16 bytes
-                let blocks = ((val as u16 & 0x7f) + 1) as u16;
+                let blocks = (val as u16 & 0x7f) + 1;
                 let length = blocks * 16;
-                if (val & 0x80) == 0 {
-                    // General DMA: copy all at once
+                if (val & 0x80) == 0 && self.hdma_active {
+                    // Bit7 clear during an HBlank transfer stops it; the
+                    // register keeps the blocks that were left
+                    let left = self.hdma_remaining.div_ceil(16);
+                    self.hdma_active = false;
+                    self.hdma_hblank_mode = false;
+                    self.io[0x55] = 0x80 | ((left.saturating_sub(1) as u8) & 0x7f);
+                } else if (val & 0x80) == 0 {
+                    // General DMA: the copy happens at once, but the CPU is
+                    // halted for the length of the transfer
                     self.hdma_active = false;
                     self.do_hdma_copy(length);
                     self.io[0x55] = 0xff; // not active
+                    if self.dma_timing == DmaTiming::Accurate {
+                        let per_block = if self.double_speed { HDMA_STALL_PER_BLOCK_DOUBLE_SPEED } else { HDMA_STALL_PER_BLOCK };
+                        self.hdma_stall += blocks as u32 * per_block;
+                    }
+                } else if self.dma_timing == DmaTiming::Instant {
+                    self.hdma_active = false;
+                    self.do_hdma_copy(length);
+                    self.io[0x55] = 0xff;
                 } else {
                     // HBlank DMA: start / update
                     self.hdma_active = true;
@@ -363,21 +808,241 @@ impl MMU {
         self.io[offset] = val;
     }
 
-    fn dma_transfer(&mut self, val: u8) {
-        let src = (val as u16) << 8;
-        for i in 0..0xa0 {
-            self.oam[i] = self.read_byte(src + i as u16);
+    fn start_oam_dma(&mut self, val: u8) {
+        // Writing 0xFF46 (re)starts the transfer from the beginning
+        self.oam_dma_active = true;
+        self.oam_dma_src = (val as u16) << 8;
+        self.oam_dma_offset = 0;
+        self.oam_dma_cycles = 0;
+        if self.dma_timing == DmaTiming::Instant {
+            self.step_oam_dma(OAM_DMA_LEN as u32 * OAM_DMA_CYCLES_PER_BYTE);
+        }
+    }
+
+    pub fn set_dma_timing(&mut self, timing: DmaTiming) {
+        self.dma_timing = timing;
+    }
+
+    pub fn dma_timing(&self) -> DmaTiming {
+        self.dma_timing
+    }
+
+    // Advance an in-flight OAM DMA; called from the main loop with CPU cycles
+    pub fn step_oam_dma(&mut self, cycles: u32) {
+        if !self.oam_dma_active {
+            return;
+        }
+        self.oam_dma_cycles += cycles;
+        while self.oam_dma_cycles >= OAM_DMA_CYCLES_PER_BYTE && self.oam_dma_active {
+            self.oam_dma_cycles -= OAM_DMA_CYCLES_PER_BYTE;
+            let i = self.oam_dma_offset;
+            self.oam[i as usize] = self.read_byte(self.oam_dma_src + i as u16);
+            self.oam_dma_offset += 1;
+            if self.oam_dma_offset >= OAM_DMA_LEN {
+                self.oam_dma_active = false;
+                self.oam_dma_cycles = 0;
+            }
+        }
+    }
+
+    pub fn set_warn_on_lcd_disable(&mut self, enabled: bool) {
+        self.warn_on_lcd_disable = enabled;
+        self.lcd_disable_warning = None;
+    }
+
+    pub fn lcd_disable_warning(&self) -> Option<u8> {
+        self.lcd_disable_warning
+    }
+
+    pub fn set_strict_oam_dma(&mut self, enabled: bool) {
+        self.strict_oam_dma = enabled;
+    }
+
+    // OAM is owned by the DMA unit while a transfer runs; with strict OAM
+    // DMA the CPU only reaches HRAM at all
+    fn cpu_locked_out(&self, addr: u16) -> bool {
+        self.oam_dma_active
+            && ((0xfe00..=0xfe9f).contains(&addr) || self.strict_oam_dma && !(0xff80..=0xfffe).contains(&addr))
+    }
+
+    // CPU-side accesses (fetches, loads, stores, stack). Locked-out reads
+    // return 0xFF and writes are dropped, and only these are checked for
+    // break-on-invalid-access; the PPU, DMA engines and debugger keep using
+    // read_byte/write_byte directly
+    pub fn cpu_read(&self, addr: u16) -> u8 {
+        if self.cpu_locked_out(addr) {
+            return 0xff;
+        }
+        if self.is_invalid_access(addr) {
+            self.flag_invalid_access(addr as usize, false);
+        }
+        self.read_byte(addr)
+    }
+
+    pub fn cpu_write(&mut self, addr: u16, val: u8) {
+        if self.cpu_locked_out(addr) {
+            return;
+        }
+        if self.is_invalid_access(addr) {
+            self.flag_invalid_access(addr as usize, true);
+        }
+        self.write_byte(addr, val);
+    }
+
+    // Prohibited regions: disabled cartridge RAM, the 0xFEA0-0xFEFF hole
+    // and unmapped IO registers
+    fn is_invalid_access(&self, addr: u16) -> bool {
+        match addr {
+            0xa000..=0xbfff => !self.ram_enabled,
+            0xfea0..=0xfeff => true,
+            0xff00..=0xff7f => self.is_reserved_io(addr as usize - 0xff00),
+            _ => false,
+        }
+    }
+
+    pub fn step_apu(&mut self, cycles: u32) {
+        self.apu.step(cycles);
+    }
+
+    pub fn apu(&self) -> &APU {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut APU {
+        &mut self.apu
+    }
+
+    pub fn oam_dma_state(&self) -> Option<OamDmaState> {
+        if !self.oam_dma_active {
+            return None;
+        }
+        Some(OamDmaState {
+            source: self.oam_dma_src,
+            offset: self.oam_dma_offset,
+            cycles: self.oam_dma_cycles,
+        })
+    }
+
+    pub fn serial_output(&self) -> &[u8] {
+        self.serial.output()
+    }
+
+    pub fn clear_serial_output(&mut self) {
+        self.serial.clear_output();
+    }
+
+    pub fn set_serial_byte_in(&mut self, val: u8) {
+        self.serial.set_byte_in(val);
+    }
+
+    pub fn set_serial_linked(&mut self, linked: bool) {
+        self.serial.set_linked(linked);
+    }
+
+    pub fn serial_link_request(&self) -> Option<(bool, u8)> {
+        self.serial.link_request(&self.io)
+    }
+
+    pub fn complete_serial_link(&mut self, byte_in: u8) {
+        self.serial.complete_link(byte_in, &mut self.io);
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    // STOP with KEY1 bit 0 set toggles the CPU speed; true if it did
+    pub fn try_speed_switch(&mut self) -> bool {
+        if !self.is_gbc || !self.speed_switch_armed {
+            return false;
+        }
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        self.timer.set_double_speed(self.double_speed);
+        true
+    }
+
+    pub fn step_serial(&mut self, cycles: u32) {
+        self.serial.step(cycles, &mut self.io);
+    }
+
+    pub fn step_timer(&mut self, cycles: u32) {
+        self.timer.step(cycles, &mut self.io);
+        self.clock_frame_sequencer();
+    }
+
+    // Pass DIV falling edges on to the APU frame sequencer
+    fn clock_frame_sequencer(&mut self) {
+        for _ in 0..self.timer.take_frame_seq_clocks() {
+            self.apu.clock_frame_sequencer();
+        }
+    }
+
+    // Test/debug helper: copy a full OAM image in one go, bypassing the
+    // DMA and PPU-mode access restrictions that write_byte applies
+    pub fn set_oam(&mut self, oam: &[u8; 0xa0]) {
+        self.oam.copy_from_slice(oam);
+    }
+
+    // Take memory, banking and IO state from a save state of this ROM.
+    // False (nothing changed) when its memory sizes do not fit the cartridge
+    pub fn load_state(&mut self, mut saved: MMU) -> bool {
+        let fits = saved.vram.len() == self.vram.len()
+            && saved.eram.len() == self.eram.len()
+            && saved.wram.len() == self.wram.len()
+            && saved.vram_banks.iter().all(|bank| bank.len() == 0x2000)
+            && saved.vram_bank < 2
+            && saved.wram_bank < 8;
+        if !fits {
+            return false;
         }
+        saved.rom = std::mem::take(&mut self.rom);
+        saved.boot_rom = std::mem::take(&mut self.boot_rom);
+        saved.boot_rom_disabled |= saved.boot_rom.is_empty();
+        saved.model_override = self.model_override;
+        saved.strict_oam_dma = self.strict_oam_dma;
+        saved.dma_timing = self.dma_timing;
+        saved.warn_on_lcd_disable = self.warn_on_lcd_disable;
+        saved.break_on_invalid_access = self.break_on_invalid_access;
+        // The serial port and APU keep their own host-side parts
+        self.serial.load_state(std::mem::replace(&mut saved.serial, Serial::new()));
+        self.apu.load_state(std::mem::replace(&mut saved.apu, APU::new()));
+        std::mem::swap(&mut saved.serial, &mut self.serial);
+        std::mem::swap(&mut saved.apu, &mut self.apu);
+        *self = saved;
+        true
     }
 
+    // Restore OAM contents and any in-flight DMA from a save state
+    pub fn restore_oam(&mut self, oam: &[u8], dma: Option<OamDmaState>) {
+        let n = oam.len().min(self.oam.len());
+        self.oam[..n].copy_from_slice(&oam[..n]);
+        match dma {
+            Some(st) if st.offset < OAM_DMA_LEN => {
+                self.oam_dma_active = true;
+                self.oam_dma_src = st.source;
+                self.oam_dma_offset = st.offset;
+                self.oam_dma_cycles = st.cycles % OAM_DMA_CYCLES_PER_BYTE;
+            }
+            _ => {
+                self.oam_dma_active = false;
+                self.oam_dma_offset = 0;
+                self.oam_dma_cycles = 0;
+            }
+        }
+    }
+
+    #[allow(dead_code)]
     pub fn get_vram(&self) -> &[u8] {
         if self.is_gbc { &self.vram_banks[self.vram_bank] } else { &self.vram }
     }
 
+    #[allow(dead_code)]
     pub fn get_vram_bank_ref(&self, bank: usize) -> &[u8] {
         if self.is_gbc { &self.vram_banks[bank & 1] } else { &self.vram }
     }
 
+    // PPU-side VRAM read; the bank is ignored on DMG, which only has one
     pub fn read_vram_bank_byte(&self, addr: u16, bank: usize) -> u8 {
         let offset = addr as usize - 0x8000;
         if self.is_gbc {
@@ -416,15 +1081,140 @@ impl MMU {
     pub fn get_io_mut(&mut self) -> &mut [u8] { &mut self.io }
     pub fn is_gbc(&self) -> bool { self.is_gbc }
 
+    pub fn boot_rom_disabled(&self) -> bool { self.boot_rom_disabled }
+
+    // Sum of every ROM byte except the stored checksum itself (0x014E-0x014F),
+    // the header's global checksum algorithm
+    pub fn global_checksum(&self) -> u16 {
+        self.rom
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != 0x014e && i != 0x014f)
+            .fold(0u16, |sum, (_, &b)| sum.wrapping_add(b as u16))
+    }
+
+    // Big-endian value stored at 0x014E-0x014F
+    pub fn header_global_checksum(&self) -> u16 {
+        u16::from_be_bytes([self.rom[0x014e], self.rom[0x014f]])
+    }
+
+    // Cartridge type (header 0x0147) as listed in the Pan Docs table
+    pub fn mbc_name(&self) -> String {
+        let name = match self.mbc_type {
+            0x00 => "ROM ONLY",
+            0x01 => "MBC1",
+            0x02 => "MBC1+RAM",
+            0x03 => "MBC1+RAM+BATTERY",
+            0x05 => "MBC2",
+            0x06 => "MBC2+BATTERY",
+            0x08 => "ROM+RAM",
+            0x09 => "ROM+RAM+BATTERY",
+            0x0b => "MMM01",
+            0x0c => "MMM01+RAM",
+            0x0d => "MMM01+RAM+BATTERY",
+            0x0f => "MBC3+TIMER+BATTERY",
+            0x10 => "MBC3+TIMER+RAM+BATTERY",
+            0x11 => "MBC3",
+            0x12 => "MBC3+RAM",
+            0x13 => "MBC3+RAM+BATTERY",
+            0x19 => "MBC5",
+            0x1a => "MBC5+RAM",
+            0x1b => "MBC5+RAM+BATTERY",
+            0x1c => "MBC5+RUMBLE",
+            0x1d => "MBC5+RUMBLE+RAM",
+            0x1e => "MBC5+RUMBLE+RAM+BATTERY",
+            0x20 => "MBC6",
+            0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+            0xfc => "POCKET CAMERA",
+            0xfd => "BANDAI TAMA5",
+            0xfe => "HuC3",
+            0xff => "HuC1+RAM+BATTERY",
+            other => return format!("UNKNOWN (0x{:02X})", other),
+        };
+        name.to_string()
+    }
+
+    // IO offsets with no register behind them on the current model
+    fn is_reserved_io(&self, offset: usize) -> bool {
+        match offset {
+            0x03 | 0x08..=0x0e | 0x15 | 0x1f | 0x27..=0x2f => true,
+            0x50 => false,
+            0x4c..=0x7f => !(self.is_gbc
+                && matches!(offset, 0x4d | 0x4f | 0x51..=0x56 | 0x68..=0x6c | 0x70 | 0x72..=0x77)),
+            _ => false,
+        }
+    }
+
+    fn flag_invalid_access(&self, addr: usize, write: bool) {
+        if self.break_on_invalid_access && self.invalid_access.get().is_none() {
+            self.invalid_access.set(Some(InvalidAccess { addr: addr as u16, write, pc: 0 }));
+        }
+    }
+
+    pub fn set_break_on_invalid_access(&mut self, enabled: bool) {
+        self.break_on_invalid_access = enabled;
+        self.invalid_access.set(None);
+    }
+
+    pub fn take_invalid_access(&self) -> Option<InvalidAccess> {
+        self.invalid_access.take()
+    }
+
+    // JOYP low nibble (active-low) as seen through the current select lines
+    fn joypad_lines(&self) -> u8 {
+        let joyp = self.io[0x00];
+        let held = self.input.pressed();
+        let mut lines = 0x0F; // default: all released
+        if joyp & 0x10 == 0 {
+            // D-pad: Up/Down/Left/Right are bits 2/3/1/0 of upper nibble
+            lines &= !(held >> 4);
+        }
+        if joyp & 0x20 == 0 {
+            // Buttons: A/B/Select/Start are bits 0/1/2/3 of lower nibble
+            lines &= !held;
+        }
+        lines & 0x0F
+    }
+
+    // The joypad interrupt fires when a visible line goes from high to low,
+    // whether from a press or from a select change exposing a held button
+    fn update_joypad_irq(&mut self) {
+        let lines = self.joypad_lines();
+        if self.joypad_prev_lines & !lines != 0 {
+            self.io[0x0F] |= 0x10;
+        }
+        self.joypad_prev_lines = lines;
+    }
+
     // Joypad updates from frontend
     pub fn joypad_press(&mut self, bit: u8) {
-        self.joypad_buttons &= !(1 << bit);
-        // Request joypad interrupt when any button down (simplified)
-        self.io[0x0F] |= 0x10;
+        self.input.press_button(bit);
+        self.update_joypad_irq();
     }
 
     pub fn joypad_release(&mut self, bit: u8) {
-        self.joypad_buttons |= 1 << bit;
+        self.input.release_button(bit);
+        self.update_joypad_irq();
+    }
+
+    // Apply every button at once; `pressed` is active-high in press_button
+    // bit order
+    pub fn joypad_set_state(&mut self, pressed: u8) {
+        self.input.set_state(pressed);
+        self.update_joypad_irq();
+    }
+
+    // Held buttons, bit set = pressed
+    pub fn joypad_pressed(&self) -> u8 {
+        self.input.pressed()
+    }
+
+    // CPU T-cycles spent halted by general-purpose HDMA since the last call:
+    // 32 per block, 64 in double speed. Like every CPU cycle count, run_frame
+    // halves it for the PPU and APU in double speed, so a block is 32 dots
+    // either way
+    pub fn take_hdma_stall(&mut self) -> u32 {
+        std::mem::take(&mut self.hdma_stall)
     }
 
     // Perform one 16-byte HDMA chunk if active and in HBlank
@@ -439,7 +1229,7 @@ impl MMU {
             self.hdma_hblank_mode = false;
             self.io[0x55] = 0xff; // done
         } else {
-            let blocks = (self.hdma_remaining + 15) / 16;
+            let blocks = self.hdma_remaining.div_ceil(16);
             self.io[0x55] = 0x80 | (((blocks - 1) as u8) & 0x7f);
         }
     }
@@ -462,3 +1252,532 @@ impl MMU {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // CPU cycles until a channel 1 length of 4 runs out, counted from a DIV
+    // reset with the frame sequencer at step 0
+    fn length_expiry_cycles(double_speed: bool) -> u32 {
+        let mut mmu = MMU::new();
+        if double_speed {
+            mmu.is_gbc = true;
+            mmu.speed_switch_armed = true;
+            assert!(mmu.try_speed_switch());
+        }
+        mmu.write_byte(0xff26, 0x00);
+        mmu.write_byte(0xff04, 0x00);
+        mmu.write_byte(0xff26, 0x80);
+        mmu.write_byte(0xff12, 0xf0);
+        mmu.write_byte(0xff11, 0x3c);
+        mmu.write_byte(0xff14, 0xc0);
+        let mut cycles = 0;
+        while mmu.read_byte(0xff26) & 0x01 != 0 {
+            mmu.step_timer(4);
+            cycles += 4;
+            assert!(cycles < 1 << 20, "length counter never expired");
+        }
+        cycles
+    }
+
+    #[test]
+    fn frame_sequencer_keeps_wall_clock_rate_in_double_speed() {
+        // Length clocks on steps 0, 2, 4 and 6: the fourth is the 7th tick
+        let normal = length_expiry_cycles(false);
+        assert_eq!(normal, 7 * 8192);
+        // Twice the CPU cycles is the same 256 Hz in wall-clock time
+        assert_eq!(length_expiry_cycles(true), 2 * normal);
+    }
+
+    #[test]
+    fn div_write_clocks_frame_sequencer_on_falling_edge() {
+        let mut mmu = MMU::new();
+        mmu.write_byte(0xff26, 0x00);
+        mmu.write_byte(0xff04, 0x00);
+        mmu.write_byte(0xff26, 0x80);
+        // Counter bit 12 set, not yet fallen
+        mmu.step_timer(4096);
+        assert_eq!(mmu.apu.frame_sequencer_step(), 0);
+        mmu.write_byte(0xff04, 0x00);
+        assert_eq!(mmu.apu.frame_sequencer_step(), 1);
+    }
+
+    #[test]
+    fn reset_matches_documented_dmg_boot_values() {
+        // Pan Docs "Power Up Sequence", DMG column, as read back by the CPU.
+        // OBP0/OBP1 are left uninitialized by the boot ROM
+        let expected: &[(u16, u8)] = &[
+            (0xff00, 0xcf), (0xff01, 0x00), (0xff02, 0x7e), (0xff04, 0xab), (0xff05, 0x00),
+            (0xff06, 0x00), (0xff07, 0xf8), (0xff0f, 0xe1), (0xff10, 0x80), (0xff11, 0xbf),
+            (0xff12, 0xf3), (0xff13, 0xff), (0xff14, 0xbf), (0xff16, 0x3f), (0xff17, 0x00),
+            (0xff18, 0xff), (0xff19, 0xbf), (0xff1a, 0x7f), (0xff1b, 0xff), (0xff1c, 0x9f),
+            (0xff1d, 0xff), (0xff1e, 0xbf), (0xff20, 0xff), (0xff21, 0x00), (0xff22, 0x00),
+            (0xff23, 0xbf), (0xff24, 0x77), (0xff25, 0xf3), (0xff26, 0xf1), (0xff40, 0x91),
+            (0xff41, 0x85), (0xff42, 0x00), (0xff43, 0x00), (0xff44, 0x00), (0xff45, 0x00),
+            (0xff46, 0xff), (0xff47, 0xfc), (0xff4a, 0x00), (0xff4b, 0x00), (0xffff, 0x00),
+        ];
+        let mut mmu = MMU::new();
+        mmu.write_byte(0xff40, 0x00);
+        mmu.write_byte(0xff26, 0x00);
+        mmu.reset();
+        for &(addr, val) in expected {
+            assert_eq!(mmu.read_byte(addr), val, "{:04x}", addr);
+        }
+    }
+
+    #[test]
+    fn oam_reads_ff_during_dma() {
+        let mut mmu = MMU::new();
+        for i in 0..0xa0u16 {
+            mmu.write_byte(0xc000 + i, i as u8 ^ 0x3c);
+        }
+        mmu.cpu_write(0xff46, 0xc0);
+        mmu.step_oam_dma(40 * OAM_DMA_CYCLES_PER_BYTE);
+        assert_eq!(mmu.cpu_read(0xfe00), 0xff);
+        assert_eq!(mmu.cpu_read(0xfe9f), 0xff);
+        // CPU writes are dropped as well; other components see the real OAM
+        mmu.cpu_write(0xfe00, 0x99);
+        assert_eq!(mmu.read_byte(0xfe00), 0x3c);
+        // HRAM stays reachable
+        mmu.cpu_write(0xff80, 0x12);
+        assert_eq!(mmu.cpu_read(0xff80), 0x12);
+
+        mmu.step_oam_dma(120 * OAM_DMA_CYCLES_PER_BYTE);
+        assert!(mmu.oam_dma_state().is_none());
+        for i in 0..0xa0u16 {
+            assert_eq!(mmu.cpu_read(0xfe00 + i), i as u8 ^ 0x3c);
+        }
+    }
+
+    #[test]
+    fn stat_write_only_changes_enable_bits() {
+        let mut mmu = MMU::new();
+        // Mode 3 with LY=LYC, as the PPU leaves it
+        mmu.get_io_mut()[0x41] = 0x07;
+        mmu.cpu_write(0xff41, 0x78);
+        assert_eq!(mmu.cpu_read(0xff41), 0xff);
+        mmu.cpu_write(0xff41, 0x00);
+        assert_eq!(mmu.cpu_read(0xff41), 0x87);
+        mmu.get_io_mut()[0x41] = 0x80;
+        mmu.cpu_write(0xff41, 0xff);
+        assert_eq!(mmu.cpu_read(0xff41), 0xf8);
+    }
+
+    #[test]
+    fn ff50_unmaps_boot_rom_until_reset() {
+        let mut mmu = MMU::new();
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x0000] = 0x11;
+        mmu.load_rom(&rom);
+        assert!(mmu.load_boot_rom(&[0x22; 0x100]));
+        mmu.reset();
+        assert_eq!(mmu.cpu_read(0x0000), 0x22);
+        assert_eq!(mmu.cpu_read(0xff50), 0xfe);
+        mmu.cpu_write(0xff50, 0x01);
+        assert_eq!(mmu.cpu_read(0x0000), 0x11);
+        assert_eq!(mmu.cpu_read(0xff50), 0xff);
+        // Writing 0 afterwards cannot map it back in
+        mmu.cpu_write(0xff50, 0x00);
+        assert_eq!(mmu.cpu_read(0x0000), 0x11);
+        mmu.reset();
+        assert_eq!(mmu.cpu_read(0x0000), 0x22);
+    }
+
+    #[test]
+    fn mbc_name_follows_header_type() {
+        let mut mmu = MMU::new();
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x0147] = 0x13;
+        mmu.load_rom(&rom);
+        assert_eq!(mmu.mbc_name(), "MBC3+RAM+BATTERY");
+        rom[0x0147] = 0x42;
+        mmu.load_rom(&rom);
+        assert_eq!(mmu.mbc_name(), "UNKNOWN (0x42)");
+    }
+
+    #[test]
+    fn frame_sequencer_step_follows_div() {
+        let mut mmu = MMU::new();
+        mmu.write_byte(0xff04, 0x00);
+        let start = mmu.apu().frame_sequencer_step();
+        // One step per 8192 T-cycles at normal speed
+        mmu.step_timer(8191);
+        assert_eq!(mmu.apu().frame_sequencer_step(), start);
+        mmu.step_timer(1);
+        assert_eq!(mmu.apu().frame_sequencer_step(), (start + 1) & 7);
+        mmu.step_timer(8192 * 10);
+        assert_eq!(mmu.apu().frame_sequencer_step(), (start + 11) & 7);
+    }
+
+    #[test]
+    fn ie_keeps_all_bits_if_forces_upper_bits() {
+        let mut mmu = MMU::new();
+        mmu.cpu_write(0xffff, 0xff);
+        mmu.cpu_write(0xff0f, 0xff);
+        assert_eq!(mmu.cpu_read(0xffff), 0xff);
+        assert_eq!(mmu.cpu_read(0xff0f), 0xff);
+        // IE stores bits 5-7 as written; IF always reads them as 1
+        mmu.cpu_write(0xffff, 0xa0);
+        mmu.cpu_write(0xff0f, 0x00);
+        assert_eq!(mmu.cpu_read(0xffff), 0xa0);
+        assert_eq!(mmu.cpu_read(0xff0f), 0xe0);
+    }
+
+    #[test]
+    fn mbc1_ram_enable_then_bank_select_in_mode_1() {
+        let mut mmu = MMU::new();
+        let mut rom = vec![0u8; 0x8000];
+        // MBC1+RAM+BATTERY with 32 KiB RAM
+        rom[0x0147] = 0x03;
+        rom[0x0149] = 0x03;
+        mmu.load_rom(&rom);
+        mmu.cpu_write(0x0000, 0x0a);
+        mmu.cpu_write(0x6000, 0x01);
+        mmu.cpu_write(0x4000, 0x02);
+        mmu.cpu_write(0xa000, 0x55);
+        assert_eq!(mmu.eram[2 * 0x2000], 0x55);
+        assert_eq!(mmu.eram.iter().filter(|&&b| b != 0).count(), 1);
+        mmu.cpu_write(0x4000, 0x00);
+        assert_eq!(mmu.cpu_read(0xa000), 0x00);
+        mmu.cpu_write(0x4000, 0x02);
+        assert_eq!(mmu.cpu_read(0xa000), 0x55);
+        // Disabling RAM blocks both directions
+        mmu.cpu_write(0x0000, 0x00);
+        mmu.cpu_write(0xa000, 0x66);
+        assert_eq!(mmu.cpu_read(0xa000), 0xff);
+        assert_eq!(mmu.eram[2 * 0x2000], 0x55);
+    }
+
+    #[test]
+    fn ff75_keeps_bits_4_to_6_on_cgb_only() {
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x0143] = 0x80;
+        let mut mmu = MMU::new();
+        mmu.load_rom(&rom);
+        mmu.cpu_write(0xff75, 0x00);
+        assert_eq!(mmu.cpu_read(0xff75), 0x8f);
+        mmu.cpu_write(0xff75, 0xff);
+        assert_eq!(mmu.cpu_read(0xff75), 0xff);
+        mmu.cpu_write(0xff75, 0x50);
+        assert_eq!(mmu.cpu_read(0xff75), 0xdf);
+        mmu.cpu_write(0xff72, 0x5a);
+        assert_eq!(mmu.cpu_read(0xff72), 0x5a);
+
+        let mut mmu = MMU::new();
+        mmu.cpu_write(0xff75, 0x00);
+        mmu.cpu_write(0xff72, 0x5a);
+        assert_eq!((mmu.cpu_read(0xff75), mmu.cpu_read(0xff72)), (0xff, 0xff));
+    }
+
+    #[test]
+    fn set_oam_bypasses_access_restrictions() {
+        let mut mmu = MMU::new();
+        // LCD on in mode 3 with an OAM DMA running: CPU writes are dropped
+        mmu.get_io_mut()[0x40] = 0x91;
+        mmu.get_io_mut()[0x41] = 0x83;
+        mmu.cpu_write(0xff46, 0xc0);
+        mmu.cpu_write(0xfe00, 0x42);
+        assert_eq!(mmu.get_oam()[0], 0x00);
+
+        let mut oam = [0u8; 0xa0];
+        let sprites = [[16, 8, 1, 0x00], [32, 40, 2, 0x20], [100, 160, 3, 0x90]];
+        for (i, sprite) in sprites.iter().enumerate() {
+            oam[i * 4 + 36..i * 4 + 40].copy_from_slice(sprite);
+        }
+        mmu.set_oam(&oam);
+        let entries: Vec<&[u8]> = mmu.get_oam().chunks(4).collect();
+        assert_eq!(entries.len(), 40);
+        for (i, sprite) in sprites.iter().enumerate() {
+            assert_eq!(entries[9 + i], sprite);
+        }
+        assert!(entries.iter().enumerate().all(|(i, e)| (9..12).contains(&i) || e == &[0; 4]));
+    }
+
+    #[test]
+    fn strict_oam_dma_locks_everything_but_hram() {
+        for strict in [false, true] {
+            let mut mmu = MMU::new();
+            mmu.set_strict_oam_dma(strict);
+            mmu.write_byte(0xc100, 0x42);
+            mmu.cpu_write(0xff46, 0xc0);
+            mmu.step_oam_dma(10 * OAM_DMA_CYCLES_PER_BYTE);
+            assert_eq!(mmu.cpu_read(0xfe10), 0xff);
+            assert_eq!(mmu.cpu_read(0xc100), if strict { 0xff } else { 0x42 });
+            mmu.cpu_write(0xc100, 0x24);
+            assert_eq!(mmu.read_byte(0xc100), if strict { 0x42 } else { 0x24 });
+            mmu.cpu_write(0xff80, 0x12);
+            assert_eq!(mmu.cpu_read(0xff80), 0x12);
+        }
+    }
+
+    #[test]
+    fn mbc2_nibble_ram_and_address_bit_8() {
+        let mut rom = vec![0u8; 0x40000];
+        rom[0x0147] = 0x06;
+        rom[0x0148] = 0x03;
+        for bank in 0..16 {
+            rom[bank * 0x4000 + 0x10] = bank as u8;
+        }
+        let mut mmu = MMU::new();
+        mmu.load_rom(&rom);
+        // Address bit 8 clear: RAM enable
+        mmu.cpu_write(0x0000, 0x0a);
+        mmu.cpu_write(0xa000, 0xab);
+        mmu.cpu_write(0xa1ff, 0x05);
+        assert_eq!(mmu.cpu_read(0xa000), 0xfb);
+        assert_eq!(mmu.cpu_read(0xa1ff), 0xf5);
+        // 512 nibbles mirrored across the window
+        assert_eq!(mmu.cpu_read(0xa200), 0xfb);
+        assert_eq!(mmu.cpu_read(0xbfff), 0xf5);
+        // Address bit 8 set: ROM bank, 4 bits
+        mmu.cpu_write(0x0100, 0x15);
+        assert_eq!(mmu.cpu_read(0x4010), 5);
+        assert_eq!(mmu.cpu_read(0xa000), 0xfb);
+        mmu.cpu_write(0x0000, 0x00);
+        assert_eq!(mmu.cpu_read(0xa000), 0xff);
+    }
+
+    #[test]
+    fn oam_dma_takes_160_m_cycles_unless_instant() {
+        for timing in [DmaTiming::Accurate, DmaTiming::Instant] {
+            let mut mmu = MMU::new();
+            mmu.set_dma_timing(timing);
+            mmu.write_byte(0xc09f, 0x77);
+            mmu.cpu_write(0xff46, 0xc0);
+            if timing == DmaTiming::Accurate {
+                mmu.step_oam_dma(159 * OAM_DMA_CYCLES_PER_BYTE);
+                assert!(mmu.oam_dma_state().is_some());
+                assert_eq!(mmu.get_oam()[0x9f], 0x00);
+                mmu.step_oam_dma(OAM_DMA_CYCLES_PER_BYTE);
+            }
+            assert!(mmu.oam_dma_state().is_none());
+            assert_eq!(mmu.get_oam()[0x9f], 0x77);
+        }
+    }
+
+    #[test]
+    fn mbc5_reads_bank_0x1ff() {
+        // 8 MiB, the size that has a bank 0x1FF
+        let mut rom = vec![0u8; 0x800000];
+        rom[0x0147] = 0x19;
+        rom[0x0148] = 0x08;
+        for bank in 0..0x200 {
+            rom[bank * 0x4000 + 0x20] = bank as u8;
+            rom[bank * 0x4000 + 0x21] = (bank >> 8) as u8;
+        }
+        let mut mmu = MMU::new();
+        mmu.load_rom(&rom);
+        mmu.cpu_write(0x2000, 0xff);
+        mmu.cpu_write(0x3000, 0x01);
+        assert_eq!((mmu.cpu_read(0x4020), mmu.cpu_read(0x4021)), (0xff, 0x01));
+        // Bit 8 alone, then bank 0, which MBC5 maps as is
+        mmu.cpu_write(0x2000, 0x00);
+        assert_eq!((mmu.cpu_read(0x4020), mmu.cpu_read(0x4021)), (0x00, 0x01));
+        mmu.cpu_write(0x3000, 0x00);
+        assert_eq!((mmu.cpu_read(0x4020), mmu.cpu_read(0x4021)), (0x00, 0x00));
+    }
+
+    #[test]
+    fn joypad_irq_needs_the_group_selected() {
+        let mut mmu = MMU::new();
+        // Action buttons selected; Right is on the d-pad group
+        mmu.cpu_write(0xff00, 0x10);
+        mmu.cpu_write(0xff0f, 0x00);
+        mmu.joypad_press(4);
+        assert_eq!(mmu.cpu_read(0xff0f) & 0x10, 0);
+        assert_eq!(mmu.cpu_read(0xff00) & 0x0f, 0x0f);
+        // Selecting the d-pad pulls P10 low: that falling edge interrupts
+        mmu.cpu_write(0xff00, 0x20);
+        assert_eq!(mmu.cpu_read(0xff0f) & 0x10, 0x10);
+        assert_eq!(mmu.cpu_read(0xff00) & 0x0f, 0x0e);
+    }
+
+    #[test]
+    fn mbc1_bank_number_wraps_to_rom_size() {
+        // MBC1, 256 KiB: 16 banks
+        let mut rom = vec![0u8; 0x40000];
+        rom[0x0147] = 0x01;
+        rom[0x0148] = 0x03;
+        for bank in 0..16 {
+            rom[bank * 0x4000 + 0x30] = bank as u8;
+        }
+        let mut mmu = MMU::new();
+        mmu.load_rom(&rom);
+        mmu.cpu_write(0x2000, 0x13);
+        assert_eq!(mmu.cpu_read(0x4030), 0x03);
+        // Upper bits from 0x4000 make bank 0x21, which aliases bank 1
+        mmu.cpu_write(0x2000, 0x01);
+        mmu.cpu_write(0x4000, 0x01);
+        assert_eq!(mmu.cpu_read(0x4030), 0x01);
+    }
+
+    #[test]
+    fn sram_round_trip_with_rtc() {
+        // MBC3+TIMER+RAM+BATTERY with 32 KiB RAM
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x0147] = 0x10;
+        rom[0x0149] = 0x03;
+        let mut mmu = MMU::new();
+        mmu.load_rom(&rom);
+        mmu.cpu_write(0x0000, 0x0a);
+        mmu.cpu_write(0x4000, 0x03);
+        mmu.cpu_write(0xbfff, 0x99);
+        mmu.cpu_write(0x4000, 0x09);
+        mmu.cpu_write(0xa000, 42);
+        let saved = mmu.export_sram();
+        assert_eq!(saved.len(), 0x8000 + crate::rtc::SNAPSHOT_LEN);
+
+        let mut restored = MMU::new();
+        restored.load_rom(&rom);
+        assert!(!restored.import_sram(&saved[..0x7fff]));
+        assert!(restored.import_sram(&saved));
+        assert!(restored.export_sram() == saved);
+        restored.cpu_write(0x0000, 0x0a);
+        restored.cpu_write(0x4000, 0x03);
+        assert_eq!(restored.cpu_read(0xbfff), 0x99);
+        restored.cpu_write(0x6000, 0x00);
+        restored.cpu_write(0x6000, 0x01);
+        restored.cpu_write(0x4000, 0x09);
+        // The live minutes written before the save, seen through a latch
+        assert_eq!(restored.cpu_read(0xa000), 42);
+
+        // No battery, nothing to save
+        rom[0x0147] = 0x12;
+        let mut mmu = MMU::new();
+        mmu.load_rom(&rom);
+        assert!(mmu.export_sram().is_empty());
+        assert!(!mmu.import_sram(&saved));
+    }
+
+    #[test]
+    fn hdma_abort_keeps_remaining_blocks() {
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x0143] = 0x80;
+        let mut mmu = MMU::new();
+        mmu.load_rom(&rom);
+        for i in 0..0xc0u16 {
+            mmu.write_byte(0xc000 + i, 0x40 + i as u8);
+        }
+        // 0xC000 -> 0x8000, six blocks in HBlank mode
+        mmu.cpu_write(0xff51, 0xc0);
+        mmu.cpu_write(0xff52, 0x00);
+        mmu.cpu_write(0xff53, 0x00);
+        mmu.cpu_write(0xff54, 0x00);
+        mmu.cpu_write(0xff55, 0x85);
+        assert_eq!(mmu.cpu_read(0xff55), 0x05);
+        mmu.hdma_hblank_step();
+        mmu.hdma_hblank_step();
+        assert_eq!(mmu.cpu_read(0xff55), 0x03);
+        // Bit 7 clear while it runs: stop, reporting the blocks left
+        mmu.cpu_write(0xff55, 0x00);
+        assert_eq!(mmu.cpu_read(0xff55), 0x83);
+        assert_eq!(mmu.take_hdma_stall(), 0);
+        mmu.hdma_hblank_step();
+        assert_eq!(mmu.read_byte(0x801f), 0x5f);
+        assert_eq!(mmu.read_byte(0x8020), 0x00);
+
+        // A general transfer picks up at the next addresses, runs whole and
+        // stalls the CPU
+        mmu.cpu_write(0xff55, 0x03);
+        assert_eq!(mmu.cpu_read(0xff55), 0xff);
+        assert_eq!(mmu.read_byte(0x8020), 0x60);
+        assert_eq!(mmu.read_byte(0x805f), 0x9f);
+        assert_eq!(mmu.take_hdma_stall(), 128);
+
+        // After KEY1 + STOP the same four blocks cost twice the CPU cycles
+        mmu.cpu_write(0xff4d, 0x01);
+        assert!(mmu.try_speed_switch());
+        mmu.cpu_write(0xff55, 0x03);
+        assert_eq!(mmu.read_byte(0x809f), 0xdf);
+        assert_eq!(mmu.take_hdma_stall(), 256);
+    }
+
+    #[test]
+    fn global_checksum_matches_crafted_header() {
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x0134..0x0138].copy_from_slice(b"TEST");
+        rom[0x4000] = 0xff;
+        rom[0x7fff] = 0x80;
+        let sum = rom.iter().fold(0u16, |s, &b| s.wrapping_add(b as u16));
+        rom[0x014e..0x0150].copy_from_slice(&sum.to_be_bytes());
+        let mut mmu = MMU::new();
+        mmu.load_rom(&rom);
+        assert_eq!(mmu.global_checksum(), sum);
+        assert_eq!(mmu.header_global_checksum(), sum);
+        rom[0x4001] = 1;
+        mmu.load_rom(&rom);
+        assert_ne!(mmu.global_checksum(), mmu.header_global_checksum());
+    }
+
+    #[test]
+    fn cgb_wram_bank_0_is_fixed_at_0xc000() {
+        let mut mmu = MMU::new();
+        mmu.is_gbc = true;
+        // Bank 0 storage, 0xC000 and its echo are the same bytes
+        mmu.wram[0x0123] = 0x5a;
+        assert_eq!(mmu.read_byte(0xc123), 0x5a);
+        assert_eq!(mmu.read_byte(0xe123), 0x5a);
+        mmu.write_byte(0xc456, 0xa5);
+        assert_eq!(mmu.wram[0x0456], 0xa5);
+        // SVBK 0 selects bank 1 for 0xD000, never bank 0
+        for bank in 0..8 {
+            mmu.write_byte(0xff70, bank);
+            mmu.write_byte(0xd123, 0x10 + bank);
+            assert_eq!(mmu.read_byte(0xc123), 0x5a, "svbk {}", bank);
+        }
+        mmu.write_byte(0xff70, 1);
+        assert_eq!(mmu.read_byte(0xd123), 0x11);
+        assert_eq!(mmu.wram[WRAM_BANK_SIZE + 0x0123], 0x11);
+        assert_eq!(mmu.wram[7 * WRAM_BANK_SIZE + 0x0123], 0x17);
+    }
+
+    #[test]
+    fn div_write_resets_the_internal_counter() {
+        let mut mmu = MMU::new();
+        mmu.step_timer(0x80);
+        mmu.write_byte(0xff04, 0x77);
+        assert_eq!(mmu.read_byte(0xff04), 0x00);
+        // A full 256 cycles to the next DIV tick, not what was left before
+        mmu.step_timer(255);
+        assert_eq!(mmu.read_byte(0xff04), 0x00);
+        mmu.step_timer(1);
+        assert_eq!(mmu.read_byte(0xff04), 0x01);
+
+        // 262144 Hz: bit 3 is high 8 cycles in, so the reset bumps TIMA
+        mmu.write_byte(0xff04, 0x00);
+        mmu.write_byte(0xff05, 0x00);
+        mmu.write_byte(0xff07, 0x05);
+        mmu.step_timer(8);
+        mmu.write_byte(0xff04, 0x00);
+        assert_eq!(mmu.read_byte(0xff05), 0x01);
+        mmu.step_timer(15);
+        assert_eq!(mmu.read_byte(0xff05), 0x01);
+        mmu.step_timer(1);
+        assert_eq!(mmu.read_byte(0xff05), 0x02);
+    }
+
+    #[test]
+    fn joypad_irq_only_on_a_selected_falling_edge() {
+        let mut mmu = MMU::new();
+        // Nothing selected: no press can pull a line low
+        mmu.cpu_write(0xff00, 0x30);
+        mmu.cpu_write(0xff0f, 0x00);
+        mmu.joypad_press(0);
+        mmu.joypad_press(6);
+        assert_eq!(mmu.cpu_read(0xff0f) & 0x10, 0);
+        mmu.joypad_release(0);
+        mmu.joypad_release(6);
+
+        // Action buttons selected: Up stays invisible, Start interrupts
+        mmu.cpu_write(0xff00, 0x10);
+        mmu.joypad_press(6);
+        assert_eq!(mmu.cpu_read(0xff0f) & 0x10, 0);
+        mmu.joypad_press(3);
+        assert_eq!(mmu.cpu_read(0xff0f) & 0x10, 0x10);
+        // Releasing is a rising edge
+        mmu.cpu_write(0xff0f, 0x00);
+        mmu.joypad_release(3);
+        assert_eq!(mmu.cpu_read(0xff0f) & 0x10, 0);
+    }
+}
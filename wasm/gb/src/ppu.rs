@@ -1,4 +1,7 @@
 use crate::mmu::MMU;
+use serde::{Deserialize, Serialize};
+use serde_big_array::BigArray;
+use wasm_bindgen::prelude::*;
 
 pub const SCREEN_WIDTH: usize = 160;
 pub const SCREEN_HEIGHT: usize = 144;
@@ -13,44 +16,309 @@ const MODE_DRAWING: u8 = 3;
 const MODE_OAM_CYCLES: u32 = 80;
 const MODE_DRAWING_CYCLES: u32 = 172;
 const SCANLINE_CYCLES: u32 = 456;
+// Mode 3 dots spent fetching the first tile (twice, the first one thrown
+// away) before any pixel goes out; one pixel per dot after that, so the
+// 160th ends mode 3
+const MODE_DRAWING_FETCH_DELAY: u32 = MODE_DRAWING_CYCLES - SCREEN_WIDTH as u32;
+// The first line after LCD enable is 4 dots short, and LY=LYC is not
+// compared for its first 4 dots (mooneye-gb acceptance/ppu/lcdon_timing-GS)
+const LCD_ON_FIRST_LINE_CYCLES: u32 = SCANLINE_CYCLES - 4;
+const LCD_ON_LYC_DELAY: u32 = 4;
+
+// LCD registers as seen when a visible scanline was rendered
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ScanlineRegs {
+    pub ly: u8,
+    pub lcdc: u8,
+    pub scy: u8,
+    pub scx: u8,
+    pub wy: u8,
+    pub wx: u8,
+    pub bgp: u8,
+    pub obp0: u8,
+    pub obp1: u8,
+}
+
+// Pixel layout of the framebuffer. RGBA (the default) is what canvas
+// ImageData wants; packed RGB saves a quarter of the bytes for frontends
+// uploading to an RGB texture
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FrameBufferFormat {
+    #[default]
+    Rgba = 0,
+    Rgb = 1,
+}
+
+impl FrameBufferFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            FrameBufferFormat::Rgba => RgbaSink::BYTES_PER_PIXEL,
+            FrameBufferFormat::Rgb => RgbSink::BYTES_PER_PIXEL,
+        }
+    }
+}
+
+// How one pixel is stored for a framebuffer format. The layer renderers
+// are generic over it, so the format is matched once per line instead of
+// once per pixel; a new format is a new sink plus a match arm in
+// render_scanline
+trait PixelSink {
+    const BYTES_PER_PIXEL: usize;
+    fn put(px: &mut [u8], rgb: [u8; 3]);
+}
+
+struct RgbaSink;
+
+impl PixelSink for RgbaSink {
+    const BYTES_PER_PIXEL: usize = 4;
+
+    #[inline]
+    fn put(px: &mut [u8], rgb: [u8; 3]) {
+        px[..3].copy_from_slice(&rgb);
+        px[3] = 255;
+    }
+}
+
+struct RgbSink;
+
+impl PixelSink for RgbSink {
+    const BYTES_PER_PIXEL: usize = 3;
+
+    #[inline]
+    fn put(px: &mut [u8], rgb: [u8; 3]) {
+        px.copy_from_slice(&rgb);
+    }
+}
 
+// Default DMG shades, lightest to darkest (green screen)
+const DEFAULT_DMG_PALETTE: [[u8; 3]; 4] = [
+    [224, 248, 208], // White
+    [136, 192, 112], // Light gray
+    [52, 104, 86],   // Dark gray
+    [8, 24, 32],     // Black
+];
+
+// Address of a BG/window tile's data. LCDC bit 4 set selects 0x8000 with
+// unsigned tile numbers; clear selects 0x9000 with signed numbers, so
+// 0x00-0x7F map to 0x9000-0x97F0 and 0x80-0xFF to 0x8800-0x8FF0
+fn tile_data_addr(lcdc: u8, tile_num: u8) -> u16 {
+    if lcdc & 0x10 != 0 {
+        0x8000 + tile_num as u16 * 16
+    } else {
+        0x9000u16.wrapping_add((tile_num as i8 as i16 * 16) as u16)
+    }
+}
+
+// Save states carry the timing and line state; the framebuffer and output
+// settings are skipped
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Serialize, Deserialize)]
 pub struct PPU {
+    #[serde(skip)]
     frame_buffer: Vec<u8>,
+    #[serde(skip)]
+    format: FrameBufferFormat,
+    // RGB for DMG shades 0..3; kept across reset
+    #[serde(skip)]
+    dmg_palette: [[u8; 3]; 4],
     scanline_counter: u32,
     // Mapped BG color index (0..3) for current scanline, per pixel
+    #[serde(with = "BigArray")]
     bg_color_line: [u8; SCREEN_WIDTH],
     // CGB BG priority bit per pixel (attr bit7)
+    #[serde(with = "BigArray")]
     bg_priority_line: [bool; SCREEN_WIDTH],
+    // Per-line register snapshots for the current frame (raster effects)
+    #[serde(with = "BigArray")]
+    scanline_regs: [ScanlineRegs; SCREEN_HEIGHT],
+    // Combined STAT interrupt line (LYC and mode sources ORed together)
+    stat_line: bool,
+    // Debug: pre-palette color index (0..3) per pixel of the last frame;
+    // only filled while enabled
+    #[serde(skip)]
+    raw_index_enabled: bool,
+    #[serde(skip)]
+    raw_index_buffer: Vec<u8>,
+    // Set when a rendered line differs from what the framebuffer held
+    #[serde(skip)]
+    frame_dirty: bool,
+    // LCDC bit 7 as of the last step, to catch the on/off edges
+    lcd_on: bool,
+    // First line after the LCD is switched on: no OAM scan (STAT stays in
+    // mode 0 until mode 3) and the LY=LYC compare comes a few dots late
+    lcd_on_first_line: bool,
+    // Headless runs skip pixel work; timing, STAT and interrupts still run
+    #[serde(skip)]
+    rendering_enabled: bool,
+    // Sprites picked by the mode 2 OAM scan for line `line_sprites_ly`, in
+    // drawing priority order: (X, OAM index, Y)
+    line_sprites: [(u8, usize, u8); 10],
+    line_sprite_count: usize,
+    line_sprites_ly: Option<u8>,
 }
 
 impl PPU {
     pub fn new() -> Self {
         Self {
             frame_buffer: vec![0xff; SCREEN_WIDTH * SCREEN_HEIGHT * 4],
+            format: FrameBufferFormat::Rgba,
+            dmg_palette: DEFAULT_DMG_PALETTE,
             scanline_counter: 0,
             bg_color_line: [0; SCREEN_WIDTH],
             bg_priority_line: [false; SCREEN_WIDTH],
+            scanline_regs: [ScanlineRegs::default(); SCREEN_HEIGHT],
+            stat_line: false,
+            raw_index_enabled: false,
+            raw_index_buffer: Vec::new(),
+            frame_dirty: true,
+            lcd_on: true,
+            lcd_on_first_line: false,
+            rendering_enabled: true,
+            line_sprites: [(0, 0, 0); 10],
+            line_sprite_count: 0,
+            line_sprites_ly: None,
         }
     }
 
     pub fn reset(&mut self, mmu: &mut MMU) {
         self.frame_buffer.fill(0xff);
+        self.frame_dirty = true;
         self.scanline_counter = 0;
-        self.set_mode(mmu, MODE_OAM_SCAN);
+        self.stat_line = false;
+        self.lcd_on = true;
+        self.lcd_on_first_line = false;
+        self.line_sprites_ly = None;
+        // Post-boot the PPU sits at LY=0 in mode 1 (STAT=0x85); the first
+        // step moves it into OAM scan
         self.set_ly(mmu, 0);
+        self.set_mode(mmu, MODE_VBLANK);
+        self.check_lyc(mmu);
         // Clear BG color line
         self.bg_color_line.fill(0);
         self.bg_priority_line.fill(false);
+        self.scanline_regs.fill(ScanlineRegs::default());
+        self.raw_index_buffer.fill(0);
+    }
+
+    // Take the PPU state from a save state; the framebuffer keeps the last
+    // frame until the next one is drawn
+    pub fn load_state(&mut self, mut saved: PPU) {
+        saved.frame_buffer = std::mem::take(&mut self.frame_buffer);
+        saved.format = self.format;
+        saved.dmg_palette = self.dmg_palette;
+        saved.raw_index_enabled = self.raw_index_enabled;
+        saved.raw_index_buffer = std::mem::take(&mut self.raw_index_buffer);
+        saved.frame_dirty = true;
+        saved.rendering_enabled = self.rendering_enabled;
+        saved.line_sprite_count = saved.line_sprite_count.min(saved.line_sprites.len());
+        *self = saved;
     }
 
     pub fn get_frame_buffer(&self) -> &[u8] {
         &self.frame_buffer
     }
 
+    // Copy of the current frame as RGBA, whatever the buffer format
+    pub fn frame_rgba(&self) -> Vec<u8> {
+        let bpp = self.format.bytes_per_pixel();
+        let mut rgba = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * RgbaSink::BYTES_PER_PIXEL];
+        for (dst, src) in rgba.chunks_exact_mut(RgbaSink::BYTES_PER_PIXEL).zip(self.frame_buffer.chunks_exact(bpp)) {
+            RgbaSink::put(dst, [src[0], src[1], src[2]]);
+        }
+        rgba
+    }
+
+    // Printable 80x36 preview of the frame for terminals and CI logs: each
+    // character covers a 2x4 pixel block, by average brightness " .:#"
+    pub fn frame_ascii(&self) -> String {
+        const COLS: usize = 80;
+        const ROWS: usize = 36;
+        let (bw, bh) = (SCREEN_WIDTH / COLS, SCREEN_HEIGHT / ROWS);
+        let bpp = self.format.bytes_per_pixel();
+        let mut out = String::with_capacity((COLS + 1) * ROWS);
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                let mut sum = 0u32;
+                for y in row * bh..(row + 1) * bh {
+                    for x in col * bw..(col + 1) * bw {
+                        let i = (y * SCREEN_WIDTH + x) * bpp;
+                        let px = &self.frame_buffer[i..i + 3];
+                        sum += (px[0] as u32 * 299 + px[1] as u32 * 587 + px[2] as u32 * 114) / 1000;
+                    }
+                }
+                out.push(match sum / (bw * bh) as u32 {
+                    192.. => ' ',
+                    128..=191 => '.',
+                    64..=127 => ':',
+                    _ => '#',
+                });
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    pub fn set_rendering_enabled(&mut self, enabled: bool) {
+        self.rendering_enabled = enabled;
+    }
+
+    pub fn frame_buffer_format(&self) -> FrameBufferFormat {
+        self.format
+    }
+
+    // Switch layouts, repacking the current picture so the next present
+    // still shows it. The buffer is reallocated: re-fetch its pointer
+    pub fn set_frame_buffer_format(&mut self, format: FrameBufferFormat) {
+        if format == self.format {
+            return;
+        }
+        let (old_bpp, new_bpp) = (self.format.bytes_per_pixel(), format.bytes_per_pixel());
+        let mut buffer = vec![0xff; SCREEN_WIDTH * SCREEN_HEIGHT * new_bpp];
+        for (dst, src) in buffer.chunks_exact_mut(new_bpp).zip(self.frame_buffer.chunks_exact(old_bpp)) {
+            dst[..3].copy_from_slice(&src[..3]);
+        }
+        self.frame_buffer = buffer;
+        self.format = format;
+        self.frame_dirty = true;
+    }
+
+    // Whether any pixel changed since the last clear_frame_dirty
+    pub fn frame_dirty(&self) -> bool {
+        self.frame_dirty
+    }
+
+    pub fn clear_frame_dirty(&mut self) {
+        self.frame_dirty = false;
+    }
+
+    // Register snapshots taken as each visible line was rendered
+    pub fn scanline_regs(&self) -> &[ScanlineRegs] {
+        &self.scanline_regs
+    }
+
+    pub fn set_raw_index_enabled(&mut self, enabled: bool) {
+        self.raw_index_enabled = enabled;
+        self.raw_index_buffer = if enabled { vec![0; SCREEN_WIDTH * SCREEN_HEIGHT] } else { Vec::new() };
+    }
+
+    // Empty unless the raw index debug buffer is enabled
+    pub fn get_raw_index_buffer(&self) -> &[u8] {
+        &self.raw_index_buffer
+    }
+
+    #[inline]
+    fn set_raw_index(&mut self, ly: u8, x: usize, index: u8) {
+        if self.raw_index_enabled {
+            self.raw_index_buffer[ly as usize * SCREEN_WIDTH + x] = index;
+        }
+    }
+
     #[inline]
-    fn set_pixel_rgb(&mut self, ly: u8, x: usize, rgb: [u8; 3]) {
-        let idx = (ly as usize * SCREEN_WIDTH + x) * 4;
-        if idx + 3 >= self.frame_buffer.len() {
+    fn set_pixel_rgb<S: PixelSink>(&mut self, ly: u8, x: usize, rgb: [u8; 3]) {
+        let bpp = S::BYTES_PER_PIXEL;
+        let idx = (ly as usize * SCREEN_WIDTH + x) * bpp;
+        if idx + bpp > self.frame_buffer.len() {
             panic!(
                 "PPU framebuffer overflow: ly={}, x={}, idx={}, len={}",
                 ly,
@@ -59,51 +327,175 @@ impl PPU {
                 self.frame_buffer.len()
             );
         }
-        self.frame_buffer[idx] = rgb[0];
-        self.frame_buffer[idx + 1] = rgb[1];
-        self.frame_buffer[idx + 2] = rgb[2];
-        self.frame_buffer[idx + 3] = 255;
+        S::put(&mut self.frame_buffer[idx..idx + bpp], rgb);
+    }
+
+    // Advance exactly one dot (T-cycle), independent of CPU stepping. Mode,
+    // LY, STAT timing and the mode 3 pixel position are tracked per dot;
+    // the framebuffer is still drawn a whole line at a time when mode 3
+    // begins.
+    pub fn step_dot(&mut self, mmu: &mut MMU) -> bool {
+        self.step(mmu, 1)
+    }
+
+    // Dot position within the current scanline (0..455)
+    pub fn dot(&self) -> u32 {
+        self.scanline_counter
+    }
+
+    // X of the pixel going out on this dot of mode 3 (0 while the first
+    // tile is fetched), None in the other modes
+    pub fn pixel_x(&self, mmu: &MMU) -> Option<u8> {
+        if !self.lcd_on || self.get_mode(mmu) != MODE_DRAWING {
+            return None;
+        }
+        let into_mode = self.scanline_counter.saturating_sub(MODE_OAM_CYCLES);
+        Some(into_mode.saturating_sub(MODE_DRAWING_FETCH_DELAY).min(SCREEN_WIDTH as u32) as u8)
+    }
+
+    fn start_oam_scan(&mut self, mmu: &mut MMU, ly: u8) {
+        self.set_mode(mmu, MODE_OAM_SCAN);
+        self.scan_oam(mmu, ly);
+    }
+
+    // Mode 2: pick the first 10 sprites in OAM order whose rows cover this
+    // line, by Y alone (X off screen still counts), and order them for
+    // drawing. Mode 3 draws from this list, so OAM changes after the scan do
+    // not change which sprites show on the line
+    fn scan_oam(&mut self, mmu: &MMU, ly: u8) {
+        let sprite_height = if mmu.get_io()[0x40] & 0x04 != 0 { 16 } else { 8 };
+        let oam = mmu.get_oam();
+        // Y is signed so sprites partly above the screen still hit
+        let mut count: usize = 0;
+        for i in 0..40 {
+            let row = ly as i16 - (oam[i * 4] as i16 - 16);
+            if (0..sprite_height as i16).contains(&row) {
+                if count < 10 {
+                    self.line_sprites[count] = (oam[i * 4 + 1], i, oam[i * 4]);
+                    count += 1;
+                } else {
+                    break; // max 10/line
+                }
+            }
+        }
+
+        // Highest priority first. DMG: lower X, then lower OAM index. CGB
+        // ignores X and goes by OAM index alone, which the list is already in
+        if !mmu.is_gbc() {
+            self.line_sprites[..count].sort_unstable();
+        }
+        self.line_sprite_count = count;
+        self.line_sprites_ly = Some(ly);
+    }
+
+    // Renderer testing without the CPU: set LY and draw that one line from
+    // the current VRAM, OAM and registers. Mode and STAT are left alone
+    pub fn render_line_for_test(&mut self, mmu: &mut MMU, ly: u8) {
+        self.set_ly(mmu, ly);
+        self.scan_oam(mmu, ly);
+        self.render_scanline(mmu);
     }
 
-    // Step PPU; return true when a frame is ready
+    // Step PPU; return true when a frame is ready. Long stalls (speed
+    // switch, general DMA) are fed in pieces no longer than the shortest
+    // mode, so no line or mode change is skipped
     pub fn step(&mut self, mmu: &mut MMU, cycles: u32) -> bool {
+        let mut frame_ready = false;
+        let mut left = cycles;
+        loop {
+            let chunk = left.min(MODE_OAM_CYCLES);
+            left -= chunk;
+            frame_ready |= self.step_chunk(mmu, chunk);
+            if left == 0 {
+                return frame_ready;
+            }
+        }
+    }
+
+    fn step_chunk(&mut self, mmu: &mut MMU, cycles: u32) -> bool {
         let lcdc = mmu.get_io()[0x40];
 
-        // LCD off?
+        // LCD off: LY and the line counter go to 0 and STAT reports mode 0.
+        // The mode change itself never raises a STAT interrupt
         if (lcdc & 0x80) == 0 {
+            if self.lcd_on {
+                self.lcd_on = false;
+                self.scanline_counter = 0;
+                let io = mmu.get_io_mut();
+                io[0x44] = 0;
+                io[0x41] &= 0xfc;
+                self.stat_line = false;
+                self.line_sprites_ly = None;
+            }
             return false;
         }
 
+        // LCD back on: start a fresh frame at the top of line 0
+        if !self.lcd_on {
+            self.lcd_on = true;
+            self.lcd_on_first_line = true;
+            self.scanline_counter = 0;
+        }
+
+        // Pick up CPU writes to STAT enables and LYC since the last step; a
+        // source enabled while its condition already holds is a rising edge
+        if !(self.lcd_on_first_line && self.scanline_counter < LCD_ON_LYC_DELAY) {
+            self.check_lyc(mmu);
+        }
+
         self.scanline_counter += cycles;
         let ly = self.get_ly(mmu);
 
         // End of scanline
-        if self.scanline_counter >= SCANLINE_CYCLES {
-            self.scanline_counter -= SCANLINE_CYCLES;
+        let line_cycles = if self.lcd_on_first_line { LCD_ON_FIRST_LINE_CYCLES } else { SCANLINE_CYCLES };
+        if self.scanline_counter >= line_cycles {
+            self.scanline_counter -= line_cycles;
+            self.lcd_on_first_line = false;
             let new_ly = (ly + 1) % 154;
             self.set_ly(mmu, new_ly);
 
+            if new_ly < 144 {
+                // DMG: for a few dots while LY changes neither a mode source
+                // nor LY=LYC holds, so the STAT line drops and mode 2 or LYC
+                // at the new line interrupts even if mode 0 kept it high. CGB
+                // has no such gap and those are blocked
+                if !mmu.is_gbc() {
+                    mmu.get_io_mut()[0x41] &= !0x04;
+                    self.stat_line = false;
+                }
+                self.start_oam_scan(mmu, new_ly);
+            }
+
             // LYC=LY
             self.check_lyc(mmu);
 
             // VBlank
             if new_ly == 144 {
                 self.set_mode(mmu, MODE_VBLANK);
+                self.line_sprites_ly = None;
+                // Hardware quirk: the mode 2 source also fires as line 144
+                // starts, as if an OAM scan were beginning
+                if mmu.get_io()[0x41] & 0x20 != 0 && !self.stat_line {
+                    self.request_interrupt(mmu, 1);
+                    self.stat_line = true;
+                }
                 self.request_interrupt(mmu, 0); // VBlank interrupt
                 return true; // Frame ready
-            } else if new_ly == 0 {
-                self.set_mode(mmu, MODE_OAM_SCAN);
             }
         }
 
         // Mode update
         if ly < 144 {
             if self.scanline_counter < MODE_OAM_CYCLES {
-                self.set_mode(mmu, MODE_OAM_SCAN);
+                if !self.lcd_on_first_line && self.get_mode(mmu) != MODE_OAM_SCAN {
+                    self.start_oam_scan(mmu, ly);
+                }
             } else if self.scanline_counter < MODE_OAM_CYCLES + MODE_DRAWING_CYCLES {
                 if self.get_mode(mmu) != MODE_DRAWING {
                     self.set_mode(mmu, MODE_DRAWING);
-                    self.render_scanline(mmu);
+                    if self.rendering_enabled {
+                        self.render_scanline(mmu);
+                    }
                 }
             } else {
                 if self.get_mode(mmu) != MODE_HBLANK {
@@ -115,6 +507,10 @@ impl PPU {
         false
     }
 
+    // The whole line is drawn when mode 3 starts, so LCDC (BG/window/OBJ
+    // enables, OBJ size, map and data selects) and the scroll/palette
+    // registers are sampled once per line: a mid-frame write, such as
+    // turning sprites off at LY=72, affects the following lines only
     fn render_scanline(&mut self, mmu: &MMU) {
         let lcdc = mmu.get_io()[0x40];
         let ly = self.get_ly(mmu);
@@ -123,56 +519,71 @@ impl PPU {
             return;
         }
 
-        // Clear line (white)
-        let line_start = ly as usize * SCREEN_WIDTH * 4;
+        let io = mmu.get_io();
+        self.scanline_regs[ly as usize] = ScanlineRegs {
+            ly,
+            lcdc,
+            scy: io[0x42],
+            scx: io[0x43],
+            wy: io[0x4a],
+            wx: io[0x4b],
+            bgp: io[0x47],
+            obp0: io[0x48],
+            obp1: io[0x49],
+        };
+
+        // Keep the previous contents of the line for the dirty check
+        let line_len = SCREEN_WIDTH * self.format.bytes_per_pixel();
+        let line_start = ly as usize * line_len;
+        let mut previous = [0u8; SCREEN_WIDTH * 4];
+        previous[..line_len].copy_from_slice(&self.frame_buffer[line_start..line_start + line_len]);
+
+        // Clear line (white, opaque in either format)
+        self.frame_buffer[line_start..line_start + line_len].fill(255);
         for x in 0..SCREEN_WIDTH {
-            let offset = line_start + x * 4;
-            if offset + 3 >= self.frame_buffer.len() {
-                panic!(
-                    "PPU framebuffer clear overflow: ly={}, x={}, offset={}, len={}",
-                    ly,
-                    x,
-                    offset,
-                    self.frame_buffer.len()
-                );
-            }
-            self.frame_buffer[offset] = 255;
-            self.frame_buffer[offset + 1] = 255;
-            self.frame_buffer[offset + 2] = 255;
-            self.frame_buffer[offset + 3] = 255;
             // Default BG color index = 0
             self.bg_color_line[x] = 0;
             self.bg_priority_line[x] = false;
+            self.set_raw_index(ly, x, 0);
         }
 
-        // BG (re-enabled for isolation test)
-        if lcdc & 0x01 != 0 {
-            self.render_background(mmu, ly);
+        match self.format {
+            FrameBufferFormat::Rgba => self.render_layers::<RgbaSink>(mmu, ly, lcdc),
+            FrameBufferFormat::Rgb => self.render_layers::<RgbSink>(mmu, ly, lcdc),
+        }
+
+        if !self.frame_dirty && self.frame_buffer[line_start..line_start + line_len] != previous[..line_len] {
+            self.frame_dirty = true;
+        }
+    }
+
+    fn render_layers<S: PixelSink>(&mut self, mmu: &MMU, ly: u8, lcdc: u8) {
+        // BG (re-enabled for isolation test). On CGB LCDC bit 0 is the BG
+        // master priority instead, and the BG is always drawn
+        if lcdc & 0x01 != 0 || mmu.is_gbc() {
+            self.render_background::<S>(mmu, ly);
         }
 
         // WIN (re-enabled)
         if lcdc & 0x20 != 0 {
-            self.render_window(mmu, ly);
+            self.render_window::<S>(mmu, ly);
         }
 
         // OBJ (re-enabled)
         if lcdc & 0x02 != 0 {
-            self.render_sprites(mmu, ly);
+            self.render_sprites::<S>(mmu, ly);
         }
     }
 
-    fn render_background(&mut self, mmu: &MMU, ly: u8) {
+    fn render_background<S: PixelSink>(&mut self, mmu: &MMU, ly: u8) {
         let io = mmu.get_io();
         let lcdc = io[0x40];
         let scy = io[0x42];
         let scx = io[0x43];
-        let bgp = io[0x47];
         let is_cgb = mmu.is_gbc();
 
         // Tile map/data
         let tile_map_base: u16 = if lcdc & 0x08 != 0 { 0x9c00 } else { 0x9800 };
-        let tile_data_base: u16 = if lcdc & 0x10 != 0 { 0x8000 } else { 0x8800 };
-        let signed_tile_data = (lcdc & 0x10) == 0;
 
         let y = ly.wrapping_add(scy);
         let tile_y = ((y >> 3) & 31) as u16;
@@ -182,71 +593,56 @@ impl PPU {
             let tile_x = ((x_pos >> 3) & 31) as u16;
             let tile_index = tile_y * 32 + tile_x;
 
-            // Tile number and attributes (CGB)
-            let tile_num = mmu.read_byte(tile_map_base + tile_index);
+            // Tile number (always bank 0, whatever VBK says) and attributes (CGB)
+            let tile_num = mmu.read_vram_bank_byte(tile_map_base + tile_index, 0);
             let mut attr = 0u8;
             let mut vram_bank = 0usize;
             let mut xflip = false;
             let mut yflip = false;
             let mut palette_id = 0u8;
-                if is_cgb {
-                    // Attributes are stored in VRAM bank 1 at same tile map address
-                    attr = mmu.read_vram_bank_byte(tile_map_base + tile_index, 1);
-                    vram_bank = ((attr >> 3) & 1) as usize;
-                    xflip = (attr & 0x20) != 0;
-                    yflip = (attr & 0x40) != 0;
-                    palette_id = attr & 0x07;
-                    // Set per-pixel BG priority later when pixel is written; cache the bit here
+            if is_cgb {
+                // Attributes are stored in VRAM bank 1 at same tile map address
+                attr = mmu.read_vram_bank_byte(tile_map_base + tile_index, 1);
+                vram_bank = ((attr >> 3) & 1) as usize;
+                xflip = (attr & 0x20) != 0;
+                yflip = (attr & 0x40) != 0;
+                palette_id = attr & 0x07;
+                // Set per-pixel BG priority later when pixel is written; cache the bit here
             }
 
             let mut tile_line = (y & 7) as u16;
             if yflip { tile_line = 7 - tile_line; }
-            let tile_line_addr = (tile_line * 2) as u16;
+            let tile_line_addr = tile_line * 2;
 
-            let base_addr = if signed_tile_data {
-                let offset = (tile_num as i8 as i16 as u16).wrapping_add(128);
-                tile_data_base.wrapping_add(offset * 16)
-            } else {
-                tile_data_base + (tile_num as u16) * 16
-            };
+            let base_addr = tile_data_addr(lcdc, tile_num);
 
-            // Tile data
-            let (byte1, byte2) = if is_cgb {
-                (
-                    mmu.read_vram_bank_byte(base_addr + tile_line_addr, vram_bank),
-                    mmu.read_vram_bank_byte(base_addr + tile_line_addr + 1, vram_bank),
-                )
-            } else {
-                (
-                    mmu.read_byte(base_addr + tile_line_addr),
-                    mmu.read_byte(base_addr + tile_line_addr + 1),
-                )
-            };
+            // Tile data (DMG has a single VRAM bank; vram_bank stays 0 there)
+            let byte1 = mmu.read_vram_bank_byte(base_addr + tile_line_addr, vram_bank);
+            let byte2 = mmu.read_vram_bank_byte(base_addr + tile_line_addr + 1, vram_bank);
 
             // Pixel
-            let bit = if xflip { (x_pos & 7) } else { 7 - (x_pos & 7) };
+            let bit = if xflip { x_pos & 7 } else { 7 - (x_pos & 7) };
             let color_num = ((byte2 >> bit) & 1) << 1 | ((byte1 >> bit) & 1);
             // Track raw BG color number for sprite priority checks
             self.bg_color_line[x] = color_num;
-                // Track CGB BG priority (attr bit7) per pixel
-                if is_cgb { self.bg_priority_line[x] = (attr & 0x80) != 0; }
+            self.set_raw_index(ly, x, color_num);
+            // Track CGB BG priority (attr bit7) per pixel
+            if is_cgb { self.bg_priority_line[x] = (attr & 0x80) != 0; }
             // Convert to RGB
             let rgb = if is_cgb {
-                mmu.cgb_get_bg_color_rgb(palette_id, color_num)
+                self.resolve_cgb_bg_color(mmu, palette_id, color_num)
             } else {
-                let mapped = (bgp >> (color_num * 2)) & 0x03;
-                self.get_color(mapped)
+                self.resolve_bg_color(mmu, color_num)
             };
-            self.set_pixel_rgb(ly, x, rgb);
+            self.set_pixel_rgb::<S>(ly, x, rgb);
         }
     }
 
-    fn render_window(&mut self, mmu: &MMU, ly: u8) {
+    fn render_window<S: PixelSink>(&mut self, mmu: &MMU, ly: u8) {
         let io = mmu.get_io();
         let lcdc = io[0x40];
         let wy = io[0x4a];
         let wx = io[0x4b];
-        let bgp = io[0x47];
         let is_cgb = mmu.is_gbc();
 
         if ly < wy {
@@ -254,14 +650,12 @@ impl PPU {
         }
 
         let tile_map_base: u16 = if lcdc & 0x40 != 0 { 0x9c00 } else { 0x9800 };
-        let tile_data_base: u16 = if lcdc & 0x10 != 0 { 0x8000 } else { 0x8800 };
-        let signed_tile_data = (lcdc & 0x10) == 0;
 
         let window_y = ly.wrapping_sub(wy);
         let tile_y = ((window_y >> 3) & 31) as u16;
 
         // Compute window start position (WX-7); if WX>=167, window is off-screen
-        let win_start_x = (wx as i16).wrapping_sub(7) as i16;
+        let win_start_x = (wx as i16).wrapping_sub(7);
         if win_start_x >= SCREEN_WIDTH as i16 { return; }
 
         let start_x = win_start_x.max(0) as usize;
@@ -271,7 +665,7 @@ impl PPU {
             let tile_x = ((window_x >> 3) & 31) as u16;
             let tile_index = tile_y * 32 + tile_x;
 
-            let tile_num = mmu.read_byte(tile_map_base + tile_index);
+            let tile_num = mmu.read_vram_bank_byte(tile_map_base + tile_index, 0);
             let mut vram_bank = 0usize;
             let mut xflip = false;
             let mut yflip = false;
@@ -287,124 +681,95 @@ impl PPU {
 
             let mut tile_line = (window_y & 7) as u16;
             if yflip { tile_line = 7 - tile_line; }
-            let tile_line_addr = (tile_line * 2) as u16;
+            let tile_line_addr = tile_line * 2;
 
-            let base_addr = if signed_tile_data {
-                let offset = (tile_num as i8 as i16 as u16).wrapping_add(128);
-                tile_data_base.wrapping_add(offset * 16)
-            } else {
-                tile_data_base + (tile_num as u16) * 16
-            };
+            let base_addr = tile_data_addr(lcdc, tile_num);
 
-            let (byte1, byte2) = if is_cgb {
-                (
-                    mmu.read_vram_bank_byte(base_addr + tile_line_addr, vram_bank),
-                    mmu.read_vram_bank_byte(base_addr + tile_line_addr + 1, vram_bank),
-                )
-            } else {
-                (
-                    mmu.read_byte(base_addr + tile_line_addr),
-                    mmu.read_byte(base_addr + tile_line_addr + 1),
-                )
-            };
+            let byte1 = mmu.read_vram_bank_byte(base_addr + tile_line_addr, vram_bank);
+            let byte2 = mmu.read_vram_bank_byte(base_addr + tile_line_addr + 1, vram_bank);
 
-            let bit = if xflip { (window_x & 7) } else { 7 - (window_x & 7) };
+            let bit = if xflip { window_x & 7 } else { 7 - (window_x & 7) };
             let color_num = ((byte2 >> bit) & 1) << 1 | ((byte1 >> bit) & 1);
             // Window overwrites BG color index (store raw color number for priority)
             self.bg_color_line[x] = color_num;
+            self.set_raw_index(ly, x, color_num);
             if is_cgb { self.bg_priority_line[x] = (attr & 0x80) != 0; }
             let rgb = if is_cgb {
-                mmu.cgb_get_bg_color_rgb(palette_id, color_num)
+                self.resolve_cgb_bg_color(mmu, palette_id, color_num)
             } else {
-                let mapped = (bgp >> (color_num * 2)) & 0x03;
-                self.get_color(mapped)
+                self.resolve_bg_color(mmu, color_num)
             };
-            self.set_pixel_rgb(ly, x, rgb);
+            self.set_pixel_rgb::<S>(ly, x, rgb);
         }
     }
 
-    fn render_sprites(&mut self, mmu: &MMU, ly: u8) {
+    fn render_sprites<S: PixelSink>(&mut self, mmu: &MMU, ly: u8) {
+        // Lines drawn without a mode 2 (the first line after LCD enable)
+        // scan now
+        if self.line_sprites_ly != Some(ly) {
+            self.scan_oam(mmu, ly);
+        }
         let io = mmu.get_io();
         let lcdc = io[0x40];
         let sprite_height = if lcdc & 0x04 != 0 { 16 } else { 8 };
         let oam = mmu.get_oam();
+        // CGB: with LCDC bit 0 clear, sprites win over BG/window regardless
+        // of either priority bit
+        let bg_master_priority = !mmu.is_gbc() || lcdc & 0x01 != 0;
 
-        // Collect sprites on this line into a small fixed buffer (avoid heap allocs)
-        let mut buf: [(u8, usize); 10] = [(0, 0); 10];
-        let mut count: usize = 0;
-        for i in 0..40 {
-            let sprite_y = oam[i * 4].wrapping_sub(16);
-            if ly >= sprite_y && ly < sprite_y.wrapping_add(sprite_height) {
-                if count < 10 {
-                    buf[count] = (oam[i * 4 + 1], i);
-                    count += 1;
-                } else {
-                    break; // max 10/line
-                }
-            }
-        }
+        let is_cgb = mmu.is_gbc();
 
-        // Sort by X DESC, then index DESC so that lower X (and lower index) draws last (visible)
-        for idx in 1..count {
-            let mut j = idx;
-            while j > 0 {
-                let a = buf[j - 1];
-                let b = buf[j];
-                if a.0 < b.0 || (a.0 == b.0 && a.1 < b.1) {
-                    buf[j - 1] = b;
-                    buf[j] = a;
-                    j -= 1;
-                } else {
-                    break;
-                }
-            }
-        }
+        // The first opaque sprite pixel at an X claims it, even when that
+        // sprite then loses to the BG; lower priority sprites never show there
+        let mut claimed = [false; SCREEN_WIDTH];
 
         // Render sprites
-        for n in 0..count {
-            let i = buf[n].1;
-            let sprite_y = oam[i * 4].wrapping_sub(16);
-            let sprite_x = oam[i * 4 + 1].wrapping_sub(8);
-            let mut tile_num = oam[i * 4 + 2];
+        let sprites = self.line_sprites;
+        for &(x, i, y) in sprites.iter().take(self.line_sprite_count) {
+            // Position as latched by the OAM scan; tile and attributes are
+            // fetched now
+            let sprite_y = y as i16 - 16;
+            let sprite_x = x as i16 - 8;
+            let tile_num = oam[i * 4 + 2];
             let attributes = oam[i * 4 + 3];
 
             let palette = if attributes & 0x10 != 0 { io[0x49] } else { io[0x48] };
-            let is_cgb = mmu.is_gbc();
             let obj_pal_index = attributes & 0x07; // CGB OBJ palette number
             let x_flip = (attributes & 0x20) != 0;
             let y_flip = (attributes & 0x40) != 0;
             let priority = (attributes & 0x80) != 0;
 
-            // 8x16: ignore bit0
-            if sprite_height == 16 {
-                tile_num &= 0xfe;
+            let mut tile_line = (ly as i16 - sprite_y) as u8;
+            // Picked as 8x16 but OBJ size went to 8x8 before mode 3
+            if tile_line >= sprite_height {
+                continue;
             }
-
-            let mut tile_line = ly.wrapping_sub(sprite_y);
             if y_flip {
                 tile_line = sprite_height - 1 - tile_line;
             }
 
-            let tile_addr = 0x8000 + (tile_num as u16) * 16 + (tile_line as u16) * 2;
-            let vram_bank = if is_cgb { ((attributes >> 3) & 1) as usize } else { 0 };
-            let (byte1, byte2) = if is_cgb {
-                (
-                    mmu.read_vram_bank_byte(tile_addr, vram_bank),
-                    mmu.read_vram_bank_byte(tile_addr + 1, vram_bank),
-                )
+            // 8x16: bit 0 of the tile number is ignored; the top half is the
+            // even tile and rows 8-15 (after any flip) come from the odd one
+            let tile_num = if sprite_height == 16 {
+                (tile_num & 0xfe) | (tile_line >= 8) as u8
             } else {
-                (
-                    mmu.read_byte(tile_addr),
-                    mmu.read_byte(tile_addr + 1),
-                )
+                tile_num
             };
+            let tile_addr = 0x8000 + (tile_num as u16) * 16 + ((tile_line & 7) as u16) * 2;
+            // OAM bit 3 selects the VRAM bank on CGB only
+            let vram_bank = if is_cgb { ((attributes >> 3) & 1) as usize } else { 0 };
+            let byte1 = mmu.read_vram_bank_byte(tile_addr, vram_bank);
+            let byte2 = mmu.read_vram_bank_byte(tile_addr + 1, vram_bank);
 
-            for x in 0..8 {
-                let screen_x = sprite_x.wrapping_add(x) as i16;
+            for x in 0..8u8 {
+                let screen_x = sprite_x + x as i16;
                 if screen_x < 0 || screen_x >= SCREEN_WIDTH as i16 {
                     continue;
                 }
                 let screen_x = screen_x as usize;
+                if claimed[screen_x] {
+                    continue;
+                }
 
                 let bit_pos = if x_flip { x } else { 7 - x };
                 let color_num = ((byte2 >> bit_pos) & 1) << 1 | ((byte1 >> bit_pos) & 1);
@@ -413,17 +778,16 @@ impl PPU {
                 if color_num == 0 {
                     continue;
                 }
+                claimed[screen_x] = true;
 
                 // Priority rules
-                // CGB BG priority bit forces BG over OBJ when BG color != 0
-                if is_cgb {
-                    if self.bg_priority_line[screen_x] && self.bg_color_line[screen_x] != 0 {
+                if bg_master_priority && self.bg_color_line[screen_x] != 0 {
+                    // CGB BG priority bit forces BG over OBJ when BG color != 0
+                    if is_cgb && self.bg_priority_line[screen_x] {
                         continue;
                     }
-                }
-                // DMG/OBJ priority bit: when set, OBJ behind BG colors 1-3
-                if priority {
-                    if self.bg_color_line[screen_x] != 0 {
+                    // DMG/OBJ priority bit: when set, OBJ behind BG colors 1-3
+                    if priority {
                         continue;
                     }
                 }
@@ -434,19 +798,30 @@ impl PPU {
                     let color = (palette >> (color_num * 2)) & 0x03;
                     self.get_color(color)
                 };
-                self.set_pixel_rgb(ly, screen_x, rgb);
+                self.set_raw_index(ly, screen_x, color_num);
+                self.set_pixel_rgb::<S>(ly, screen_x, rgb);
             }
         }
     }
 
+    // Displayed RGB for a DMG BG/window color index (0..3) through BGP
+    pub fn resolve_bg_color(&self, mmu: &MMU, index: u8) -> [u8; 3] {
+        let bgp = mmu.get_io()[0x47];
+        self.get_color((bgp >> ((index & 0x03) * 2)) & 0x03)
+    }
+
+    // Displayed RGB for a CGB BG color index in the given BG palette (0..7)
+    pub fn resolve_cgb_bg_color(&self, mmu: &MMU, palette: u8, index: u8) -> [u8; 3] {
+        mmu.cgb_get_bg_color_rgb(palette, index)
+    }
+
     fn get_color(&self, color: u8) -> [u8; 3] {
-        // DMG palette (green shades)
-        match color & 0x03 {
-            0 => [224, 248, 208], // White
-            1 => [136, 192, 112], // Light gray
-            2 => [52, 104, 86],   // Dark gray
-            _ => [8, 24, 32],     // Black
-        }
+        self.dmg_palette[(color & 0x03) as usize]
+    }
+
+    // Colors for DMG shades 0 (lightest) to 3; applies from the next line
+    pub fn set_dmg_palette(&mut self, colors: [[u8; 3]; 4]) {
+        self.dmg_palette = colors;
     }
 
     fn get_ly(&self, mmu: &MMU) -> u8 {
@@ -461,14 +836,8 @@ impl PPU {
         mmu.get_io()[0x41] & 0x03
     }
 
-    fn set_mode(&self, mmu: &mut MMU, mode: u8) {
+    fn set_mode(&mut self, mmu: &mut MMU, mode: u8) {
         let stat = mmu.get_io()[0x41];
-        let stat_interrupt_enabled = if mode != MODE_VBLANK {
-            (stat >> (mode + 3)) & 1
-        } else {
-            0
-        };
-        
         mmu.get_io_mut()[0x41] = (stat & 0xfc) | (mode & 0x03);
 
         // Trigger HDMA chunk on entering HBlank
@@ -476,13 +845,10 @@ impl PPU {
             mmu.hdma_hblank_step();
         }
 
-        // STAT interrupt if enabled
-        if stat_interrupt_enabled != 0 {
-            self.request_interrupt(mmu, 1); // LCD STAT interrupt
-        }
+        self.update_stat_line(mmu);
     }
 
-    fn check_lyc(&self, mmu: &mut MMU) {
+    fn check_lyc(&mut self, mmu: &mut MMU) {
         let ly = mmu.get_io()[0x44];
         let lyc = mmu.get_io()[0x45];
         let stat = mmu.get_io()[0x41];
@@ -490,13 +856,28 @@ impl PPU {
         // LY=LYC flag
         if ly == lyc {
             mmu.get_io_mut()[0x41] = stat | 0x04;
-            // STAT if enabled
-            if stat & 0x40 != 0 {
-                self.request_interrupt(mmu, 1);
-            }
         } else {
             mmu.get_io_mut()[0x41] = stat & 0xfb;
         }
+
+        self.update_stat_line(mmu);
+    }
+
+    // The STAT interrupt fires only on a rising edge of the ORed sources, so
+    // a new source becoming true while another already holds the line high
+    // is "blocked" and raises nothing
+    fn update_stat_line(&mut self, mmu: &mut MMU) {
+        let stat = mmu.get_io()[0x41];
+        let mode = stat & 0x03;
+        let line = (stat & 0x40 != 0 && stat & 0x04 != 0)
+            || (stat & 0x08 != 0 && mode == MODE_HBLANK)
+            || (stat & 0x10 != 0 && mode == MODE_VBLANK)
+            || (stat & 0x20 != 0 && mode == MODE_OAM_SCAN);
+
+        if line && !self.stat_line {
+            self.request_interrupt(mmu, 1); // LCD STAT interrupt
+        }
+        self.stat_line = line;
     }
 
     fn request_interrupt(&self, mmu: &mut MMU, interrupt: u8) {
@@ -504,3 +885,669 @@ impl PPU {
         mmu.write_byte(0xff0f, if_ | (1 << interrupt));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lcd_on() -> (PPU, MMU) {
+        let mut mmu = MMU::new();
+        let mut ppu = PPU::new();
+        ppu.reset(&mut mmu);
+        mmu.get_io_mut()[0x40] = 0x91;
+        (ppu, mmu)
+    }
+
+    #[test]
+    fn step_dot_walks_mode_boundaries() {
+        let (mut ppu, mut mmu) = lcd_on();
+        while ppu.get_ly(&mmu) != 1 {
+            ppu.step_dot(&mut mmu);
+        }
+        assert_eq!(ppu.dot(), 0);
+        assert_eq!(ppu.get_mode(&mmu), MODE_OAM_SCAN);
+        for dot in 1..SCANLINE_CYCLES {
+            ppu.step_dot(&mut mmu);
+            assert_eq!(ppu.dot(), dot);
+            let expected = match dot {
+                0..=79 => MODE_OAM_SCAN,
+                80..=251 => MODE_DRAWING,
+                _ => MODE_HBLANK,
+            };
+            assert_eq!(ppu.get_mode(&mmu), expected, "dot {}", dot);
+            assert_eq!(ppu.get_ly(&mmu), 1);
+            // Pixels start 12 dots into mode 3, one per dot
+            let pixel_x = match dot {
+                80..=91 => Some(0),
+                92..=251 => Some(dot as u8 - 92),
+                _ => None,
+            };
+            assert_eq!(ppu.pixel_x(&mmu), pixel_x, "dot {}", dot);
+        }
+        ppu.step_dot(&mut mmu);
+        assert_eq!(ppu.dot(), 0);
+        assert_eq!(ppu.get_ly(&mmu), 2);
+        assert_eq!(ppu.get_mode(&mmu), MODE_OAM_SCAN);
+    }
+
+    #[test]
+    fn scanline_regs_capture_scx_per_line() {
+        let (mut ppu, mut mmu) = lcd_on();
+        // Raster effect: SCX follows LY through the frame
+        loop {
+            let ly = ppu.get_ly(&mmu);
+            mmu.get_io_mut()[0x43] = ly.wrapping_mul(3);
+            if ppu.step_dot(&mut mmu) {
+                break;
+            }
+        }
+        for (ly, regs) in ppu.scanline_regs().iter().enumerate() {
+            assert_eq!(regs.ly, ly as u8);
+            assert_eq!(regs.scx, (ly as u8).wrapping_mul(3));
+        }
+    }
+
+    #[test]
+    fn resolve_bg_color_dmg_custom_palette() {
+        let (mut ppu, mut mmu) = lcd_on();
+        let shades = [[250, 250, 250], [170, 170, 170], [85, 85, 85], [5, 5, 5]];
+        ppu.set_dmg_palette(shades);
+        // BGP 0x1B reverses the shades: index 0 shows shade 3
+        mmu.write_byte(0xff47, 0x1b);
+        for index in 0..4 {
+            assert_eq!(ppu.resolve_bg_color(&mmu, index), shades[3 - index as usize]);
+        }
+    }
+
+    #[test]
+    fn resolve_bg_color_cgb_palette_ram() {
+        let (ppu, mut mmu) = lcd_on();
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x143] = 0x80;
+        mmu.load_rom(&rom);
+        assert!(mmu.is_gbc());
+        // BG palette 2, colors 0..3 = red, green, blue, white, auto-increment
+        mmu.write_byte(0xff68, 0x80 | (2 * 8));
+        for color in [0x001fu16, 0x03e0, 0x7c00, 0x7fff] {
+            mmu.write_byte(0xff69, color as u8);
+            mmu.write_byte(0xff69, (color >> 8) as u8);
+        }
+        assert_eq!(ppu.resolve_cgb_bg_color(&mmu, 2, 0), [255, 0, 0]);
+        assert_eq!(ppu.resolve_cgb_bg_color(&mmu, 2, 1), [0, 255, 0]);
+        assert_eq!(ppu.resolve_cgb_bg_color(&mmu, 2, 2), [0, 0, 255]);
+        assert_eq!(ppu.resolve_cgb_bg_color(&mmu, 2, 3), [255, 255, 255]);
+        assert_eq!(ppu.resolve_cgb_bg_color(&mmu, 1, 0), [0, 0, 0]);
+    }
+
+    // Advance to the start of VBlank, then record the LY and dot of every
+    // STAT interrupt over the next frame
+    fn stat_interrupts_over_frame(ppu: &mut PPU, mmu: &mut MMU, stat: u8, lyc: u8) -> Vec<(u8, u32)> {
+        while !ppu.step_dot(mmu) {}
+        mmu.write_byte(0xff45, lyc);
+        mmu.write_byte(0xff41, stat);
+        mmu.get_io_mut()[0x0f] = 0;
+        let mut hits = Vec::new();
+        while !ppu.step_dot(mmu) {
+            if mmu.get_io()[0x0f] & 0x02 != 0 {
+                mmu.get_io_mut()[0x0f] &= !0x02;
+                hits.push((ppu.get_ly(mmu), ppu.dot()));
+            }
+        }
+        hits
+    }
+
+    #[test]
+    fn stat_line_blocks_hblank_while_lyc_matches() {
+        let (mut ppu, mut mmu) = lcd_on();
+        // LYC=5 and HBlank sources enabled
+        let hits = stat_interrupts_over_frame(&mut ppu, &mut mmu, 0x48, 5);
+        // One per visible line: the HBlank edge, except on line 5 where LYC
+        // raises the line at the start and holds it through HBlank
+        let lines: Vec<u8> = hits.iter().map(|&(ly, _)| ly).collect();
+        assert_eq!(lines, (0..144).collect::<Vec<u8>>());
+        for &(ly, dot) in &hits {
+            if ly == 5 {
+                assert!(dot < MODE_OAM_CYCLES, "line 5 fired at dot {}", dot);
+            } else {
+                assert!(dot >= MODE_OAM_CYCLES + MODE_DRAWING_CYCLES, "line {} fired at dot {}", ly, dot);
+            }
+        }
+    }
+
+    #[test]
+    fn signed_tile_data_addressing() {
+        // LCDC bit 4 clear: signed numbers around 0x9000
+        assert_eq!(tile_data_addr(0x81, 0x00), 0x9000);
+        assert_eq!(tile_data_addr(0x81, 0x7f), 0x97f0);
+        assert_eq!(tile_data_addr(0x81, 0x80), 0x8800);
+        assert_eq!(tile_data_addr(0x81, 0xff), 0x8ff0);
+        // LCDC bit 4 set: unsigned from 0x8000
+        assert_eq!(tile_data_addr(0x91, 0x00), 0x8000);
+        assert_eq!(tile_data_addr(0x91, 0x7f), 0x87f0);
+        assert_eq!(tile_data_addr(0x91, 0x80), 0x8800);
+        assert_eq!(tile_data_addr(0x91, 0xff), 0x8ff0);
+    }
+
+    // Tile 0 rows read as color indices 0, 1, 2, 3 repeating
+    fn fill_tile0_index_ramp(mmu: &mut MMU) {
+        for row in 0..8 {
+            mmu.write_byte(0x8000 + row * 2, 0x55);
+            mmu.write_byte(0x8001 + row * 2, 0x33);
+        }
+    }
+
+    #[test]
+    fn raw_index_buffer_ignores_bgp() {
+        let (mut ppu, mut mmu) = lcd_on();
+        fill_tile0_index_ramp(&mut mmu);
+        ppu.set_raw_index_enabled(true);
+        let ramp: Vec<u8> = (0..SCREEN_WIDTH).map(|x| x as u8 & 3).collect();
+        let mut lines = Vec::new();
+        for bgp in [0xe4, 0x1b] {
+            mmu.write_byte(0xff47, bgp);
+            ppu.render_line_for_test(&mut mmu, 0);
+            assert_eq!(&ppu.get_raw_index_buffer()[..SCREEN_WIDTH], &ramp[..]);
+            lines.push(ppu.get_frame_buffer()[..SCREEN_WIDTH * 4].to_vec());
+        }
+        assert_ne!(lines[0], lines[1]);
+    }
+
+    // Set one BGR555 color in CGB BG or OBJ palette RAM through BCPS/BCPD
+    // or OCPS/OCPD
+    fn write_cgb_color(mmu: &mut MMU, obj: bool, palette: u8, index: u8, color: u16) {
+        let spec = if obj { 0xff6a } else { 0xff68 };
+        mmu.write_byte(spec, 0x80 | (palette * 8 + index * 2));
+        mmu.write_byte(spec + 1, color as u8);
+        mmu.write_byte(spec + 1, (color >> 8) as u8);
+    }
+
+    // CGB machine with LCD, BG and sprites on; BG palette 0 color 3 blue,
+    // OBJ palette 0 color 1 red, BG tile 0 solid color 3, sprite tile 1
+    // solid color 1 and sprite 0 at the top-left corner
+    fn cgb_sprite_over_bg() -> (PPU, MMU) {
+        let (ppu, mut mmu) = lcd_on();
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x143] = 0x80;
+        mmu.load_rom(&rom);
+        mmu.write_byte(0xff40, 0x93);
+        write_cgb_color(&mut mmu, false, 0, 3, 0x7c00);
+        write_cgb_color(&mut mmu, true, 0, 1, 0x001f);
+        for row in 0..8 {
+            mmu.write_byte(0x8000 + row * 2, 0xff);
+            mmu.write_byte(0x8001 + row * 2, 0xff);
+            mmu.write_byte(0x8010 + row * 2, 0xff);
+            mmu.write_byte(0x8011 + row * 2, 0x00);
+        }
+        let mut oam = [0u8; 0xa0];
+        oam[..4].copy_from_slice(&[16, 8, 1, 0x00]);
+        mmu.set_oam(&oam);
+        (ppu, mmu)
+    }
+
+    fn pixel(ppu: &PPU, x: usize, y: usize) -> [u8; 3] {
+        let i = (y * SCREEN_WIDTH + x) * 4;
+        let fb = ppu.get_frame_buffer();
+        [fb[i], fb[i + 1], fb[i + 2]]
+    }
+
+    #[test]
+    fn cgb_bg_priority_attribute_beats_sprite() {
+        for (attr, expected) in [(0x00, [255, 0, 0]), (0x80, [0, 0, 255])] {
+            let (mut ppu, mut mmu) = cgb_sprite_over_bg();
+            mmu.write_byte(0xff4f, 1);
+            mmu.write_byte(0x9800, attr);
+            mmu.write_byte(0xff4f, 0);
+            ppu.render_line_for_test(&mut mmu, 0);
+            assert_eq!(pixel(&ppu, 0, 0), expected, "attr {:02x}", attr);
+        }
+    }
+
+    #[test]
+    fn frame_dirty_only_when_a_pixel_changes() {
+        let (mut ppu, mut mmu) = lcd_on();
+        fill_tile0_index_ramp(&mut mmu);
+        ppu.render_line_for_test(&mut mmu, 0);
+        ppu.clear_frame_dirty();
+        ppu.render_line_for_test(&mut mmu, 0);
+        assert!(!ppu.frame_dirty());
+        mmu.write_byte(0xff47, 0x1b);
+        ppu.render_line_for_test(&mut mmu, 0);
+        assert!(ppu.frame_dirty());
+    }
+
+    #[test]
+    fn dmg_sprite_priority_against_bg_index() {
+        // BG columns 0-3 hold indices 0-3; the sprite is solid index 1,
+        // which OBP0 maps to the darkest shade
+        let render = |attr: u8| {
+            let (mut ppu, mut mmu) = lcd_on();
+            fill_tile0_index_ramp(&mut mmu);
+            for row in 0..8 {
+                mmu.write_byte(0x8010 + row * 2, 0xff);
+                mmu.write_byte(0x8011 + row * 2, 0x00);
+            }
+            mmu.write_byte(0xff40, 0x93);
+            mmu.write_byte(0xff47, 0xe4);
+            mmu.write_byte(0xff48, 0xff);
+            let mut oam = [0u8; 0xa0];
+            oam[..4].copy_from_slice(&[16, 8, 1, attr]);
+            mmu.set_oam(&oam);
+            ppu.render_line_for_test(&mut mmu, 0);
+            [pixel(&ppu, 0, 0), pixel(&ppu, 2, 0)]
+        };
+        let (mut ppu, mut mmu) = lcd_on();
+        fill_tile0_index_ramp(&mut mmu);
+        mmu.write_byte(0xff47, 0xe4);
+        ppu.render_line_for_test(&mut mmu, 0);
+        let bg_index_2 = pixel(&ppu, 2, 0);
+        let black = pixel(&ppu, 3, 0);
+
+        // Behind BG: shows over index 0 only
+        assert_eq!(render(0x80), [black, bg_index_2]);
+        // Above BG: shows over any index
+        assert_eq!(render(0x00), [black, black]);
+    }
+
+    #[test]
+    fn sprites_transparency_limit_and_x_order() {
+        // The four DMG shades under BGP 0xE4
+        let (mut ppu, mut mmu) = lcd_on();
+        fill_tile0_index_ramp(&mut mmu);
+        mmu.write_byte(0xff47, 0xe4);
+        ppu.render_line_for_test(&mut mmu, 0);
+        let shade: Vec<[u8; 3]> = (0..4).map(|x| pixel(&ppu, x, 0)).collect();
+
+        let (mut ppu, mut mmu) = lcd_on();
+        for row in 0..8 {
+            // Tile 1: columns 0-3 index 0, 4-7 index 1. Tile 2: all index 2
+            mmu.write_byte(0x8010 + row * 2, 0x0f);
+            mmu.write_byte(0x8021 + row * 2, 0xff);
+        }
+        mmu.write_byte(0xff40, 0x93);
+        mmu.write_byte(0xff47, 0xe4);
+        mmu.write_byte(0xff48, 0xe4);
+        mmu.write_byte(0xff49, 0xff);
+        let mut oam = [0u8; 0xa0];
+        // Overlapping pair: the lower X wins even though it comes later in OAM
+        oam[0..4].copy_from_slice(&[16, 148, 2, 0x10]);
+        oam[4..8].copy_from_slice(&[16, 144, 2, 0x00]);
+        // Tile 1 at x=0 shows the BG through its index 0 half
+        oam[8..12].copy_from_slice(&[16, 8, 1, 0x00]);
+        // Seven more fill the line's 10 slots; the eighth is dropped
+        for i in 0..8 {
+            let x = 28 + 10 * i as u8;
+            oam[12 + i * 4..16 + i * 4].copy_from_slice(&[16, x, 2, 0x00]);
+        }
+        mmu.set_oam(&oam);
+        ppu.render_line_for_test(&mut mmu, 0);
+
+        assert_eq!(pixel(&ppu, 0, 0), shade[0]);
+        assert_eq!(pixel(&ppu, 4, 0), shade[1]);
+        assert_eq!(pixel(&ppu, 137, 0), shade[2]);
+        assert_eq!(pixel(&ppu, 141, 0), shade[2]);
+        assert_eq!(pixel(&ppu, 145, 0), shade[3]);
+        assert_eq!(pixel(&ppu, 85, 0), shade[2]);
+        assert_eq!(pixel(&ppu, 93, 0), shade[0]);
+    }
+
+    #[test]
+    fn dmg_ignores_vbk_and_renders_bank_0() {
+        let (mut ppu, mut mmu) = lcd_on();
+        fill_tile0_index_ramp(&mut mmu);
+        mmu.write_byte(0xff47, 0xe4);
+        ppu.render_line_for_test(&mut mmu, 0);
+        let expected = ppu.get_frame_buffer()[..SCREEN_WIDTH * 4].to_vec();
+
+        let (mut ppu, mut mmu) = lcd_on();
+        mmu.write_byte(0xff47, 0xe4);
+        // VBK does not exist on DMG: tile data written after the poke and
+        // a would-be attribute byte both land in the one bank
+        mmu.cpu_write(0xff4f, 0x01);
+        fill_tile0_index_ramp(&mut mmu);
+        mmu.write_byte(0x9800, 0x00);
+        assert_eq!(mmu.get_vram().len(), 0x2000);
+        assert_eq!(mmu.get_vram()[0x0000], 0x55);
+        ppu.render_line_for_test(&mut mmu, 0);
+        assert_eq!(&ppu.get_frame_buffer()[..SCREEN_WIDTH * 4], &expected[..]);
+    }
+
+    #[test]
+    fn cgb_tile_attributes_pick_palette_bank_and_flip() {
+        let (mut ppu, mut mmu) = lcd_on();
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x143] = 0x80;
+        mmu.load_rom(&rom);
+        mmu.write_byte(0xff40, 0x91);
+        write_cgb_color(&mut mmu, false, 0, 0, 0x7c00);
+        write_cgb_color(&mut mmu, false, 2, 0, 0x03e0);
+        write_cgb_color(&mut mmu, false, 2, 1, 0x001f);
+        // Bank 1 tile 0: only column 0 is index 1. Bank 0 tile 0 stays blank
+        mmu.write_byte(0xff4f, 1);
+        for row in 0..8 {
+            mmu.write_byte(0x8000 + row * 2, 0x80);
+        }
+        // First map entry: palette 2, tile data from bank 1, X flip
+        mmu.write_byte(0x9800, 0x2a);
+        mmu.write_byte(0xff4f, 0);
+        ppu.render_line_for_test(&mut mmu, 0);
+        assert_eq!(pixel(&ppu, 0, 0), [0, 255, 0]);
+        assert_eq!(pixel(&ppu, 7, 0), [255, 0, 0]);
+        assert_eq!(pixel(&ppu, 8, 0), [0, 0, 255]);
+    }
+
+    #[test]
+    fn cgb_sprite_uses_obj_palette_3_from_bank_1() {
+        let (mut ppu, mut mmu) = lcd_on();
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x143] = 0x80;
+        mmu.load_rom(&rom);
+        mmu.write_byte(0xff40, 0x93);
+        write_cgb_color(&mut mmu, true, 0, 1, 0x7c00);
+        write_cgb_color(&mut mmu, true, 3, 1, 0x03e0);
+        write_cgb_color(&mut mmu, true, 3, 2, 0x001f);
+        // Tile 1: index 1 in bank 0, index 2 in bank 1
+        for row in 0..8 {
+            mmu.write_byte(0x8010 + row * 2, 0xff);
+        }
+        mmu.write_byte(0xff4f, 1);
+        for row in 0..8 {
+            mmu.write_byte(0x8011 + row * 2, 0xff);
+        }
+        mmu.write_byte(0xff4f, 0);
+        // Palette 3 from bank 1, palette 3 from bank 0, palette 0 from bank 0
+        let mut oam = [0u8; 0xa0];
+        oam[..4].copy_from_slice(&[16, 8, 1, 0x0b]);
+        oam[4..8].copy_from_slice(&[16, 16, 1, 0x03]);
+        oam[8..12].copy_from_slice(&[16, 24, 1, 0x00]);
+        mmu.set_oam(&oam);
+        ppu.render_line_for_test(&mut mmu, 0);
+        assert_eq!(pixel(&ppu, 0, 0), [255, 0, 0]);
+        assert_eq!(pixel(&ppu, 8, 0), [0, 255, 0]);
+        assert_eq!(pixel(&ppu, 16, 0), [0, 0, 255]);
+    }
+
+    #[test]
+    fn lcd_off_resets_ly_and_mode() {
+        let (mut ppu, mut mmu) = lcd_on();
+        while ppu.get_ly(&mmu) != 50 {
+            ppu.step(&mut mmu, 4);
+        }
+        ppu.step(&mut mmu, 100);
+        assert_ne!(mmu.read_byte(0xff41) & 0x03, 0);
+        mmu.write_byte(0xff40, 0x11);
+        ppu.step(&mut mmu, 4);
+        assert_eq!(ppu.get_ly(&mmu), 0);
+        assert_eq!(mmu.read_byte(0xff41) & 0x03, 0);
+        // Stays there while off, and restarts from line 0 when turned on
+        ppu.step(&mut mmu, 10_000);
+        assert_eq!(ppu.get_ly(&mmu), 0);
+        // That first line is 4 dots short
+        mmu.write_byte(0xff40, 0x91);
+        ppu.step(&mut mmu, 451);
+        assert_eq!(ppu.get_ly(&mmu), 0);
+        ppu.step(&mut mmu, 1);
+        assert_eq!(ppu.get_ly(&mmu), 1);
+        // The next one is full length
+        ppu.step(&mut mmu, 455);
+        assert_eq!(ppu.get_ly(&mmu), 1);
+        ppu.step(&mut mmu, 1);
+        assert_eq!(ppu.get_ly(&mmu), 2);
+    }
+
+    #[test]
+    fn stat_oam_and_lyc_sources_fire_once_per_line() {
+        let (mut ppu, mut mmu) = lcd_on();
+        // LYC=5 and OAM sources enabled; both rise together on line 5
+        let hits = stat_interrupts_over_frame(&mut ppu, &mut mmu, 0x60, 5);
+        let lines: Vec<u8> = hits.iter().map(|&(ly, _)| ly).collect();
+        assert_eq!(lines, (0..144).collect::<Vec<u8>>());
+        assert!(hits.iter().all(|&(_, dot)| dot < MODE_OAM_CYCLES));
+    }
+
+    // Run a frame with `oam`, solid index 3 tiles 2 and 3, and LCDC switched
+    // in line 71's HBlank; returns which lines show a sprite pixel at x=0
+    fn sprite_lines_with_lcdc_change(oam: &[u8; 0xa0], lcdc_before: u8, lcdc_after: u8) -> Vec<u8> {
+        let (mut ppu, mut mmu) = lcd_on();
+        for i in 0..32 {
+            mmu.write_byte(0x8020 + i, 0xff);
+        }
+        mmu.write_byte(0xff47, 0xe4);
+        mmu.write_byte(0xff48, 0xe4);
+        mmu.write_byte(0xff40, lcdc_before);
+        mmu.set_oam(oam);
+        while ppu.get_ly(&mmu) != 71 || mmu.read_byte(0xff41) & 0x03 != 0 {
+            ppu.step(&mut mmu, 4);
+        }
+        mmu.write_byte(0xff40, lcdc_after);
+        while !ppu.step(&mut mmu, 4) {}
+        let bg = pixel(&ppu, 100, 0);
+        (0..144).filter(|&y| pixel(&ppu, 0, y as usize) != bg).collect()
+    }
+
+    #[test]
+    fn lcdc_sprite_bits_apply_from_the_next_line() {
+        // A column of 8x8 sprites down the left edge; OBJ off from line 72
+        let mut oam = [0u8; 0xa0];
+        for k in 0..18 {
+            oam[k * 4..k * 4 + 4].copy_from_slice(&[16 + 8 * k as u8, 8, 2, 0]);
+        }
+        assert_eq!(sprite_lines_with_lcdc_change(&oam, 0x93, 0x91), (0..72).collect::<Vec<u8>>());
+
+        // One sprite on lines 64-71 grows to 64-79 when 8x16 is selected
+        let mut oam = [0u8; 0xa0];
+        oam[..4].copy_from_slice(&[80, 8, 2, 0]);
+        assert_eq!(sprite_lines_with_lcdc_change(&oam, 0x93, 0x97), (64..80).collect::<Vec<u8>>());
+        assert_eq!(sprite_lines_with_lcdc_change(&oam, 0x97, 0x93), (64..72).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn hblank_and_oam_stat_counts_per_model() {
+        let per_line = |cgb: bool| {
+            let (mut ppu, mut mmu) = lcd_on();
+            if cgb {
+                let mut rom = vec![0u8; 0x8000];
+                rom[0x143] = 0x80;
+                mmu.load_rom(&rom);
+                mmu.write_byte(0xff40, 0x91);
+            }
+            let hits = stat_interrupts_over_frame(&mut ppu, &mut mmu, 0x28, 0);
+            let mut counts = [0u8; 144];
+            for (ly, _) in hits {
+                counts[ly as usize] += 1;
+            }
+            counts
+        };
+        // DMG: the STAT line drops as LY changes, so mode 2 fires after
+        // every HBlank. CGB: HBlank holds it high through the next OAM scan
+        assert!(per_line(false).iter().all(|&n| n == 2));
+        let cgb = per_line(true);
+        assert_eq!(cgb[0], 2);
+        assert!(cgb[1..].iter().all(|&n| n == 1));
+    }
+
+    #[test]
+    fn custom_dmg_palette_colors_pixels() {
+        let (mut ppu, mut mmu) = lcd_on();
+        let gray = [[255, 255, 255], [170, 170, 170], [85, 85, 85], [0, 0, 0]];
+        ppu.set_dmg_palette(gray);
+        fill_tile0_index_ramp(&mut mmu);
+        mmu.write_byte(0xff47, 0xe4);
+        ppu.render_line_for_test(&mut mmu, 0);
+        assert_eq!((0..4).map(|x| pixel(&ppu, x, 0)).collect::<Vec<_>>(), gray);
+        // BGP still picks the shade
+        mmu.write_byte(0xff47, 0x1b);
+        ppu.render_line_for_test(&mut mmu, 0);
+        assert_eq!(pixel(&ppu, 0, 0), gray[3]);
+    }
+
+    #[test]
+    fn frame_ascii_of_solid_frames() {
+        let ascii_with_bgp = |bgp: u8| {
+            let (mut ppu, mut mmu) = lcd_on();
+            mmu.write_byte(0xff47, bgp);
+            for ly in 0..SCREEN_HEIGHT as u8 {
+                ppu.render_line_for_test(&mut mmu, ly);
+            }
+            ppu.frame_ascii()
+        };
+        let row = |c: char| format!("{}\n", c.to_string().repeat(80));
+        assert_eq!(ascii_with_bgp(0x00), row(' ').repeat(36));
+        assert_eq!(ascii_with_bgp(0xff), row('#').repeat(36));
+    }
+
+    #[test]
+    fn first_line_after_lcd_on_timing() {
+        let (mut ppu, mut mmu) = lcd_on();
+        mmu.write_byte(0xff45, 0x01);
+        ppu.step_dot(&mut mmu);
+        assert_eq!(mmu.read_byte(0xff41) & 0x04, 0);
+        mmu.write_byte(0xff40, 0x11);
+        ppu.step_dot(&mut mmu);
+        mmu.write_byte(0xff45, 0x00);
+        mmu.write_byte(0xff40, 0x91);
+        let stat = |mmu: &MMU| mmu.read_byte(0xff41);
+        // As lcdon_timing expects: no LY=LYC for the first dots, and STAT
+        // reads mode 0 where line 0 would normally scan OAM
+        ppu.step_dot(&mut mmu);
+        assert_eq!(stat(&mmu) & 0x07, 0x00);
+        for _ in 1..8 {
+            ppu.step_dot(&mut mmu);
+        }
+        assert_eq!(stat(&mmu) & 0x07, 0x04);
+        while ppu.dot() < MODE_OAM_CYCLES - 1 {
+            ppu.step_dot(&mut mmu);
+        }
+        assert_eq!(stat(&mmu) & 0x03, 0);
+        ppu.step_dot(&mut mmu);
+        assert_eq!(stat(&mmu) & 0x03, 3);
+        // Line 1 is a normal one
+        while ppu.get_ly(&mmu) != 1 {
+            ppu.step_dot(&mut mmu);
+        }
+        ppu.step_dot(&mut mmu);
+        assert_eq!(stat(&mmu) & 0x03, 2);
+    }
+
+    #[test]
+    fn cgb_8x16_sprite_reads_both_halves_from_bank_1() {
+        let (mut ppu, mut mmu) = cgb_sprite_over_bg();
+        mmu.write_byte(0xff40, 0x97);
+        write_cgb_color(&mut mmu, true, 0, 2, 0x03e0);
+        // Bank 1 tile 2 solid index 1, tile 3 solid index 2; bank 0 blank
+        mmu.write_byte(0xff4f, 1);
+        for row in 0..8 {
+            mmu.write_byte(0x8020 + row * 2, 0xff);
+            mmu.write_byte(0x8031 + row * 2, 0xff);
+        }
+        mmu.write_byte(0xff4f, 0);
+        // Odd tile number: bit 0 is ignored in 8x16 mode
+        let mut oam = [0u8; 0xa0];
+        oam[..4].copy_from_slice(&[16, 8, 3, 0x08]);
+        mmu.set_oam(&oam);
+        ppu.render_line_for_test(&mut mmu, 0);
+        ppu.render_line_for_test(&mut mmu, 8);
+        assert_eq!(pixel(&ppu, 0, 0), [255, 0, 0]);
+        assert_eq!(pixel(&ppu, 0, 8), [0, 255, 0]);
+    }
+
+    #[test]
+    fn render_line_for_test_draws_the_tilemap() {
+        let (mut ppu, mut mmu) = lcd_on();
+        mmu.write_byte(0xff47, 0xe4);
+        // Tile 1 row 0 solid color 3, placed second in the first map row
+        mmu.write_byte(0x8010, 0xff);
+        mmu.write_byte(0x8011, 0xff);
+        mmu.write_byte(0x9801, 0x01);
+        ppu.render_line_for_test(&mut mmu, 0);
+        assert_eq!(ppu.get_ly(&mmu), 0);
+        for x in 0..24 {
+            let expected = if (8..16).contains(&x) { DEFAULT_DMG_PALETTE[3] } else { DEFAULT_DMG_PALETTE[0] };
+            assert_eq!(pixel(&ppu, x, 0), expected, "x {}", x);
+        }
+    }
+
+    #[test]
+    fn oam_writes_after_the_scan_keep_the_line_sprites() {
+        let (mut ppu, mut mmu) = lcd_on();
+        mmu.write_byte(0xff40, 0x93);
+        mmu.write_byte(0xff47, 0xe4);
+        mmu.write_byte(0xff48, 0xe4);
+        for row in 0..8 {
+            mmu.write_byte(0x8010 + row * 2, 0xff);
+            mmu.write_byte(0x8011 + row * 2, 0xff);
+        }
+        // Sprite 0 on lines 1-8 at the left edge
+        let mut oam = [0u8; 0xa0];
+        oam[..4].copy_from_slice(&[17, 8, 1, 0x00]);
+        mmu.set_oam(&oam);
+        while !(ppu.get_ly(&mmu) == 1 && ppu.dot() == MODE_OAM_CYCLES - 1) {
+            ppu.step_dot(&mut mmu);
+        }
+        // Scan done; move the sprite off the line before mode 3 draws it
+        oam[0] = 0;
+        mmu.set_oam(&oam);
+        while ppu.get_mode(&mmu) != MODE_HBLANK {
+            ppu.step_dot(&mut mmu);
+        }
+        assert_eq!(pixel(&ppu, 0, 1), DEFAULT_DMG_PALETTE[3]);
+        // The next line scans the new OAM
+        while ppu.get_ly(&mmu) != 3 {
+            ppu.step_dot(&mut mmu);
+        }
+        assert_eq!(pixel(&ppu, 0, 2), DEFAULT_DMG_PALETTE[0]);
+    }
+
+    #[test]
+    fn each_sink_writes_its_own_layout() {
+        // Shades 0-3 from the index ramp under BGP 0xE4
+        for (format, expected) in [
+            (FrameBufferFormat::Rgba, DEFAULT_DMG_PALETTE.iter().flat_map(|c| [c[0], c[1], c[2], 255]).collect::<Vec<u8>>()),
+            (FrameBufferFormat::Rgb, DEFAULT_DMG_PALETTE.iter().flatten().copied().collect()),
+        ] {
+            let (mut ppu, mut mmu) = lcd_on();
+            mmu.write_byte(0xff47, 0xe4);
+            fill_tile0_index_ramp(&mut mmu);
+            ppu.set_frame_buffer_format(format);
+            ppu.render_line_for_test(&mut mmu, 0);
+            let bpp = format.bytes_per_pixel();
+            let fb = ppu.get_frame_buffer();
+            assert_eq!(fb.len(), SCREEN_WIDTH * SCREEN_HEIGHT * bpp);
+            assert_eq!(&fb[..4 * bpp], &expected[..], "{:?}", format);
+            // The line repeats the ramp every four pixels
+            assert_eq!(&fb[4 * bpp..8 * bpp], &expected[..], "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn rgb_and_rgba_frames_hold_the_same_colors() {
+        let render = |format| {
+            let (mut ppu, mut mmu) = cgb_sprite_over_bg();
+            // Window from line 40 showing the index ramp in BG palette 0
+            mmu.write_byte(0xff40, 0xf3);
+            mmu.write_byte(0xff4a, 40);
+            mmu.write_byte(0xff4b, 7);
+            mmu.write_byte(0x9c00, 0x02);
+            for row in 0..8 {
+                mmu.write_byte(0x8020 + row * 2, 0x55);
+                mmu.write_byte(0x8021 + row * 2, 0x33);
+            }
+            for (idx, color) in [(0, 0x7fff), (1, 0x7c1f), (2, 0x03e0)] {
+                write_cgb_color(&mut mmu, false, 0, idx, color);
+            }
+            ppu.set_frame_buffer_format(format);
+            for ly in 0..SCREEN_HEIGHT as u8 {
+                ppu.render_line_for_test(&mut mmu, ly);
+            }
+            ppu.get_frame_buffer().to_vec()
+        };
+        let rgba = render(FrameBufferFormat::Rgba);
+        let rgb = render(FrameBufferFormat::Rgb);
+        assert_eq!(rgb.len() * 4, rgba.len() * 3);
+        // Sprite, background and all four window colors
+        let colors: std::collections::HashSet<&[u8]> = rgb.chunks_exact(3).collect();
+        assert!(colors.len() >= 5, "{:?}", colors);
+        for (i, (a, b)) in rgba.chunks_exact(4).zip(rgb.chunks_exact(3)).enumerate() {
+            assert_eq!((&a[..3], a[3]), (b, 255), "pixel {}", i);
+        }
+    }
+}
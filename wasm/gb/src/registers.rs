@@ -29,6 +29,11 @@ impl Registers {
         }
     }
 
+    // Power-on state for running a boot ROM from 0x0000
+    pub fn zeroed() -> Self {
+        Registers { a: 0, b: 0, c: 0, d: 0, e: 0, h: 0, l: 0, f: 0, sp: 0, pc: 0 }
+    }
+
     // Flags
     pub fn flag_z(&self) -> bool { self.f & 0x80 != 0 }
     pub fn set_flag_z(&mut self, v: bool) { self.f = if v { self.f | 0x80 } else { self.f & 0x7f }; }
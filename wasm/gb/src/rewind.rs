@@ -0,0 +1,150 @@
+use std::collections::VecDeque;
+
+// Rewind buffer: a binary save state every `interval` frames, at most
+// `capacity` of them, dropping the oldest.
+//
+// Only the newest snapshot is kept whole. Each older one is stored as the
+// XOR against the snapshot taken after it, run-length encoded: consecutive
+// states differ in a few hundred bytes, so a delta is a small fraction of a
+// full state. Deltas point backwards in time, so dropping the oldest never
+// breaks the chain
+pub struct Rewind {
+    interval: u32,
+    capacity: usize,
+    // Frames until the next snapshot
+    countdown: u32,
+    // Newest snapshot and the frame it was taken at
+    latest: Option<(u64, Vec<u8>)>,
+    // Older snapshots, newest first: (frame, delta to the next newer one)
+    deltas: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl Rewind {
+    pub fn new() -> Self {
+        Rewind { interval: 0, capacity: 0, countdown: 0, latest: None, deltas: VecDeque::new() }
+    }
+
+    // Interval 0 or capacity 0 turns rewind off; either way the buffer
+    // starts over
+    pub fn configure(&mut self, interval: u32, capacity: usize) {
+        self.interval = interval;
+        self.capacity = capacity;
+        self.clear();
+    }
+
+    pub fn clear(&mut self) {
+        self.countdown = self.interval;
+        self.latest = None;
+        self.deltas.clear();
+    }
+
+    // Count a finished frame; true when a snapshot is due
+    pub fn frame_done(&mut self) -> bool {
+        if self.interval == 0 || self.capacity == 0 {
+            return false;
+        }
+        self.countdown = self.countdown.saturating_sub(1);
+        if self.countdown > 0 {
+            return false;
+        }
+        self.countdown = self.interval;
+        true
+    }
+
+    pub fn push(&mut self, frame: u64, state: Vec<u8>) {
+        if let Some((prev_frame, prev)) = self.latest.take() {
+            self.deltas.push_front((prev_frame, encode_delta(&state, &prev)));
+        }
+        self.latest = Some((frame, state));
+        // The newest snapshot counts toward the capacity too
+        self.deltas.truncate(self.capacity.saturating_sub(1));
+    }
+
+    pub fn len(&self) -> usize {
+        self.latest.is_some() as usize + self.deltas.len()
+    }
+
+    // Remove and return the newest snapshot taken at or before `frame`,
+    // discarding everything newer; the oldest one if none is that old.
+    // None when the buffer is empty
+    pub fn pop_at_or_before(&mut self, frame: u64) -> Option<Vec<u8>> {
+        let (mut at, mut state) = self.latest.take()?;
+        while at > frame {
+            let Some((older_frame, delta)) = self.deltas.pop_front() else { break };
+            state = apply_delta(&state, &delta);
+            at = older_frame;
+        }
+        // What is left of the buffer now hangs off the restored snapshot
+        if let Some((older_frame, delta)) = self.deltas.pop_front() {
+            let older = apply_delta(&state, &delta);
+            self.latest = Some((older_frame, older));
+        }
+        self.countdown = self.interval;
+        Some(state)
+    }
+}
+
+// Delta from `base` to `target`: target length (u32 LE), then runs of
+// (zero count, literal count, literal bytes) over target XOR base, counts
+// as LEB128. Bytes past the end of `base` XOR with zero
+fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let xor = |i: usize| target[i] ^ base.get(i).copied().unwrap_or(0);
+    let mut out = Vec::new();
+    out.extend_from_slice(&(target.len() as u32).to_le_bytes());
+    let mut i = 0;
+    while i < target.len() {
+        let zeros_start = i;
+        while i < target.len() && xor(i) == 0 {
+            i += 1;
+        }
+        let literal_start = i;
+        // A literal run ends at the first pair of zero bytes; a lone zero
+        // costs less inline than a new run header
+        while i < target.len() && !(xor(i) == 0 && (i + 1 >= target.len() || xor(i + 1) == 0)) {
+            i += 1;
+        }
+        push_varint(&mut out, literal_start - zeros_start);
+        push_varint(&mut out, i - literal_start);
+        out.extend((literal_start..i).map(xor));
+    }
+    out
+}
+
+fn apply_delta(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let len = u32::from_le_bytes([delta[0], delta[1], delta[2], delta[3]]) as usize;
+    let mut out: Vec<u8> = (0..len).map(|i| base.get(i).copied().unwrap_or(0)).collect();
+    let mut pos = 4;
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_varint(delta, &mut pos);
+        let literals = read_varint(delta, &mut pos);
+        for &byte in &delta[pos..pos + literals] {
+            out[i] ^= byte;
+            i += 1;
+        }
+        pos += literals;
+    }
+    out
+}
+
+fn push_varint(out: &mut Vec<u8>, mut val: usize) {
+    while val >= 0x80 {
+        out.push(val as u8 | 0x80);
+        val >>= 7;
+    }
+    out.push(val as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut val = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        val |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return val;
+        }
+        shift += 7;
+    }
+}
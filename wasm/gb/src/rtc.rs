@@ -0,0 +1,194 @@
+use serde::{Deserialize, Serialize};
+
+// MBC3 real-time clock
+//
+// Registers 0x08-0x0C as the cartridge maps them at 0xA000 once selected:
+// seconds, minutes, hours, day counter low byte, and day bit 8 / halt (bit 6)
+// / day carry (bit 7). Reads see the latched copy; writes go to the live clock
+const CPU_CLOCK: u32 = 4_194_304;
+
+// Save blob: "RTC", format version, live registers, latched registers,
+// sub-second cycles (u32 LE), last wall-clock sync (u64 LE, MAX = never)
+const SNAPSHOT_MAGIC: &[u8; 3] = b"RTC";
+const SNAPSHOT_VERSION: u8 = 1;
+pub const SNAPSHOT_LEN: usize = 3 + 1 + 5 + 5 + 4 + 8;
+
+#[derive(Serialize, Deserialize)]
+pub struct Rtc {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16,
+    halt: bool,
+    carry: bool,
+    // T-cycles toward the next second
+    sub_second: u32,
+    latched: [u8; 5],
+    // Last write to 0x6000-0x7FFF was 0x00; a following 0x01 latches
+    latch_armed: bool,
+    // Wall time of the last set_now and emulated seconds counted since, so
+    // a later sync only adds the time the emulator was not running
+    synced_at: Option<u64>,
+    seconds_since_sync: u64,
+}
+
+impl Rtc {
+    pub fn new() -> Self {
+        Rtc {
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days: 0,
+            halt: false,
+            carry: false,
+            sub_second: 0,
+            latched: [0; 5],
+            latch_armed: false,
+            synced_at: None,
+            seconds_since_sync: 0,
+        }
+    }
+
+    // Emulated time; called from the main loop with CPU cycles
+    pub fn step(&mut self, cycles: u32) {
+        if self.halt {
+            return;
+        }
+        self.sub_second += cycles;
+        while self.sub_second >= CPU_CLOCK {
+            self.sub_second -= CPU_CLOCK;
+            self.seconds_since_sync += 1;
+            self.advance(1);
+        }
+    }
+
+    // Catch the clock up with wall time (unix seconds). The first call only
+    // records the reference point; later calls add whatever elapsed beyond
+    // what emulation already counted
+    pub fn set_now(&mut self, unix_secs: u64) {
+        if let Some(prev) = self.synced_at {
+            let missed = unix_secs.saturating_sub(prev).saturating_sub(self.seconds_since_sync);
+            if !self.halt {
+                self.advance(missed);
+            }
+        }
+        self.synced_at = Some(unix_secs);
+        self.seconds_since_sync = 0;
+    }
+
+    fn advance(&mut self, secs: u64) {
+        let total = self.seconds as u64 + secs;
+        self.seconds = (total % 60) as u8;
+        let total = self.minutes as u64 + total / 60;
+        self.minutes = (total % 60) as u8;
+        let total = self.hours as u64 + total / 60;
+        self.hours = (total % 24) as u8;
+        let days = self.days as u64 + total / 24;
+        if days > 0x1ff {
+            self.carry = true;
+        }
+        self.days = (days & 0x1ff) as u16;
+    }
+
+    fn registers(&self) -> [u8; 5] {
+        [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.days as u8,
+            (self.days >> 8) as u8 | (self.halt as u8) << 6 | (self.carry as u8) << 7,
+        ]
+    }
+
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(SNAPSHOT_LEN);
+        out.extend_from_slice(SNAPSHOT_MAGIC);
+        out.push(SNAPSHOT_VERSION);
+        out.extend_from_slice(&self.registers());
+        out.extend_from_slice(&self.latched);
+        out.extend_from_slice(&self.sub_second.to_le_bytes());
+        out.extend_from_slice(&self.synced_at.unwrap_or(u64::MAX).to_le_bytes());
+        out
+    }
+
+    // False (clock untouched) for anything but a version 1 snapshot
+    pub fn restore(&mut self, data: &[u8]) -> bool {
+        if data.len() < SNAPSHOT_LEN || &data[..3] != SNAPSHOT_MAGIC || data[3] != SNAPSHOT_VERSION {
+            return false;
+        }
+        for (i, &val) in data[4..9].iter().enumerate() {
+            self.write(0x08 + i as u8, val);
+        }
+        self.latched.copy_from_slice(&data[9..14]);
+        self.sub_second = u32::from_le_bytes([data[14], data[15], data[16], data[17]]) % CPU_CLOCK;
+        let mut synced = [0u8; 8];
+        synced.copy_from_slice(&data[18..26]);
+        let synced = u64::from_le_bytes(synced);
+        self.synced_at = (synced != u64::MAX).then_some(synced);
+        self.seconds_since_sync = 0;
+        self.latch_armed = false;
+        true
+    }
+
+    // 0x6000-0x7FFF: writing 0x00 then 0x01 copies the live clock
+    pub fn write_latch(&mut self, val: u8) {
+        if self.latch_armed && val == 0x01 {
+            self.latched = self.registers();
+        }
+        self.latch_armed = val == 0x00;
+    }
+
+    pub fn read(&self, reg: u8) -> u8 {
+        match reg {
+            0x08..=0x0c => self.latched[(reg - 0x08) as usize],
+            _ => 0xff,
+        }
+    }
+
+    pub fn write(&mut self, reg: u8, val: u8) {
+        match reg {
+            0x08 => {
+                self.seconds = val & 0x3f;
+                self.sub_second = 0;
+            }
+            0x09 => self.minutes = val & 0x3f,
+            0x0a => self.hours = val & 0x1f,
+            0x0b => self.days = (self.days & 0x100) | val as u16,
+            0x0c => {
+                self.days = (self.days & 0xff) | ((val as u16 & 0x01) << 8);
+                self.halt = val & 0x40 != 0;
+                self.carry = val & 0x80 != 0;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_see_the_latched_copy() {
+        let mut rtc = Rtc::new();
+        rtc.write(0x08, 5);
+        rtc.write(0x09, 59);
+        assert_eq!(rtc.read(0x08), 0);
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+        assert_eq!((rtc.read(0x08), rtc.read(0x09)), (5, 59));
+
+        // The live clock runs on while the latched copy stays put
+        rtc.step(CPU_CLOCK * 55);
+        assert_eq!((rtc.read(0x08), rtc.read(0x09)), (5, 59));
+        // Only 0x00 followed directly by 0x01 latches
+        rtc.write_latch(0x01);
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x02);
+        rtc.write_latch(0x01);
+        assert_eq!(rtc.read(0x08), 5);
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+        assert_eq!((rtc.read(0x08), rtc.read(0x09), rtc.read(0x0a)), (0, 0, 1));
+    }
+}
@@ -0,0 +1,161 @@
+use serde::{Deserialize, Serialize};
+
+// Serial port (SB 0xFF01, SC 0xFF02)
+//
+// An internal-clock transfer shifts SB out MSB first, one bit per serial
+// clock, while the other end's bits shift in at the bottom. Nothing on the
+// link port leaves the line high, so SB ends up 0xFF. After the eighth bit
+// SC bit 7 clears and the serial interrupt is requested. External-clock
+// transfers wait for a clock that never comes.
+//
+// When linked to another core, transfers on either clock instead wait until
+// the link exchanges whole bytes (see GameBoy::link_exchange)
+#[derive(Serialize, Deserialize)]
+pub struct Serial {
+    // SC bit 7 set: a transfer is requested, on the internal clock or not
+    active: bool,
+    internal_clock: bool,
+    linked: bool,
+    // Bits still to shift; 0 when idle
+    bits_left: u8,
+    // CPU cycles per bit and until the next one
+    bit_period: u32,
+    bit_cycles_left: u32,
+    // SB when the transfer started
+    outgoing: u8,
+    // What the other end sends during the next transfer
+    incoming: u8,
+    // Every byte sent since the last clear, for test ROM output; not part
+    // of save states
+    #[serde(skip)]
+    output: Vec<u8>,
+}
+
+// Nothing connected: the input line floats high
+const NO_DEVICE: u8 = 0xff;
+
+impl Serial {
+    pub fn new() -> Self {
+        Serial {
+            active: false,
+            internal_clock: false,
+            linked: false,
+            bits_left: 0,
+            bit_period: 0,
+            bit_cycles_left: 0,
+            outgoing: 0,
+            incoming: NO_DEVICE,
+            output: Vec::new(),
+        }
+    }
+
+    // A link connection outlives a reset
+    pub fn reset(&mut self) {
+        self.active = false;
+        self.bits_left = 0;
+        self.incoming = NO_DEVICE;
+        self.output.clear();
+    }
+
+    // SC write. Internal clock runs at 8192 Hz, or 262144 Hz with the CGB
+    // fast-clock bit 1; counted in CPU cycles, so double speed doubles it.
+    // Clearing bit 7 abandons a transfer in progress
+    pub fn write_control(&mut self, val: u8, is_cgb: bool, io: &[u8]) {
+        self.active = val & 0x80 != 0;
+        self.internal_clock = val & 0x01 != 0;
+        if !self.active || !self.internal_clock || self.linked {
+            self.bits_left = 0;
+            return;
+        }
+        self.bit_period = if is_cgb && val & 0x02 != 0 { 16 } else { 512 };
+        self.bit_cycles_left = self.bit_period;
+        self.bits_left = 8;
+        self.outgoing = io[0x01];
+    }
+
+    pub fn step(&mut self, cycles: u32, io: &mut [u8]) {
+        let mut cycles = cycles;
+        while self.bits_left > 0 && cycles >= self.bit_cycles_left {
+            cycles -= self.bit_cycles_left;
+            self.bit_cycles_left = self.bit_period;
+            self.shift_bit(io);
+        }
+        if self.bits_left > 0 {
+            self.bit_cycles_left -= cycles;
+        }
+    }
+
+    fn shift_bit(&mut self, io: &mut [u8]) {
+        self.bits_left -= 1;
+        let bit_in = (self.incoming >> self.bits_left) & 1;
+        io[0x01] = io[0x01] << 1 | bit_in;
+        if self.bits_left == 0 {
+            self.output.push(self.outgoing);
+            self.incoming = NO_DEVICE;
+            self.finish(io);
+        }
+    }
+
+    fn finish(&mut self, io: &mut [u8]) {
+        self.active = false;
+        io[0x02] &= 0x7f;
+        io[0x0f] |= 0x08;
+    }
+
+    // Take a saved port, keeping the output log and link connection
+    pub fn load_state(&mut self, mut saved: Serial) {
+        saved.output = std::mem::take(&mut self.output);
+        saved.linked = self.linked;
+        *self = saved;
+    }
+
+    pub fn set_linked(&mut self, linked: bool) {
+        self.linked = linked;
+    }
+
+    // Linked transfer waiting for the partner: whether this side drives the
+    // clock, and the byte it sends
+    pub fn link_request(&self, io: &[u8]) -> Option<(bool, u8)> {
+        (self.linked && self.active).then_some((self.internal_clock, io[0x01]))
+    }
+
+    // All eight bits exchanged at once; `byte_in` is what the partner sent
+    pub fn complete_link(&mut self, byte_in: u8, io: &mut [u8]) {
+        self.output.push(io[0x01]);
+        io[0x01] = byte_in;
+        self.finish(io);
+    }
+
+    // Link hook: the byte the other end shifts in during the next (or
+    // current) transfer; back to 0xFF once that transfer completes
+    pub fn set_byte_in(&mut self, val: u8) {
+        self.incoming = val;
+    }
+
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn clear_output(&mut self) {
+        self.output.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completed_transfer_requests_the_serial_interrupt() {
+        let mut serial = Serial::new();
+        let mut io = [0u8; 0x80];
+        io[0x01] = 0x42;
+        io[0x02] = 0x81;
+        serial.write_control(0x81, false, &io);
+        serial.step(8 * 512 - 1, &mut io);
+        assert_eq!((io[0x02], io[0x0f]), (0x81, 0x00));
+        serial.step(1, &mut io);
+        assert_eq!((io[0x01], io[0x02], io[0x0f]), (0xff, 0x01, 0x08));
+        assert_eq!(serial.output(), &[0x42]);
+    }
+}
@@ -1,47 +1,248 @@
+use serde::{Deserialize, Serialize};
+
 // Timer
+//
+// DIV is the upper byte of a 16-bit counter that ticks every T-cycle. TIMA
+// increments on the falling edge of (TAC enable AND the counter bit picked by
+// TAC), so disabling the timer or resetting DIV while that bit is high bumps
+// TIMA once, and enabling it while the bit is high does not. On overflow
+// TIMA reads 0 for one M-cycle before TMA is loaded and the interrupt
+// requested; writing TIMA in that window cancels the reload.
+//
+// The APU frame sequencer steps on the falling edge of DIV bit 4 (counter
+// bit 12), bit 5 at CGB double speed so it stays at 512 Hz. A DIV write
+// that clears a set bit clocks it too
+#[derive(Serialize, Deserialize)]
 pub struct Timer {
-    div_counter: u32,
-    tima_counter: u32,
+    counter: u16,
+    signal: bool,
+    // T-cycles until the pending TMA reload, 0 when none
+    reload_delay: u8,
+    // Counter bit driving the frame sequencer, and its falling edges not
+    // yet passed on to the APU
+    frame_seq_bit: u8,
+    frame_seq_clocks: u32,
 }
 
+const RELOAD_DELAY: u8 = 4;
+
+// Counter bit watched for each TAC clock select (4096, 262144, 65536, 16384 Hz)
+const TAC_BITS: [u16; 4] = [9, 3, 5, 7];
+
+// Frame sequencer counter bit at normal and double speed
+const FRAME_SEQ_BIT: u8 = 12;
+const FRAME_SEQ_BIT_DOUBLE_SPEED: u8 = 13;
+
+// Internal counter after the DMG boot ROM (DIV reads 0xab)
+const POST_BOOT_COUNTER: u16 = 0xabcc;
+
 impl Timer {
     pub fn new() -> Self {
-        Timer { div_counter: 0, tima_counter: 0 }
+        Timer {
+            counter: POST_BOOT_COUNTER,
+            signal: false,
+            reload_delay: 0,
+            frame_seq_bit: FRAME_SEQ_BIT,
+            frame_seq_clocks: 0,
+        }
     }
 
     pub fn reset(&mut self) {
-        self.div_counter = 0;
-        self.tima_counter = 0;
+        self.counter = POST_BOOT_COUNTER;
+        self.signal = false;
+        self.reload_delay = 0;
+        self.frame_seq_bit = FRAME_SEQ_BIT;
+        self.frame_seq_clocks = 0;
     }
 
     pub fn step(&mut self, cycles: u32, io: &mut [u8]) {
-        // DIV @16384Hz
-        self.div_counter += cycles;
-        if self.div_counter >= 256 {
-            self.div_counter -= 256;
-            io[0x04] = io[0x04].wrapping_add(1);
+        // Catch TAC writes before counting further
+        self.update_signal(io);
+        for _ in 0..cycles {
+            if self.reload_delay > 0 {
+                self.reload_delay -= 1;
+                if self.reload_delay == 0 {
+                    io[0x05] = io[0x06];
+                    io[0x0f] |= 0x04;
+                }
+            }
+            let old = self.counter;
+            self.counter = self.counter.wrapping_add(1);
+            self.count_frame_seq_edge(old);
+            self.update_signal(io);
+        }
+    }
+
+    // DIV (0xFF04) is the upper byte of the counter
+    pub fn div(&self) -> u8 {
+        (self.counter >> 8) as u8
+    }
+
+    // Writes to 0xFF04-0xFF07, forwarded by the MMU so the counter, edge
+    // detector and reload state change together with the registers. Any
+    // DIV write clears the whole counter, and that or a TAC write can be
+    // the falling edge that bumps TIMA. A TIMA write during the overflow
+    // window replaces the pending reload; a TMA write there is what loads
+    pub fn write(&mut self, offset: usize, val: u8, io: &mut [u8]) {
+        match offset {
+            0x04 => {
+                let old = self.counter;
+                self.counter = 0;
+                self.count_frame_seq_edge(old);
+            }
+            0x05 => {
+                self.reload_delay = 0;
+                io[0x05] = val;
+            }
+            0x06 => io[0x06] = val,
+            // Bits 3-7 are unused and read as 1
+            0x07 => io[0x07] = 0xf8 | (val & 0x07),
+            _ => return,
+        }
+        self.update_signal(io);
+    }
+
+    pub fn set_double_speed(&mut self, on: bool) {
+        self.frame_seq_bit = if on { FRAME_SEQ_BIT_DOUBLE_SPEED } else { FRAME_SEQ_BIT };
+    }
+
+    // Frame sequencer clocks since the last call
+    pub fn take_frame_seq_clocks(&mut self) -> u32 {
+        std::mem::take(&mut self.frame_seq_clocks)
+    }
+
+    fn count_frame_seq_edge(&mut self, old: u16) {
+        if (old & !self.counter) >> self.frame_seq_bit & 1 != 0 {
+            self.frame_seq_clocks += 1;
         }
+    }
 
-        // TIMA if enabled
+    fn update_signal(&mut self, io: &mut [u8]) {
         let tac = io[0x07];
-        if tac & 0x04 != 0 {
-            let frequencies = [1024, 16, 64, 256];
-            let frequency = frequencies[(tac & 0x03) as usize];
-
-            self.tima_counter += cycles;
-            if self.tima_counter >= frequency {
-                self.tima_counter -= frequency;
-
-                let tima = io[0x05];
-                if tima == 0xff {
-                    // Overflow -> timer interrupt
-                    let tma = io[0x06];
-                    io[0x05] = tma;
-                    io[0x0f] |= 0x04;
-                } else {
-                    io[0x05] = tima.wrapping_add(1);
-                }
+        let bit = TAC_BITS[(tac & 0x03) as usize];
+        let signal = tac & 0x04 != 0 && (self.counter >> bit) & 1 != 0;
+        if self.signal && !signal {
+            let tima = io[0x05];
+            if tima == 0xff {
+                // Overflow: TMA and the timer interrupt follow a cycle later
+                io[0x05] = 0;
+                self.reload_delay = RELOAD_DELAY;
+            } else {
+                io[0x05] = tima.wrapping_add(1);
             }
         }
+        self.signal = signal;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Timer at `counter` with TAC already written, and the IO bytes it uses
+    fn timer_at(counter: u16, tac: u8) -> (Timer, Vec<u8>) {
+        let mut timer = Timer::new();
+        let mut io = vec![0u8; 0x10];
+        timer.counter = counter;
+        timer.write(0x07, tac, &mut io);
+        (timer, io)
+    }
+
+    #[test]
+    fn tac_disable_and_enable_edges() {
+        // 262144 Hz watches counter bit 3. Disabling while it is high is a
+        // falling edge; while it is low nothing happens
+        let (mut timer, mut io) = timer_at(0x0008, 0x05);
+        timer.write(0x07, 0x01, &mut io);
+        assert_eq!(io[0x05], 1);
+        let (mut timer, mut io) = timer_at(0x0010, 0x05);
+        timer.write(0x07, 0x01, &mut io);
+        assert_eq!(io[0x05], 0);
+
+        // Enabling while the bit is high counts nothing until it falls
+        let (mut timer, mut io) = timer_at(0x0008, 0x01);
+        timer.write(0x07, 0x05, &mut io);
+        assert_eq!(io[0x05], 0);
+        timer.step(7, &mut io);
+        assert_eq!(io[0x05], 0);
+        timer.step(1, &mut io);
+        assert_eq!(io[0x05], 1);
+
+        // A disable/enable pair in the high half costs exactly one increment
+        timer.step(8, &mut io);
+        timer.write(0x07, 0x01, &mut io);
+        timer.write(0x07, 0x05, &mut io);
+        assert_eq!(io[0x05], 2);
+        timer.step(8, &mut io);
+        assert_eq!(io[0x05], 3);
+    }
+
+    #[test]
+    fn div_is_the_counter_high_byte() {
+        let mut timer = Timer::new();
+        let mut io = vec![0u8; 0x10];
+        assert_eq!(timer.div(), 0xab);
+        timer.step(0x33, &mut io);
+        assert_eq!(timer.div(), 0xab);
+        timer.step(1, &mut io);
+        assert_eq!(timer.div(), 0xac);
+        timer.write(0x04, 0x5a, &mut io);
+        assert_eq!(timer.div(), 0x00);
+        timer.step(256 * 3 + 255, &mut io);
+        assert_eq!(timer.div(), 0x03);
+
+        // A DIV write while the selected bit is high is a TIMA edge
+        let (mut timer, mut io) = timer_at(0x0208, 0x05);
+        timer.write(0x04, 0x00, &mut io);
+        assert_eq!(io[0x05], 1);
+        let (mut timer, mut io) = timer_at(0x0200, 0x05);
+        timer.write(0x04, 0x00, &mut io);
+        assert_eq!(io[0x05], 0);
+    }
+
+    #[test]
+    fn tac_rates_count_every_period() {
+        // tim00, tim01, tim10, tim11
+        for (tac, period) in [(0x04, 1024), (0x05, 16), (0x06, 64), (0x07, 256)] {
+            let (mut timer, mut io) = timer_at(0x0000, tac);
+            timer.step(period - 1, &mut io);
+            assert_eq!(io[0x05], 0, "tac {:02x}", tac);
+            timer.step(1, &mut io);
+            assert_eq!(io[0x05], 1, "tac {:02x}", tac);
+            timer.step(period * 9, &mut io);
+            assert_eq!(io[0x05], 10, "tac {:02x}", tac);
+        }
+    }
+
+    #[test]
+    fn overflow_reload_window() {
+        // tima_reload: 0 for one M-cycle, then TMA and the interrupt
+        let (mut timer, mut io) = timer_at(0x0000, 0x05);
+        io[0x05] = 0xff;
+        io[0x06] = 0x42;
+        timer.step(16, &mut io);
+        assert_eq!((io[0x05], io[0x0f]), (0x00, 0x00));
+        timer.step(3, &mut io);
+        assert_eq!((io[0x05], io[0x0f]), (0x00, 0x00));
+        timer.step(1, &mut io);
+        assert_eq!((io[0x05], io[0x0f]), (0x42, 0x04));
+
+        // tima_write_reloading: a TIMA write in the window cancels it
+        let (mut timer, mut io) = timer_at(0x0000, 0x05);
+        io[0x05] = 0xff;
+        io[0x06] = 0x42;
+        timer.step(17, &mut io);
+        timer.write(0x05, 0x10, &mut io);
+        timer.step(4, &mut io);
+        assert_eq!((io[0x05], io[0x0f]), (0x10, 0x00));
+
+        // tma_write_reloading: a TMA write in the window is what gets loaded
+        let (mut timer, mut io) = timer_at(0x0000, 0x05);
+        io[0x05] = 0xff;
+        io[0x06] = 0x42;
+        timer.step(17, &mut io);
+        timer.write(0x06, 0x99, &mut io);
+        timer.step(3, &mut io);
+        assert_eq!((io[0x05], io[0x0f]), (0x99, 0x04));
     }
 }